@@ -1,4 +1,6 @@
 
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
 use std::path::{PathBuf, Path};
 use std::process::Stdio;
 use std::{process, fs};
@@ -34,6 +36,15 @@ pub mod color {
     pub const BG_WHITE: &str = "\x1b[47m";
 }
 
+/// Interpreted tests that run more operators than this are stuck
+const MAX_STEPS: u64 = 10_000_000;
+
+/// The optimisation levels the --checked tests are built with
+const CHECKED_OPTS: [&str; 2] = ["0", "1"];
+
+/// The exit code of a program built with --checked when a check fails
+const CHECK_FAILED: i32 = 101;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 struct TestOutput {
@@ -43,20 +54,81 @@ struct TestOutput {
     status: i32
 }
 
-fn run_test<P: Into<PathBuf> + std::convert::AsRef<std::ffi::OsStr>>(f_in: PathBuf, f_out: &PathBuf, compiler: P, compile_mode: bool, stdin: String) -> Result<TestOutput> {
-    let mut command = process::Command::new(compiler);
+/// The files a test starts with and the folder it ends up in, the
+/// interpreter gets a sandbox and compiled programs run in a copy of `seed`
+struct TestFs {
+    seed: Option<PathBuf>,
+    dir: PathBuf,
+}
+
+/// The arguments in a `// args: ` first line of a test, double quotes group words
+fn test_args(f_in: &Path) -> Result<Vec<String>> {
+    let code = fs::read_to_string(f_in)?;
+    let Some(line) = code.lines().next().and_then(|l| l.strip_prefix("// args:")) else {
+        return Ok(Vec::new());
+    };
+    let mut args = Vec::new();
+    let mut arg = None::<String>;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                arg.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Interpret `f_in` and write its coverage to `coverage` if given, or compile
+/// and run it if `compile` has a target, optimisation level and emit mode
+fn run_test<P: AsRef<Path>>(f_in: PathBuf, f_out: &Path, compiler: P, compile: Option<(&str, &str, &str)>, stdin: String, test_fs: &TestFs, coverage: Option<&Path>) -> Result<TestOutput> {
+    let program_args = test_args(&f_in)?;
+    let _ = fs::remove_dir_all(&test_fs.dir);
+    let mut command = process::Command::new(fs::canonicalize(compiler)?);
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
-    if compile_mode {
+    if let Some((target, opt, emit)) = compile {
         command.arg("-cqr");
+        command.arg("-t");
+        command.arg(target);
+        command.arg("--emit");
+        command.arg(emit);
+        command.arg(format!("-O{opt}"));
+        command.arg("-I");
+        command.arg(std::path::absolute("./include")?);
+        match &test_fs.seed {
+            Some(seed) => copy_dir(seed, &test_fs.dir)?,
+            None => fs::create_dir_all(&test_fs.dir)?,
+        }
+        command.current_dir(&test_fs.dir);
     } else {
         command.arg("-sq");
+        command.arg("--deterministic");
+        command.arg("--max-steps");
+        command.arg(MAX_STEPS.to_string());
+        command.arg("--sandbox");
+        if let Some(seed) = &test_fs.seed {
+            command.arg(seed);
+        }
+        command.arg("--sandbox-dump");
+        command.arg(&test_fs.dir);
+        if let Some(coverage) = coverage {
+            command.arg("--coverage");
+            command.arg(coverage);
+        }
     }
 
     command.arg("-i");
-    command.arg(f_in);
+    command.arg(std::path::absolute(f_in)?);
     command.arg("-o");
-    command.arg(f_out);
+    command.arg(std::path::absolute(f_out)?);
+    command.arg("--");
+    command.args(program_args);
 
     let child = command.spawn()?;
 
@@ -79,21 +151,596 @@ fn run_test<P: Into<PathBuf> + std::convert::AsRef<std::ffi::OsStr>>(f_in: PathB
     })
 }
 
-fn run_tests(args: Args) -> Result<()>{
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let dest = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &dest)?;
+        } else {
+            fs::copy(&path, &dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Every path under `dir` with the contents of the files, None for folders
+fn read_tree(dir: &Path) -> Result<BTreeMap<PathBuf, Option<Vec<u8>>>> {
+    let mut tree = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(tree);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = PathBuf::from(path.file_name().unwrap());
+        if path.is_dir() {
+            tree.insert(name.clone(), None);
+            for (p, data) in read_tree(&path)? {
+                tree.insert(name.join(p), data);
+            }
+        } else {
+            tree.insert(name, Some(fs::read(&path)?));
+        }
+    }
+    Ok(tree)
+}
+
+fn compare_files(intp: &TestFs, comp: &TestFs, f_in: &Path) -> Result<()> {
+    let intp_tree = read_tree(&intp.dir)?;
+    let comp_tree = read_tree(&comp.dir)?;
+    if intp_tree != comp_tree {
+        println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} compiled and interpreted versions leave different files behind", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=f_in.display());
+        let path = intp_tree.iter().zip(&comp_tree).find(|(i, c)| i != c)
+            .map(|((p, _), _)| p)
+            .or_else(|| intp_tree.keys().nth(comp_tree.len()))
+            .or_else(|| comp_tree.keys().nth(intp_tree.len()));
+        if let Some(path) = path {
+            println!("first difference at {}", path.display());
+        }
+        println!("compiled:\n{}", comp.dir.display());
+        println!("interpreted:\n{}", intp.dir.display());
+        return Err(eyre!("Testing failed"));
+    }
+    Ok(())
+}
+
+/// Compile `f_in` to assembly at -O1, returns None if it does not compile
+fn compile_asm(f_in: &Path, f_out: &Path, compiler: &str) -> Result<Option<String>> {
+    let f_asm = f_out.with_extension("nasm");
+    let _ = fs::remove_file(&f_asm);
+    let out = process::Command::new(compiler)
+        .args(["-cq", "-O1", "--emit", "asm", "-i"])
+        .arg(f_in)
+        .arg("-o")
+        .arg(f_out)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !out.success() || !f_asm.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(f_asm)?))
+}
+
+fn golden_path(args: &Args, f_in: &Path) -> PathBuf {
+    PathBuf::from(&args.golden).join(f_in.file_stem().unwrap()).with_extension("nasm")
+}
+
+/// Interpret `f_in` under the debugger driven by `script`, returns its stdout
+fn run_debugger(f_in: &Path, script: &Path, compiler: &str) -> Result<String> {
+    let out = process::Command::new(compiler)
+        .args(["-sq", "--debug-script"])
+        .arg(script)
+        .arg("-i")
+        .arg(f_in)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// Interpret `f_in` with --profile, returns the report and the collapsed stacks
+fn run_profiler(f_in: &Path, f_out: &Path, compiler: &str) -> Result<(String, String)> {
+    let report = f_out.with_extension("prof");
+    let folded = f_out.with_extension("prof.folded");
+    process::Command::new(compiler)
+        .args(["-sq", "--profile"])
+        .arg(&report)
+        .arg("-i")
+        .arg(f_in)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok((fs::read_to_string(report).unwrap_or_default(), fs::read_to_string(folded).unwrap_or_default()))
+}
+
+/// Interpret `f_in` with --coverage, returns its LCOV with the source paths
+/// made relative to the working directory so the goldens are portable
+fn run_coverage(f_in: &Path, f_out: &Path, compiler: &str) -> Result<String> {
+    let lcov = f_out.with_extension("lcov");
+    let _ = fs::remove_file(&lcov);
+    process::Command::new(compiler)
+        .args(["-sq", "--deterministic", "--coverage"])
+        .arg(&lcov)
+        .arg("-i")
+        .arg(f_in)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    relative_sources(&fs::read_to_string(lcov).unwrap_or_default())
+}
 
-    let files = fs::read_dir(args.input)?;
+/// Strip the working directory from the `SF:` paths of an LCOV file
+fn relative_sources(lcov: &str) -> Result<String> {
+    let cwd = format!("SF:{}/", std::path::absolute(".")?.display());
+    Ok(lcov.replace(&cwd, "SF:"))
+}
+
+/// Merge the LCOV files `run_coverage` wrote for `files`, returns the merged
+/// file with relative source paths
+fn run_coverage_merge(args: &Args, files: &[PathBuf]) -> Result<String> {
+    let lcovs = files.iter().map(|f| PathBuf::from(&args.output).join(f.file_name().unwrap()).with_extension("lcov")).collect::<Vec<_>>();
+    let merged = PathBuf::from(&args.output).join("merged.lcov");
+    merge_coverage(&lcovs, &merged)?;
+    relative_sources(&fs::read_to_string(merged)?)
+}
+
+/// Compile `f_in` with --checked at -O`opt` and run it, returns the last line
+/// of its stderr, the failed check, and its exit code
+fn run_checked(f_in: &Path, f_out: &Path, compiler: &str, opt: &str) -> Result<(String, i32)> {
+    let out = process::Command::new(compiler)
+        .args(["-cqr", "--checked"])
+        .arg(format!("-O{opt}"))
+        .arg("-I")
+        .arg(std::path::absolute("./include")?)
+        .arg("-i")
+        .arg(f_in)
+        .arg("-o")
+        .arg(f_out)
+        .stdin(Stdio::null())
+        .output()?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    Ok((stderr.lines().last().unwrap_or_default().to_string(), out.status.code().unwrap_or_default()))
+}
+
+/// Run `mclangc --docs` on `f_in`, returns what it printed
+fn run_docs(f_in: &Path, compiler: &str) -> Result<String> {
+    let out = process::Command::new(compiler)
+        .arg("--docs")
+        .arg("-i")
+        .arg(f_in)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// Feed `script` to `mclangc repl`, returns everything it printed with the
+/// errors in between
+fn run_repl(script: &Path, f_out: &Path, compiler: &str) -> Result<String> {
+    let log = f_out.with_extension("repl");
+    let out = fs::File::create(&log)?;
+    process::Command::new(compiler)
+        .args(["-q", "repl"])
+        .stdin(fs::File::open(script)?)
+        .stdout(out.try_clone()?)
+        .stderr(out)
+        .status()?;
+    Ok(fs::read_to_string(log)?)
+}
+
+/// The files with extension `ext` in `dir`, with the file their expected
+/// output is in
+fn dir_tests(dir: &str, ext: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let Ok(dir) = fs::read_dir(dir) else {
+        return Ok(Vec::new());
+    };
+    let mut tests = Vec::new();
+    for file in dir {
+        let path = file?.path();
+        if path.extension().is_some_and(|e| e == ext) {
+            let expected = path.with_extension("out");
+            tests.push((path, expected));
+        }
+    }
+    tests.sort();
+    Ok(tests)
+}
+
+/// The expected coverage of a test
+fn coverage_path(args: &Args, f_in: &Path) -> PathBuf {
+    PathBuf::from(&args.coverage_dir).join(f_in.file_stem().unwrap()).with_extension("lcov")
+}
 
-    for file in  files {
-        let file = file?;
-        let f_name = file.file_name().to_string_lossy().to_string();
+/// The expected profile of a test and its expected collapsed stacks
+fn profile_paths(args: &Args, f_in: &Path) -> (PathBuf, PathBuf) {
+    let base = PathBuf::from(&args.profile_dir).join(f_in.file_stem().unwrap());
+    (base.with_extension("prof"), base.with_extension("prof.folded"))
+}
+
+/// The debugger script of a test and the file its expected output is in
+fn debug_paths(args: &Args, f_in: &Path) -> (PathBuf, PathBuf) {
+    let base = PathBuf::from(&args.debug_dir).join(f_in.file_stem().unwrap());
+    (base.with_extension("mdb"), base.with_extension("out"))
+}
+
+fn test_files(args: &Args) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for file in fs::read_dir(&args.input)? {
+        let path = file?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "mcl") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Coverage of one source file in an LCOV file
+#[derive(Default)]
+struct LcovFile {
+    /// Name to line and calls
+    functions: BTreeMap<String, (usize, u64)>,
+    /// Line, block and branch to how often it was taken, None if never reached
+    branches: BTreeMap<(usize, usize, usize), Option<u64>>,
+    lines: BTreeMap<usize, u64>,
+}
+
+/// Add up the LCOV files the tests wrote into one at `out`, tests that did
+/// not get to run have none
+fn merge_coverage(files: &[PathBuf], out: &Path) -> Result<()> {
+    let mut merged = BTreeMap::<String, LcovFile>::new();
+    for f in files {
+        let Ok(lcov) = fs::read_to_string(f) else {
+            continue;
+        };
+        let mut source = String::new();
+        for line in lcov.lines() {
+            let (key, value) = line.split_once(':').unwrap_or((line, ""));
+            let fields = value.split(',').collect::<Vec<_>>();
+            let file = merged.entry(source.clone()).or_default();
+            match key {
+                "SF" => value.clone_into(&mut source),
+                "FN" => {
+                    file.functions.entry(fields[1].to_string()).or_insert((fields[0].parse()?, 0));
+                }
+                "FNDA" => file.functions.entry(fields[1].to_string()).or_default().1 += fields[0].parse::<u64>()?,
+                "BRDA" => {
+                    let branch = file.branches.entry((fields[0].parse()?, fields[1].parse()?, fields[2].parse()?)).or_default();
+                    if let Ok(n) = fields[3].parse::<u64>() {
+                        *branch = Some(branch.unwrap_or_default() + n);
+                    }
+                }
+                "DA" => *file.lines.entry(fields[0].parse()?).or_default() += fields[1].parse::<u64>()?,
+                _ => (),
+            }
+        }
+    }
+
+    let mut s = String::from("TN:\n");
+    for (name, file) in merged.iter().filter(|(name, _)| !name.is_empty()) {
+        let _ = writeln!(s, "SF:{name}");
+        for (f, (line, _)) in &file.functions {
+            let _ = writeln!(s, "FN:{line},{f}");
+        }
+        for (f, (_, n)) in &file.functions {
+            let _ = writeln!(s, "FNDA:{n},{f}");
+        }
+        let _ = writeln!(s, "FNF:{}", file.functions.len());
+        let _ = writeln!(s, "FNH:{}", file.functions.values().filter(|(_, n)| *n > 0).count());
+        for ((line, block, branch), n) in &file.branches {
+            let n = n.map_or_else(|| String::from("-"), |n| n.to_string());
+            let _ = writeln!(s, "BRDA:{line},{block},{branch},{n}");
+        }
+        let _ = writeln!(s, "BRF:{}", file.branches.len());
+        let _ = writeln!(s, "BRH:{}", file.branches.values().filter(|n| n.is_some_and(|n| n > 0)).count());
+        for (line, n) in &file.lines {
+            let _ = writeln!(s, "DA:{line},{n}");
+        }
+        let _ = writeln!(s, "LF:{}", file.lines.len());
+        let _ = writeln!(s, "LH:{}", file.lines.values().filter(|n| **n > 0).count());
+        let _ = writeln!(s, "end_of_record");
+    }
+    fs::write(out, s)?;
+    Ok(())
+}
+
+/// Make sure the tools the emit modes need are installed, without them
+/// mclangc only writes the ir and every test would fail with empty output
+fn check_emit_tools(args: &Args) -> Result<()> {
+    for emit in &args.emit {
+        let (var, tool, flag) = match emit.as_str() {
+            "llvm" => ("LLC", "llc", "--version"),
+            "qbe" => ("QBE", "qbe", "-h"),
+            _ => continue,
+        };
+        let tool = std::env::var(var).unwrap_or_else(|_| String::from(tool));
+        if process::Command::new(&tool).arg(flag).stdout(Stdio::null()).stderr(Stdio::null()).status().is_err() {
+            println!("{b}[ {r}ERR{rs}{b} ]{rs} --emit {emit} needs {tool}, install it or point ${var} at it", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT);
+            return Err(eyre!("Testing failed"));
+        }
+    }
+    Ok(())
+}
+
+/// How to assemble linux_aarch64 code when it can not be run, None when
+/// qemu-aarch64 is installed or the host is aarch64 so the tests run
+fn aarch64_assembler() -> Result<Option<Vec<&'static str>>> {
+    let found = |tool: &str| process::Command::new(tool).arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok();
+    if cfg!(target_arch = "aarch64") || found("qemu-aarch64") {
+        return Ok(None);
+    }
+    if found("aarch64-linux-gnu-as") {
+        return Ok(Some(vec!["aarch64-linux-gnu-as"]));
+    }
+    if found("llvm-mc") {
+        return Ok(Some(vec!["llvm-mc", "--triple=aarch64-linux-gnu", "--filetype=obj"]));
+    }
+    println!("{b}[ {r}ERR{rs}{b} ]{rs} linux_aarch64 needs qemu-aarch64 to run the tests, or aarch64-linux-gnu-as or llvm-mc to assemble them", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT);
+    Err(eyre!("Testing failed"))
+}
+
+/// Compile `f_in` for linux_aarch64 at -O`opt` and assemble it, for when the
+/// tests can not run. A program that does not compile has to fail with the
+/// same errors as in the interpreter
+fn assemble_aarch64(f_in: &Path, f_out: &Path, compiler: &str, opt: &str, assembler: &[&str], intp: &TestOutput) -> Result<()> {
+    let asm = f_out.with_extension("s");
+    let _ = fs::remove_file(&asm);
+    let out = process::Command::new(compiler)
+        .args(["-cq", "-t", "linux_aarch64", "--emit", "asm"])
+        .arg(format!("-O{opt}"))
+        .arg("-I")
+        .arg(std::path::absolute("./include")?)
+        .arg("-i")
+        .arg(std::path::absolute(f_in)?)
+        .arg("-o")
+        .arg(f_out.with_extension("aarch64"))
+        .stdin(Stdio::null())
+        .output()?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !asm.exists() {
+        if stderr != intp.stderr {
+            println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} linux_aarch64 -O{opt} did not compile", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=f_in.display());
+            println!("{stderr}");
+            return Err(eyre!("Testing failed"));
+        }
+        println!("{b}[ {g}OK{rs}{b} ]{rs} {f} linux_aarch64 -O{opt} fails like the interpreter", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=f_in.display());
+        return Ok(());
+    }
+
+    let out = process::Command::new(assembler[0])
+        .args(&assembler[1..])
+        .arg(&asm)
+        .arg("-o")
+        .arg(f_out.with_extension("aarch64.o"))
+        .stdin(Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} linux_aarch64 -O{opt} does not assemble with {a}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=f_in.display(), a=assembler[0]);
+        println!("{}", String::from_utf8_lossy(&out.stderr));
+        return Err(eyre!("Testing failed"));
+    }
+    println!("{b}[ {g}OK{rs}{b} ]{rs} {f} linux_aarch64 -O{opt} assembled with {a}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=f_in.display(), a=assembler[0]);
+    Ok(())
+}
+
+fn run_tests(args: Args) -> Result<()>{
+    check_emit_tools(&args)?;
+    let aarch64_as = if args.target.iter().any(|t| t == "linux_aarch64") { aarch64_assembler()? } else { None };
+    let mut coverage = Vec::new();
+    let mut covered = Vec::new();
+
+    for file in test_files(&args)? {
+        let f_name = file.file_name().unwrap().to_string_lossy().to_string();
         let f_out = PathBuf::from(&args.output).join(f_name);
 
+        let seed = PathBuf::from(&args.fs_dir).join(file.file_stem().unwrap());
+        let seed = seed.is_dir().then_some(seed);
+        let fs_out = f_out.with_extension("fs");
+        let intp_fs = TestFs { seed: seed.clone(), dir: fs_out.join("interpreted") };
+
+        let lcov = args.coverage.is_some().then(|| f_out.with_extension("lcov"));
+        if let Some(lcov) = &lcov {
+            let _ = fs::remove_file(lcov);
+        }
+        let intp = run_test(file.clone(), &f_out, &args.compiler_path, None, String::new(), &intp_fs, lcov.as_deref())?;
+        coverage.extend(lcov);
+        for target in &args.target {
+            for opt in &args.optimisation {
+                if let Some(assembler) = aarch64_as.as_deref().filter(|_| target == "linux_aarch64") {
+                    assemble_aarch64(&file, &f_out, &args.compiler_path, opt, assembler, &intp)?;
+                    continue;
+                }
+                for emit in &args.emit {
+                    let comp_fs = TestFs { seed: seed.clone(), dir: fs_out.join(format!("{target}-O{opt}-{emit}")) };
+                    let comp = run_test(file.clone(), &f_out, &args.compiler_path, Some((target, opt, emit)), String::new(), &comp_fs, None)?;
+                    compare_files(&intp_fs, &comp_fs, &file)?;
+                    compare_results(&intp, &comp, &file)?;
+                }
+            }
+        }
 
-        let intp = run_test(file.path(), &f_out, &args.compiler_path, false, String::new())?;
-        let comp = run_test(file.path(), &f_out, &args.compiler_path, true, String::new())?;
-        compare_results(&intp, &comp, &file.path())?;
+        let golden = golden_path(&args, &file);
+        if golden.exists() {
+            let asm = compile_asm(&file, &f_out, &args.compiler_path)?.unwrap_or_default();
+            compare_golden(&asm, &fs::read_to_string(&golden)?, &file)?;
+        }
+
+        let (script, expected) = debug_paths(&args, &file);
+        if script.exists() {
+            let out = run_debugger(&file, &script, &args.compiler_path)?;
+            if out != fs::read_to_string(&expected).unwrap_or_default() {
+                println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} debugger output differs from {e}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=file.display(), e=expected.display());
+                println!("got:\n{out}");
+                return Err(eyre!("Testing failed"));
+            }
+            println!("{b}[ {g}OK{rs}{b} ]{rs} {f} debugger", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=file.display());
+        }
+
+        let (report, folded) = profile_paths(&args, &file);
+        if report.exists() {
+            let out = run_profiler(&file, &f_out, &args.compiler_path)?;
+            for (got, expected) in [(out.0, &report), (out.1, &folded)] {
+                if got != fs::read_to_string(expected).unwrap_or_default() {
+                    println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} profile differs from {e}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=file.display(), e=expected.display());
+                    println!("got:\n{got}");
+                    return Err(eyre!("Testing failed"));
+                }
+            }
+            println!("{b}[ {g}OK{rs}{b} ]{rs} {f} profile", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=file.display());
+        }
+
+        let expected = coverage_path(&args, &file);
+        if expected.exists() {
+            let out = run_coverage(&file, &f_out, &args.compiler_path)?;
+            if out != fs::read_to_string(&expected)? {
+                println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} coverage differs from {e}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=file.display(), e=expected.display());
+                println!("got:\n{out}");
+                return Err(eyre!("Testing failed"));
+            }
+            println!("{b}[ {g}OK{rs}{b} ]{rs} {f} coverage", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=file.display());
+            covered.push(file);
+        }
+    }
+
+    for (file, expected) in dir_tests(&args.checked_dir, "mcl")? {
+        let f_out = PathBuf::from(&args.output).join(file.file_stem().unwrap()).with_extension("checked");
+        let expected = fs::read_to_string(&expected).unwrap_or_default();
+        for opt in CHECKED_OPTS {
+            let (message, status) = run_checked(&file, &f_out, &args.compiler_path, opt)?;
+            if message != expected.trim_end() || status != CHECK_FAILED {
+                println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} -O{opt} did not fail its check", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=file.display());
+                println!("expected (exit code {CHECK_FAILED}):\n{}", expected.trim_end());
+                println!("got (exit code {status}):\n{message}");
+                return Err(eyre!("Testing failed"));
+            }
+        }
+        println!("{b}[ {g}OK{rs}{b} ]{rs} {f} checked", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=file.display());
+    }
+
+    for (script, expected) in dir_tests(&args.repl_dir, "in")? {
+        let f_out = PathBuf::from(&args.output).join(script.file_name().unwrap());
+        let out = run_repl(&script, &f_out, &args.compiler_path)?;
+        if out != fs::read_to_string(&expected).unwrap_or_default() {
+            println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} repl output differs from {e}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=script.display(), e=expected.display());
+            println!("got:\n{out}");
+            return Err(eyre!("Testing failed"));
+        }
+        println!("{b}[ {g}OK{rs}{b} ]{rs} {f} repl", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=script.display());
+    }
+
+    for (file, expected) in dir_tests(&args.docs_dir, "mcl")? {
+        let out = run_docs(&file, &args.compiler_path)?;
+        if out != fs::read_to_string(&expected).unwrap_or_default() {
+            println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} docs differ from {e}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=file.display(), e=expected.display());
+            println!("got:\n{out}");
+            return Err(eyre!("Testing failed"));
+        }
+        println!("{b}[ {g}OK{rs}{b} ]{rs} {f} docs", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=file.display());
+    }
+
+    let expected = PathBuf::from(&args.coverage_dir).join("merged.lcov");
+    if expected.exists() {
+        let out = run_coverage_merge(&args, &covered)?;
+        if out != fs::read_to_string(&expected)? {
+            println!("{b}[ {r}ERR{rs}{b} ]{rs} merged coverage differs from {e}", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, e=expected.display());
+            println!("got:\n{out}");
+            return Err(eyre!("Testing failed"));
+        }
+        println!("{b}[ {g}OK{rs}{b} ]{rs} merged coverage", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT);
     }
 
+    if let Some(out) = &args.coverage {
+        merge_coverage(&coverage, Path::new(out))?;
+        println!("{b}[ {g}OK{rs}{b} ]{rs} coverage written to {out}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT);
+    }
+
+    Ok(())
+}
+
+/// Rewrite the -O1 assembly of every test with a golden file, the debugger
+/// output of every test with a debugger script and the profile of every test
+/// with one. The same goes for the coverage of the tests in the coverage
+/// folder and their merge, the failed checks of the --checked tests and the
+/// output of the --docs and repl tests. Golden assembly is only kept for the
+/// optimiser and peephole tests, so an empty `<test>.nasm` has to be created
+/// to record a new one
+fn record_golden(args: Args) -> Result<()> {
+    fs::create_dir_all(&args.golden)?;
+    let mut covered = Vec::new();
+    for file in test_files(&args)? {
+        let (script, expected) = debug_paths(&args, &file);
+        if script.exists() {
+            fs::write(&expected, run_debugger(&file, &script, &args.compiler_path)?)?;
+            println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=expected.display());
+        }
+
+        let f_out = PathBuf::from(&args.output).join(file.file_name().unwrap());
+        let (report, folded) = profile_paths(&args, &file);
+        if report.exists() {
+            let (got_report, got_folded) = run_profiler(&file, &f_out, &args.compiler_path)?;
+            fs::write(&report, got_report)?;
+            fs::write(&folded, got_folded)?;
+            println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=report.display());
+        }
+
+        let expected = coverage_path(&args, &file);
+        if expected.exists() {
+            fs::write(&expected, run_coverage(&file, &f_out, &args.compiler_path)?)?;
+            println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=expected.display());
+            covered.push(file.clone());
+        }
+
+        let golden = golden_path(&args, &file);
+        if !golden.exists() {
+            continue;
+        }
+        let Some(asm) = compile_asm(&file, &f_out, &args.compiler_path)? else {
+            continue;
+        };
+        fs::write(&golden, asm)?;
+        println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=golden.display());
+    }
+
+    for (file, expected) in dir_tests(&args.checked_dir, "mcl")? {
+        let f_out = PathBuf::from(&args.output).join(file.file_stem().unwrap()).with_extension("checked");
+        let (message, _) = run_checked(&file, &f_out, &args.compiler_path, CHECKED_OPTS[0])?;
+        fs::write(&expected, message + "\n")?;
+        println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=expected.display());
+    }
+
+    for (script, expected) in dir_tests(&args.repl_dir, "in")? {
+        let f_out = PathBuf::from(&args.output).join(script.file_name().unwrap());
+        fs::write(&expected, run_repl(&script, &f_out, &args.compiler_path)?)?;
+        println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=expected.display());
+    }
+
+    for (file, expected) in dir_tests(&args.docs_dir, "mcl")? {
+        fs::write(&expected, run_docs(&file, &args.compiler_path)?)?;
+        println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=expected.display());
+    }
+
+    let expected = PathBuf::from(&args.coverage_dir).join("merged.lcov");
+    if expected.exists() {
+        fs::write(&expected, run_coverage_merge(&args, &covered)?)?;
+        println!("{b}[ {g}REC{rs}{b} ]{rs} {f}", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=expected.display());
+    }
+    Ok(())
+}
+
+fn compare_golden(asm: &str, golden: &str, f_in: &Path) -> Result<()> {
+    if asm != golden {
+        println!("{b}[ {r}ERR{rs}{b} ]{rs} {f} assembly differs from the golden file", r=color::FG_RED, rs=color::RESET, b=color::BRIGHT, f=f_in.display());
+        let line = asm.lines().zip(golden.lines()).position(|(a, g)| a != g)
+            .unwrap_or_else(|| asm.lines().count().min(golden.lines().count()));
+        println!("first difference on line {}:", line + 1);
+        println!("compiled:\n{}", asm.lines().nth(line).unwrap_or("<eof>"));
+        println!("golden:\n{}", golden.lines().nth(line).unwrap_or("<eof>"));
+        return Err(eyre!("Testing failed"));
+    }
+
+    println!("{b}[ {g}OK{rs}{b} ]{rs} {f} assembly", g=color::FG_GREEN, rs=color::RESET, b=color::BRIGHT, f=f_in.display());
     Ok(())
 }
 
@@ -129,7 +776,7 @@ fn compare_results(intp: &TestOutput, comp: &TestOutput, f_in: &Path) -> Result<
 #[command(author, version, about, long_about = None)]
 struct Args {
 
-    /// Mode, allowed modes: test, record
+    /// Mode, allowed modes: test, record (writes the golden assembly, debugger output, profile, coverage, --checked, --docs and repl files)
     #[arg(long, short)]
     mode: String,
     
@@ -151,8 +798,55 @@ struct Args {
 
     /// Compiler path
     #[arg(long, short, default_value_t=String::from("./target/release/mclangc"))]
-    compiler_path: String
+    compiler_path: String,
+
+    /// Targets to compile the tests for, each one is compared against the interpreter. Without qemu-aarch64 the linux_aarch64 tests are only assembled
+    #[arg(long, short, default_values_t=[String::from("linux_x86_64")])]
+    target: Vec<String>,
+
+    /// Optimisation levels to compile the tests with, each one is compared against the interpreter
+    #[arg(long, short='O', default_values_t=[String::from("0"), String::from("1")])]
+    optimisation: Vec<String>,
+
+    /// What to compile the tests to before running them (exe, llvm or qbe), each one is compared against the interpreter
+    #[arg(long, short, default_values_t=[String::from("exe")])]
+    emit: Vec<String>,
+
+    /// Folder with the expected -O1 assembly of the tests, tests without a file there are not checked or recorded
+    #[arg(long, short, default_value_t=String::from("./tests/asm"))]
+    golden: String,
+
+    /// Folder with the files a test starts with (`<test>/`), interpreted tests run in a sandbox seeded with them and compiled ones in a copy
+    #[arg(long, default_value_t=String::from("./tests/fs"))]
+    fs_dir: String,
+
+    /// Folder with the expected profiles (`<test>.prof` and `<test>.prof.folded`), only tests with one are profiled
+    #[arg(long, default_value_t=String::from("./tests/profile"))]
+    profile_dir: String,
+
+    /// Folder with programs that have to fail a check when built with --checked (`<test>.mcl`) and the message they fail with (`<test>.out`)
+    #[arg(long, default_value_t=String::from("./tests/checked"))]
+    checked_dir: String,
+
+    /// Folder with programs (`<test>.mcl`) and what `mclangc --docs` prints for them (`<test>.out`)
+    #[arg(long, default_value_t=String::from("./tests/docs"))]
+    docs_dir: String,
+
+    /// Folder with lines to type into the repl (`<test>.in`) and what it prints for them (`<test>.out`)
+    #[arg(long, default_value_t=String::from("./tests/repl"))]
+    repl_dir: String,
+
+    /// Folder with the expected coverage of tests (`<test>.lcov`) and of all of them merged (`merged.lcov`)
+    #[arg(long, default_value_t=String::from("./tests/coverage"))]
+    coverage_dir: String,
+
+    /// Merge the coverage of every interpreted test into one LCOV file at this path
+    #[arg(long)]
+    coverage: Option<String>,
 
+    /// Folder with debugger scripts (`<test>.mdb`) and their expected output (`<test>.out`)
+    #[arg(long, default_value_t=String::from("./tests/debug"))]
+    debug_dir: String,
 
 }
 
@@ -161,7 +855,7 @@ fn main() -> Result<()> {
     fs::create_dir_all(&args.output)?;
     match args.mode.as_str() {
         "test" => run_tests(args),
-        "record" => todo!("Implement test result recording"),
+        "record" => record_golden(args),
         s => {
             eprintln!("Unknown mode '{s}'");
             return Err(eyre!("Bad subcommand"));
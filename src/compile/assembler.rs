@@ -0,0 +1,991 @@
+//! Assembler for the subset of nasm that `linux_x86_64::compile` emits.
+//!
+//! Every line is encoded straight to x86_64 machine code. References to labels are
+//! always encoded with fixed size fields (rel32, disp32, imm32 or imm64) so a single
+//! pass is enough, the label values are filled in by `elf` once the layout is known.
+use std::collections::HashMap;
+
+use color_eyre::Result;
+use eyre::eyre;
+
+use crate::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    Bss
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    /// 64 bit absolute address
+    Abs64,
+    /// 32 bit sign extended absolute address
+    Abs32S,
+    /// 32 bit address relative to the patched field
+    Pc32
+}
+
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    pub section: Section,
+    pub offset: usize,
+    pub symbol: String,
+    pub kind: RelocKind,
+    pub addend: i64
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: usize,
+    pub global: bool
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: usize,
+    pub symbols: Vec<Symbol>,
+    pub relocs: Vec<Reloc>
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reg {
+    num: u8,
+    size: u8
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Mem {
+    size: Option<u8>,
+    base: Option<Reg>,
+    index: Option<(Reg, u8)>,
+    disp: i64,
+    symbol: Option<String>,
+    rel: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Reg(Reg),
+    Imm(i64, Option<String>),
+    Mem(Mem)
+}
+
+impl Operand {
+    fn size(&self) -> Option<u8> {
+        match self {
+            Operand::Reg(r) => Some(r.size),
+            Operand::Mem(m) => m.size,
+            Operand::Imm(..) => None
+        }
+    }
+}
+
+/// What goes into the reg field of a modrm byte
+#[derive(Debug, Clone, Copy)]
+enum RegField {
+    Reg(Reg),
+    Digit(u8)
+}
+
+struct Fixup {
+    pos: usize,
+    symbol: String,
+    kind: RelocKind,
+    addend: i64
+}
+
+#[derive(Default)]
+struct Inst {
+    bytes: Vec<u8>,
+    fixups: Vec<Fixup>
+}
+
+const REGS: [[&str; 16]; 4] = [
+    ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"],
+    ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"],
+    ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"],
+    ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"],
+];
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFTS: [(&str, u8); 7] = [("rol", 0), ("ror", 1), ("rcl", 2), ("rcr", 3), ("shl", 4), ("sal", 4), ("shr", 5)];
+const GROUP3: [(&str, u8); 5] = [("not", 2), ("neg", 3), ("mul", 4), ("div", 6), ("idiv", 7)];
+
+fn parse_reg(s: &str) -> Option<Reg> {
+    for (i, names) in REGS.iter().enumerate() {
+        if let Some(num) = names.iter().position(|n| *n == s) {
+            #[allow(clippy::cast_possible_truncation)]
+            return Some(Reg { num: num as u8, size: 1 << i });
+        }
+    }
+    None
+}
+
+fn parse_cond(s: &str) -> Option<u8> {
+    Some(match s {
+        "o" => 0,
+        "no" => 1,
+        "b" | "c" | "nae" => 2,
+        "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" | "na" => 6,
+        "a" | "nbe" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "p" | "pe" => 10,
+        "np" | "po" => 11,
+        "l" | "nge" => 12,
+        "ge" | "nl" => 13,
+        "le" | "ng" => 14,
+        "g" | "nle" => 15,
+        _ => return None
+    })
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (neg, s) = if let Some(s) = s.strip_prefix('-') { (true, s) } else { (false, s) };
+    let v = if let Some(h) = s.strip_prefix("0x") {
+        u64::from_str_radix(h, 16).ok()?
+    } else if let Some(b) = s.strip_prefix("0b") {
+        u64::from_str_radix(b, 2).ok()?
+    } else {
+        s.parse::<u64>().ok()?
+    };
+    #[allow(clippy::cast_possible_wrap)]
+    let v = v as i64;
+    Some(if neg { v.wrapping_neg() } else { v })
+}
+
+fn fits_i8(v: i64) -> bool {
+    i8::try_from(v).is_ok()
+}
+
+fn fits_i32(v: i64) -> bool {
+    i32::try_from(v).is_ok()
+}
+
+pub struct Assembler {
+    obj: Object,
+    section: Section,
+    last_label: String,
+    globals: Vec<String>,
+    line: usize
+}
+
+/// Assemble nasm source into an unlinked object
+pub fn assemble(code: &str) -> Result<Object> {
+    let mut asm = Assembler {
+        obj: Object::default(),
+        section: Section::Text,
+        last_label: String::new(),
+        globals: Vec::new(),
+        line: 0
+    };
+
+    for (i, line) in code.lines().enumerate() {
+        asm.line = i + 1;
+        asm.line(line)?;
+    }
+
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for (i, s) in asm.obj.symbols.iter().enumerate() {
+        if names.insert(s.name.clone(), i).is_some() {
+            error!("assembler: label '{}' redefined", s.name);
+            return Err(eyre!("Label redefined"));
+        }
+    }
+    for g in &asm.globals {
+        let Some(i) = names.get(g) else {
+            error!("assembler: global '{g}' is never defined");
+            return Err(eyre!("Undefined global"));
+        };
+        asm.obj.symbols[*i].global = true;
+    }
+    for r in &asm.obj.relocs {
+        if !names.contains_key(&r.symbol) {
+            error!("assembler: undefined symbol '{}'", r.symbol);
+            return Err(eyre!("Undefined symbol"));
+        }
+    }
+
+    Ok(asm.obj)
+}
+
+impl Assembler {
+    fn err<T>(&self, msg: &str) -> Result<T> {
+        error!("assembler: line {}: {msg}", self.line);
+        Err(eyre!("Assembling failed"))
+    }
+
+    fn full_name(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{name}", self.last_label)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn offset(&self) -> usize {
+        match self.section {
+            Section::Text => self.obj.text.len(),
+            Section::Data => self.obj.data.len(),
+            Section::Bss => self.obj.bss_size
+        }
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (first, rest) = match line.split_once(char::is_whitespace) {
+            Some((f, r)) => (f, r.trim()),
+            None => (line, "")
+        };
+
+        if let Some(label) = first.strip_suffix(':') {
+            if !label.starts_with('.') {
+                self.last_label = label.to_string();
+            }
+            let name = self.full_name(label);
+            self.obj.symbols.push(Symbol { name, section: self.section, offset: self.offset(), global: false });
+            if rest.is_empty() {
+                return Ok(());
+            }
+            return self.line(rest);
+        }
+
+        let directive = first.to_lowercase();
+        match directive.as_str() {
+            "bits" => {
+                if rest != "64" {
+                    return self.err("only 'BITS 64' is supported");
+                }
+            }
+            "segment" | "section" => {
+                self.section = match rest {
+                    ".text" => Section::Text,
+                    ".data" => Section::Data,
+                    ".bss" => Section::Bss,
+                    s => return self.err(&format!("unknown section '{s}'"))
+                };
+            }
+            "global" => {
+                for g in rest.split(',') {
+                    self.globals.push(g.trim().to_string());
+                }
+            }
+            "db" | "dw" | "dd" | "dq" => {
+                let size = match directive.as_str() { "db" => 1, "dw" => 2, "dd" => 4, _ => 8 };
+                self.data(size, rest)?;
+            }
+            "resb" | "resw" | "resd" | "resq" => {
+                let size = match directive.as_str() { "resb" => 1, "resw" => 2, "resd" => 4, _ => 8 };
+                let Some(n) = parse_number(rest).and_then(|n| usize::try_from(n).ok()) else {
+                    return self.err(&format!("bad reserve count '{rest}'"));
+                };
+                if self.section != Section::Bss {
+                    return self.err("reserving space is only supported in .bss");
+                }
+                self.obj.bss_size += n * size;
+            }
+            m => {
+                if self.section != Section::Text {
+                    return self.err("instructions are only supported in .text");
+                }
+                let mut ops = Vec::new();
+                if !rest.is_empty() {
+                    for o in rest.split(',') {
+                        ops.push(self.operand(o.trim())?);
+                    }
+                }
+                let inst = self.instruction(m, &ops)?;
+                let start = self.obj.text.len();
+                for f in inst.fixups {
+                    self.obj.relocs.push(Reloc {
+                        section: Section::Text,
+                        offset: start + f.pos,
+                        symbol: f.symbol,
+                        kind: f.kind,
+                        addend: f.addend
+                    });
+                }
+                self.obj.text.extend(inst.bytes);
+            }
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, size: usize, rest: &str) -> Result<()> {
+        if self.section != Section::Data {
+            return self.err("data is only supported in .data");
+        }
+        for item in rest.split(',') {
+            let item = item.trim();
+            if let Some(s) = item.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                self.obj.data.extend(s.bytes());
+            } else if let Some(v) = parse_number(item) {
+                self.obj.data.extend(&v.to_le_bytes()[..size]);
+            } else if size == 8 {
+                let symbol = self.full_name(item);
+                self.obj.relocs.push(Reloc { section: Section::Data, offset: self.obj.data.len(), symbol, kind: RelocKind::Abs64, addend: 0 });
+                self.obj.data.extend([0; 8]);
+            } else {
+                return self.err(&format!("bad data item '{item}'"));
+            }
+        }
+        Ok(())
+    }
+
+    fn operand(&self, s: &str) -> Result<Operand> {
+        let mut s = s;
+        let mut size = None;
+        for (kw, sz) in [("byte", 1), ("word", 2), ("dword", 4), ("qword", 8)] {
+            if s.len() > kw.len() && s[..kw.len()].eq_ignore_ascii_case(kw) && s[kw.len()..].starts_with([' ', '[']) {
+                size = Some(sz);
+                s = s[kw.len()..].trim_start();
+                break;
+            }
+        }
+
+        if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            return self.memory(inner, size).map(Operand::Mem);
+        }
+        if size.is_some() {
+            return self.err(&format!("size keyword on a non memory operand '{s}'"));
+        }
+        if let Some(r) = parse_reg(&s.to_lowercase()) {
+            return Ok(Operand::Reg(r));
+        }
+        if let Some(v) = parse_number(s) {
+            return Ok(Operand::Imm(v, None));
+        }
+        // names can contain '-', so only treat it as an offset if a number follows
+        if let Some(at) = s.rfind(['+', '-']) {
+            if let Some(add) = parse_number(s[at..].trim_start_matches('+')) {
+                return Ok(Operand::Imm(add, Some(self.full_name(s[..at].trim()))));
+            }
+        }
+        Ok(Operand::Imm(0, Some(self.full_name(s))))
+    }
+
+    fn memory(&self, s: &str, size: Option<u8>) -> Result<Mem> {
+        let mut mem = Mem { size, base: None, index: None, disp: 0, symbol: None, rel: false };
+        let mut s = s.trim();
+        if s.len() > 4 && s[..4].eq_ignore_ascii_case("rel ") {
+            mem.rel = true;
+            s = s[4..].trim();
+        }
+
+        let mut terms: Vec<(bool, String)> = Vec::new();
+        let mut cur = String::new();
+        let mut neg = false;
+        for c in s.chars() {
+            let t = cur.trim();
+            let in_name = t.starts_with(|c: char| c.is_alphabetic() || c == '_' || c == '.') && !t.contains('*') && parse_reg(&t.to_lowercase()).is_none();
+            match c {
+                '-' if in_name => cur.push(c),
+                '+' | '-' => {
+                    if !t.is_empty() {
+                        terms.push((neg, t.to_string()));
+                    }
+                    cur.clear();
+                    neg = c == '-';
+                }
+                _ => cur.push(c)
+            }
+        }
+        if !cur.trim().is_empty() {
+            terms.push((neg, cur.trim().to_string()));
+        }
+
+        for (neg, t) in terms {
+            if let Some((r, sc)) = t.split_once('*') {
+                let (Some(r), Some(sc)) = (parse_reg(&r.trim().to_lowercase()), parse_number(sc.trim())) else {
+                    return self.err(&format!("bad scaled index '{t}'"));
+                };
+                if neg || r.size != 8 || !matches!(sc, 1 | 2 | 4 | 8) || mem.index.is_some() {
+                    return self.err(&format!("bad scaled index '{t}'"));
+                }
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                { mem.index = Some((r, sc as u8)); }
+            } else if let Some(r) = parse_reg(&t.to_lowercase()) {
+                if neg || r.size != 8 {
+                    return self.err(&format!("bad address register '{t}'"));
+                }
+                if mem.base.is_none() {
+                    mem.base = Some(r);
+                } else if mem.index.is_none() {
+                    mem.index = Some((r, 1));
+                } else {
+                    return self.err("too many registers in address");
+                }
+            } else if let Some(v) = parse_number(&t) {
+                mem.disp += if neg { -v } else { v };
+            } else {
+                if neg || mem.symbol.is_some() {
+                    return self.err(&format!("bad address term '{t}'"));
+                }
+                mem.symbol = Some(self.full_name(&t));
+            }
+        }
+
+        if let Some((r, _)) = mem.index {
+            if r.num == 4 {
+                return self.err("rsp cannot be used as an index");
+            }
+        }
+        if mem.rel && (mem.base.is_some() || mem.index.is_some()) {
+            return self.err("rip relative addresses cannot use registers");
+        }
+        Ok(mem)
+    }
+
+    /// Encode modrm, sib and displacement for `rm`, returns the rex bits
+    /// (without W) and the bytes, `imm_len` is the amount of immediate bytes
+    /// following the displacement, needed for rip relative fixups
+    fn modrm(&self, reg: u8, rm: &Operand, imm_len: usize, fixups: &mut Vec<Fixup>, at: usize) -> Result<(u8, Vec<u8>)> {
+        let mut bytes = Vec::new();
+        let mut rex = (reg >> 3) << 2;
+        let reg = reg & 7;
+        match rm {
+            Operand::Reg(r) => {
+                rex |= r.num >> 3;
+                bytes.push(0xC0 | (reg << 3) | (r.num & 7));
+            }
+            Operand::Mem(m) => {
+                let disp32 = |bytes: &mut Vec<u8>, fixups: &mut Vec<Fixup>, kind: RelocKind| -> Result<()> {
+                    if let Some(sym) = &m.symbol {
+                        let addend = if kind == RelocKind::Pc32 {
+                            #[allow(clippy::cast_possible_wrap)]
+                            { m.disp - 4 - imm_len as i64 }
+                        } else {
+                            m.disp
+                        };
+                        fixups.push(Fixup { pos: at + bytes.len(), symbol: sym.clone(), kind, addend });
+                        bytes.extend([0; 4]);
+                    } else {
+                        if !fits_i32(m.disp) {
+                            return self.err("displacement does not fit in 32 bits");
+                        }
+                        #[allow(clippy::cast_possible_truncation)]
+                        bytes.extend((m.disp as i32).to_le_bytes());
+                    }
+                    Ok(())
+                };
+
+                if m.rel {
+                    bytes.push((reg << 3) | 0b101);
+                    disp32(&mut bytes, fixups, RelocKind::Pc32)?;
+                    return Ok((rex, bytes));
+                }
+
+                match (m.base, m.index) {
+                    (None, None) => {
+                        bytes.push((reg << 3) | 0b100);
+                        bytes.push(0x25);
+                        disp32(&mut bytes, fixups, RelocKind::Abs32S)?;
+                    }
+                    (None, Some((idx, sc))) => {
+                        rex |= (idx.num >> 3) << 1;
+                        bytes.push((reg << 3) | 0b100);
+                        bytes.push((sc.trailing_zeros() as u8) << 6 | (idx.num & 7) << 3 | 0b101);
+                        disp32(&mut bytes, fixups, RelocKind::Abs32S)?;
+                    }
+                    (Some(base), index) => {
+                        rex |= base.num >> 3;
+                        let md = if m.symbol.is_some() || !fits_i8(m.disp) {
+                            0b10
+                        } else if m.disp != 0 || base.num & 7 == 5 {
+                            0b01
+                        } else {
+                            0b00
+                        };
+                        if let Some((idx, sc)) = index {
+                            rex |= (idx.num >> 3) << 1;
+                            bytes.push(md << 6 | (reg << 3) | 0b100);
+                            bytes.push((sc.trailing_zeros() as u8) << 6 | (idx.num & 7) << 3 | (base.num & 7));
+                        } else if base.num & 7 == 4 {
+                            bytes.push(md << 6 | (reg << 3) | 0b100);
+                            bytes.push(0x24);
+                        } else {
+                            bytes.push(md << 6 | (reg << 3) | (base.num & 7));
+                        }
+                        match md {
+                            0b01 => {
+                                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                                bytes.push(m.disp as i8 as u8);
+                            }
+                            0b10 => disp32(&mut bytes, fixups, RelocKind::Abs32S)?,
+                            _ => ()
+                        }
+                    }
+                }
+            }
+            Operand::Imm(..) => return self.err("expected a register or memory operand")
+        }
+        Ok((rex, bytes))
+    }
+
+    /// Emit `[66] [rex] opcode modrm..` for an instruction of operand size `size`
+    fn rm_inst(&self, inst: &mut Inst, size: u8, opcode: &[u8], reg: RegField, rm: &Operand, imm_len: usize) -> Result<()> {
+        let mut force_rex = false;
+        let reg = match reg {
+            RegField::Reg(r) => {
+                force_rex |= r.size == 1 && (4..8).contains(&r.num);
+                r.num
+            }
+            RegField::Digit(d) => d
+        };
+        if let Operand::Reg(r) = rm {
+            force_rex |= r.size == 1 && (4..8).contains(&r.num);
+        }
+
+        let mut fixups = Vec::new();
+        let (rex_bits, modrm) = self.modrm(reg, rm, imm_len, &mut fixups, 0)?;
+        let rex = rex_bits | if size == 8 { 0b1000 } else { 0 };
+
+        if size == 2 {
+            inst.bytes.push(0x66);
+        }
+        if rex != 0 || force_rex {
+            inst.bytes.push(0x40 | rex);
+        }
+        inst.bytes.extend(opcode);
+        let start = inst.bytes.len();
+        for mut f in fixups {
+            f.pos += start;
+            inst.fixups.push(f);
+        }
+        inst.bytes.extend(modrm);
+        Ok(())
+    }
+
+    /// Append an immediate of `len` bytes
+    fn imm(&self, inst: &mut Inst, len: usize, v: i64, sym: Option<&String>) -> Result<()> {
+        if let Some(sym) = sym {
+            let kind = match len {
+                8 => RelocKind::Abs64,
+                4 => RelocKind::Abs32S,
+                _ => return self.err("symbols can only be used in 32 or 64 bit immediates")
+            };
+            inst.fixups.push(Fixup { pos: inst.bytes.len(), symbol: sym.clone(), kind, addend: v });
+            inst.bytes.extend(&[0; 8][..len]);
+        } else {
+            inst.bytes.extend(&v.to_le_bytes()[..len]);
+        }
+        Ok(())
+    }
+
+    fn rel32(inst: &mut Inst, sym: &str, v: i64) {
+        inst.fixups.push(Fixup { pos: inst.bytes.len(), symbol: sym.to_string(), kind: RelocKind::Pc32, addend: v - 4 });
+        inst.bytes.extend([0; 4]);
+    }
+
+    fn op_size(&self, a: &Operand, b: Option<&Operand>) -> Result<u8> {
+        match (a.size(), b.and_then(Operand::size)) {
+            (Some(x), Some(y)) if x != y => self.err("operand size mismatch"),
+            (Some(x), _) | (None, Some(x)) => Ok(x),
+            (None, None) => self.err("operation size not specified")
+        }
+    }
+
+    fn instruction(&self, m: &str, ops: &[Operand]) -> Result<Inst> {
+        let mut inst = Inst::default();
+        let i = &mut inst;
+
+        if let Some(n) = ALU.iter().position(|a| *a == m) {
+            #[allow(clippy::cast_possible_truncation)]
+            let n = n as u8;
+            let [a, b] = ops else { return self.err(&format!("'{m}' takes 2 operands")) };
+            let size = self.op_size(a, Some(b))?;
+            let byte = u8::from(size != 1);
+            match (a, b) {
+                (_, Operand::Reg(r)) => self.rm_inst(i, size, &[n * 8 + byte], RegField::Reg(*r), a, 0)?,
+                (Operand::Reg(r), Operand::Mem(_)) => self.rm_inst(i, size, &[n * 8 + 2 + byte], RegField::Reg(*r), b, 0)?,
+                (_, Operand::Imm(v, sym)) => {
+                    if size == 1 {
+                        self.rm_inst(i, size, &[0x80], RegField::Digit(n), a, 1)?;
+                        self.imm(i, 1, *v, None)?;
+                    } else if sym.is_none() && fits_i8(*v) {
+                        self.rm_inst(i, size, &[0x83], RegField::Digit(n), a, 1)?;
+                        self.imm(i, 1, *v, None)?;
+                    } else {
+                        let len = if size == 2 { 2 } else { 4 };
+                        self.rm_inst(i, size, &[0x81], RegField::Digit(n), a, len)?;
+                        self.imm(i, len, *v, sym.as_ref())?;
+                    }
+                }
+                _ => return self.err(&format!("bad operands for '{m}'"))
+            }
+            return Ok(inst);
+        }
+
+        if let Some((_, n)) = SHIFTS.iter().find(|s| s.0 == m).copied().or(if m == "sar" { Some(("sar", 7)) } else { None }) {
+            let [a, b] = ops else { return self.err(&format!("'{m}' takes 2 operands")) };
+            let size = self.op_size(a, None)?;
+            let byte = u8::from(size != 1);
+            match b {
+                Operand::Reg(Reg { num: 1, size: 1 }) => self.rm_inst(i, size, &[0xD2 + byte], RegField::Digit(n), a, 0)?,
+                Operand::Imm(1, None) => self.rm_inst(i, size, &[0xD0 + byte], RegField::Digit(n), a, 0)?,
+                Operand::Imm(v, None) => {
+                    self.rm_inst(i, size, &[0xC0 + byte], RegField::Digit(n), a, 1)?;
+                    self.imm(i, 1, *v, None)?;
+                }
+                _ => return self.err(&format!("'{m}' can only shift by cl or an immediate"))
+            }
+            return Ok(inst);
+        }
+
+        if let Some((_, n)) = GROUP3.iter().find(|s| s.0 == m) {
+            let [a] = ops else { return self.err(&format!("'{m}' takes 1 operand")) };
+            let size = self.op_size(a, None)?;
+            self.rm_inst(i, size, &[0xF6 + u8::from(size != 1)], RegField::Digit(*n), a, 0)?;
+            return Ok(inst);
+        }
+
+        if let Some(cc) = m.strip_prefix("cmov").and_then(parse_cond) {
+            let [Operand::Reg(r), b] = ops else { return self.err(&format!("bad operands for '{m}'")) };
+            let size = self.op_size(&ops[0], Some(b))?;
+            self.rm_inst(i, size, &[0x0F, 0x40 + cc], RegField::Reg(*r), b, 0)?;
+            return Ok(inst);
+        }
+
+        if let Some(cc) = m.strip_prefix("set").and_then(parse_cond) {
+            let [a] = ops else { return self.err(&format!("'{m}' takes 1 operand")) };
+            if self.op_size(a, None)? != 1 {
+                return self.err(&format!("'{m}' needs a byte operand"));
+            }
+            self.rm_inst(i, 1, &[0x0F, 0x90 + cc], RegField::Digit(0), a, 0)?;
+            return Ok(inst);
+        }
+
+        if let Some(cc) = m.strip_prefix('j').and_then(parse_cond) {
+            let [Operand::Imm(v, Some(sym))] = ops else { return self.err(&format!("'{m}' needs a label")) };
+            i.bytes.extend([0x0F, 0x80 + cc]);
+            Self::rel32(i, sym, *v);
+            return Ok(inst);
+        }
+
+        match (m, ops) {
+            ("ret", []) => i.bytes.push(0xC3),
+            ("syscall", []) => i.bytes.extend([0x0F, 0x05]),
+            ("nop", []) => i.bytes.push(0x90),
+            ("cqo", []) => i.bytes.extend([0x48, 0x99]),
+            ("ud2", []) => i.bytes.extend([0x0F, 0x0B]),
+            ("int3", []) => i.bytes.push(0xCC),
+
+            ("jmp" | "call", [Operand::Imm(v, Some(sym))]) => {
+                i.bytes.push(if m == "jmp" { 0xE9 } else { 0xE8 });
+                Self::rel32(i, sym, *v);
+            }
+            ("jmp" | "call", [a]) => {
+                if self.op_size(a, None).unwrap_or(8) != 8 {
+                    return self.err(&format!("'{m}' needs a 64 bit operand"));
+                }
+                self.rm_inst(i, 4, &[0xFF], RegField::Digit(if m == "jmp" { 4 } else { 2 }), a, 0)?;
+            }
+
+            ("push" | "pop", [Operand::Reg(r)]) => {
+                if r.size != 8 {
+                    return self.err(&format!("'{m}' needs a 64 bit register"));
+                }
+                if r.num >= 8 {
+                    i.bytes.push(0x41);
+                }
+                i.bytes.push(if m == "push" { 0x50 } else { 0x58 } + (r.num & 7));
+            }
+            ("push", [Operand::Imm(v, sym)]) => {
+                if sym.is_none() && fits_i8(*v) {
+                    i.bytes.push(0x6A);
+                    self.imm(i, 1, *v, None)?;
+                } else {
+                    if !fits_i32(*v) {
+                        return self.err("push immediate does not fit in 32 bits");
+                    }
+                    i.bytes.push(0x68);
+                    self.imm(i, 4, *v, sym.as_ref())?;
+                }
+            }
+            ("push" | "pop", [a @ Operand::Mem(_)]) => {
+                if self.op_size(a, None).unwrap_or(8) != 8 {
+                    return self.err(&format!("'{m}' needs a qword operand"));
+                }
+                if m == "push" {
+                    self.rm_inst(i, 4, &[0xFF], RegField::Digit(6), a, 0)?;
+                } else {
+                    self.rm_inst(i, 4, &[0x8F], RegField::Digit(0), a, 0)?;
+                }
+            }
+
+            ("inc" | "dec", [a]) => {
+                let size = self.op_size(a, None)?;
+                self.rm_inst(i, size, &[0xFE + u8::from(size != 1)], RegField::Digit(u8::from(m == "dec")), a, 0)?;
+            }
+
+            ("imul", [a]) => {
+                let size = self.op_size(a, None)?;
+                self.rm_inst(i, size, &[0xF6 + u8::from(size != 1)], RegField::Digit(5), a, 0)?;
+            }
+            ("imul", [Operand::Reg(r), b @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+                let size = self.op_size(&ops[0], Some(b))?;
+                self.rm_inst(i, size, &[0x0F, 0xAF], RegField::Reg(*r), b, 0)?;
+            }
+
+            ("test", [a, b]) => {
+                let size = self.op_size(a, Some(b))?;
+                let byte = u8::from(size != 1);
+                match b {
+                    Operand::Reg(r) => self.rm_inst(i, size, &[0x84 + byte], RegField::Reg(*r), a, 0)?,
+                    Operand::Imm(v, None) => {
+                        let len = match size { 1 => 1, 2 => 2, _ => 4 };
+                        self.rm_inst(i, size, &[0xF6 + byte], RegField::Digit(0), a, len)?;
+                        self.imm(i, len, *v, None)?;
+                    }
+                    _ => return self.err("bad operands for 'test'")
+                }
+            }
+
+            ("lea", [Operand::Reg(r), b @ Operand::Mem(_)]) => {
+                self.rm_inst(i, r.size, &[0x8D], RegField::Reg(*r), b, 0)?;
+            }
+
+            ("movzx" | "movsx", [Operand::Reg(r), b]) => {
+                let from = self.op_size(b, None)?;
+                let op = match (m, from) {
+                    ("movzx", 1) => 0xB6,
+                    ("movzx", 2) => 0xB7,
+                    ("movsx", 1) => 0xBE,
+                    ("movsx", 2) => 0xBF,
+                    _ => return self.err(&format!("bad source size for '{m}'"))
+                };
+                self.rm_inst(i, r.size, &[0x0F, op], RegField::Reg(*r), b, 0)?;
+            }
+
+            ("mov", [Operand::Reg(r), Operand::Imm(v, sym)]) => {
+                let rex_b = u8::from(r.num >= 8);
+                match r.size {
+                    8 if sym.is_none() && fits_i32(*v) => {
+                        self.rm_inst(i, 8, &[0xC7], RegField::Digit(0), &ops[0], 4)?;
+                        self.imm(i, 4, *v, None)?;
+                    }
+                    8 if sym.is_none() && u32::try_from(*v).is_ok() => {
+                        if rex_b != 0 {
+                            i.bytes.push(0x41);
+                        }
+                        i.bytes.push(0xB8 + (r.num & 7));
+                        self.imm(i, 4, *v, None)?;
+                    }
+                    8 => {
+                        i.bytes.push(0x48 | rex_b);
+                        i.bytes.push(0xB8 + (r.num & 7));
+                        self.imm(i, 8, *v, sym.as_ref())?;
+                    }
+                    _ => {
+                        if sym.is_some() {
+                            return self.err("symbols can only be moved into 64 bit registers");
+                        }
+                        if r.size == 2 {
+                            i.bytes.push(0x66);
+                        }
+                        if rex_b != 0 || (r.size == 1 && (4..8).contains(&r.num)) {
+                            i.bytes.push(0x40 | rex_b);
+                        }
+                        i.bytes.push(if r.size == 1 { 0xB0 } else { 0xB8 } + (r.num & 7));
+                        self.imm(i, r.size as usize, *v, None)?;
+                    }
+                }
+            }
+            ("mov", [a @ Operand::Mem(_), Operand::Imm(v, sym)]) => {
+                let size = self.op_size(a, None)?;
+                let len = match size { 1 => 1, 2 => 2, _ => 4 };
+                self.rm_inst(i, size, &[0xC6 + u8::from(size != 1)], RegField::Digit(0), a, len)?;
+                self.imm(i, len, *v, sym.as_ref())?;
+            }
+            ("mov", [a, Operand::Reg(r)]) => {
+                let size = self.op_size(a, Some(&ops[1]))?;
+                self.rm_inst(i, size, &[0x88 + u8::from(size != 1)], RegField::Reg(*r), a, 0)?;
+            }
+            ("mov", [Operand::Reg(r), b @ Operand::Mem(_)]) => {
+                let size = self.op_size(&ops[0], Some(b))?;
+                self.rm_inst(i, size, &[0x8A + u8::from(size != 1)], RegField::Reg(*r), b, 0)?;
+            }
+
+            _ => return self.err(&format!("unsupported instruction '{m}' with {} operand(s)", ops.len()))
+        }
+        Ok(inst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, RelocKind, Section};
+
+    /// Every line has to assemble to its bytes, the expected bytes are what
+    /// GNU as encodes the same instruction to
+    fn check(cases: &[(&str, &[u8])]) {
+        for (line, bytes) in cases {
+            let obj = assemble(&format!("segment .text\n    {line}\n")).expect(line);
+            assert_eq!(obj.text, *bytes, "{line}");
+        }
+    }
+
+    #[test]
+    fn mov_immediates() {
+        check(&[
+            ("mov rax, 60", &[0x48, 0xC7, 0xC0, 0x3C, 0, 0, 0]),
+            ("mov rdi, 0", &[0x48, 0xC7, 0xC7, 0, 0, 0, 0]),
+            ("mov edi, 1", &[0xBF, 1, 0, 0, 0]),
+            ("mov r12, 5", &[0x49, 0xC7, 0xC4, 5, 0, 0, 0]),
+            ("mov rax, -1", &[0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]),
+            // zero extended through the 32 bit register
+            ("mov rax, 4294967295", &[0xB8, 0xFF, 0xFF, 0xFF, 0xFF]),
+            ("mov r9, -3689348814741910323", &[0x49, 0xB9, 0xCD, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]),
+            ("mov BYTE [rsp+31], 10", &[0xC6, 0x44, 0x24, 0x1F, 0x0A]),
+        ]);
+    }
+
+    #[test]
+    fn mov_registers_and_memory() {
+        check(&[
+            ("mov rax, rdi", &[0x48, 0x89, 0xF8]),
+            ("mov r12, rax", &[0x49, 0x89, 0xC4]),
+            ("mov rdi, r12", &[0x4C, 0x89, 0xE7]),
+            ("mov r13, r12", &[0x4D, 0x89, 0xE5]),
+            // rbp and r13 need a zero displacement, rsp and r12 a SIB byte
+            ("mov rbx, qword [rbp]", &[0x48, 0x8B, 0x5D, 0x00]),
+            ("mov qword [rbp], rbx", &[0x48, 0x89, 0x5D, 0x00]),
+            ("mov r14, qword [r13]", &[0x4D, 0x8B, 0x75, 0x00]),
+            ("mov r12, qword [r12]", &[0x4D, 0x8B, 0x24, 0x24]),
+            ("mov qword [r12], r13", &[0x4D, 0x89, 0x2C, 0x24]),
+            ("mov r12d, dword [r12]", &[0x45, 0x8B, 0x24, 0x24]),
+            ("mov dword [rax], ebx", &[0x89, 0x18]),
+            ("mov byte [r12], r13b", &[0x45, 0x88, 0x2C, 0x24]),
+            ("mov BYTE [rcx], al", &[0x88, 0x01]),
+            ("movzx r12, byte [r12]", &[0x4D, 0x0F, 0xB6, 0x24, 0x24]),
+            ("movzx r13, al", &[0x4C, 0x0F, 0xB6, 0xE8]),
+            ("lea rcx, [rsp+15]", &[0x48, 0x8D, 0x4C, 0x24, 0x0F]),
+            ("lea rsi, [rsp+32+rdx]", &[0x48, 0x8D, 0x74, 0x14, 0x20]),
+            ("lea rsi, [rdx+rdx*4]", &[0x48, 0x8D, 0x34, 0x92]),
+        ]);
+    }
+
+    #[test]
+    fn push_pop() {
+        check(&[
+            ("push rax", &[0x50]),
+            ("push r12", &[0x41, 0x54]),
+            ("pop rbx", &[0x5B]),
+            ("pop r13", &[0x41, 0x5D]),
+        ]);
+    }
+
+    #[test]
+    fn arithmetic() {
+        check(&[
+            ("add rbp, 8", &[0x48, 0x83, 0xC5, 0x08]),
+            ("sub rbp, 8", &[0x48, 0x83, 0xED, 0x08]),
+            ("add rsp, 40", &[0x48, 0x83, 0xC4, 0x28]),
+            ("sub rsp, 40", &[0x48, 0x83, 0xEC, 0x28]),
+            ("add r12, r13", &[0x4D, 0x01, 0xEC]),
+            ("sub rdx, rax", &[0x48, 0x29, 0xC2]),
+            ("xor eax, eax", &[0x31, 0xC0]),
+            ("xor rbx, rbx", &[0x48, 0x31, 0xDB]),
+            ("and rax, rbx", &[0x48, 0x21, 0xD8]),
+            ("or rax, rbx", &[0x48, 0x09, 0xD8]),
+            ("shr rdx, 3", &[0x48, 0xC1, 0xEA, 0x03]),
+            ("shl rax, 1", &[0x48, 0xD1, 0xE0]),
+            ("shl rax, cl", &[0x48, 0xD3, 0xE0]),
+            ("mul r9", &[0x49, 0xF7, 0xE1]),
+            ("div rbx", &[0x48, 0xF7, 0xF3]),
+        ]);
+    }
+
+    #[test]
+    fn compare_and_conditions() {
+        check(&[
+            ("cmp rax, 9", &[0x48, 0x83, 0xF8, 0x09]),
+            ("cmp r13, r14", &[0x4D, 0x39, 0xF5]),
+            ("test r13, r13", &[0x4D, 0x85, 0xED]),
+            ("sete al", &[0x0F, 0x94, 0xC0]),
+            ("setl al", &[0x0F, 0x9C, 0xC0]),
+            ("setg al", &[0x0F, 0x9F, 0xC0]),
+            ("cmove rcx, rdx", &[0x48, 0x0F, 0x44, 0xCA]),
+            ("cmovg rcx, rdx", &[0x48, 0x0F, 0x4F, 0xCA]),
+            ("cmovl rcx, rdx", &[0x48, 0x0F, 0x4C, 0xCA]),
+            ("cmovle rcx, rdx", &[0x48, 0x0F, 0x4E, 0xCA]),
+            ("ret", &[0xC3]),
+            ("syscall", &[0x0F, 0x05]),
+        ]);
+    }
+
+    #[test]
+    fn symbols_become_relocations() {
+        let obj = assemble("segment .text
+main:
+.L2:
+    jmp main
+    jz .L2
+    call main
+    mov r12, mem_0
+    lea rbp, [rel ret_stack]
+    mov qword [args_ptr], rsp
+    cmp rbp, ret_stack+2048
+segment .bss
+    mem_0: resb 3
+    args_ptr: resq 1
+    ret_stack: resq 256
+").unwrap();
+        assert_eq!(obj.text, [
+            0xE9, 0, 0, 0, 0,
+            0x0F, 0x84, 0, 0, 0, 0,
+            0xE8, 0, 0, 0, 0,
+            0x49, 0xBC, 0, 0, 0, 0, 0, 0, 0, 0,
+            0x48, 0x8D, 0x2D, 0, 0, 0, 0,
+            0x48, 0x89, 0x24, 0x25, 0, 0, 0, 0,
+            0x48, 0x81, 0xFD, 0, 0, 0, 0,
+        ]);
+        let relocs = obj.relocs.iter().map(|r| (r.offset, r.symbol.as_str(), r.kind, r.addend)).collect::<Vec<_>>();
+        assert_eq!(relocs, [
+            (1, "main", RelocKind::Pc32, -4),
+            // local labels belong to the label before them
+            (7, "main.L2", RelocKind::Pc32, -4),
+            (12, "main", RelocKind::Pc32, -4),
+            (18, "mem_0", RelocKind::Abs64, 0),
+            (29, "ret_stack", RelocKind::Pc32, -4),
+            (37, "args_ptr", RelocKind::Abs32S, 0),
+            (44, "ret_stack", RelocKind::Abs32S, 2048),
+        ]);
+    }
+
+    #[test]
+    fn sections() {
+        let obj = assemble("segment .data\n    str_0: db 104,105\nsegment .bss\n    mem_0: resb 3\n    ret_stack: resq 2\n").unwrap();
+        assert_eq!(obj.data, b"hi");
+        assert_eq!(obj.bss_size, 19);
+        let ret_stack = obj.symbol("ret_stack").unwrap();
+        assert_eq!((ret_stack.section, ret_stack.offset), (Section::Bss, 3));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(assemble("segment .text\n    mov al, rbx\n").is_err());
+        assert!(assemble("segment .text\n    frob rax\n").is_err());
+        assert!(assemble("segment .text\na:\na:\n").is_err());
+        assert!(assemble("segment .text\n    jmp nowhere\n").is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use std::{fs, path::PathBuf, io::Write, collections::HashMap};
+use crate::{constants::{Operator, OpType, KeywordType}, Args, Emit, lerror};
+use color_eyre::Result;
+use crate::compile::commands::{c_compile, linux_x86_64_run};
+use crate::constants::InstructionType;
+use super::{Constant, Memory, Function};
+use eyre::eyre;
+
+/*
+ * The data stack is an explicit uint64_t array, `sp` points one past the top.
+ * Syscall numbers are passed to syscall() as is, so they are still the
+ * linux_x86_64 ones from include/linux.mcl.
+ */
+
+/// Size of the data stack in cells
+const DATA_STACK_SZ: usize = 128 * 1024;
+
+const PRELUDE: &str = r"#include <stdint.h>
+#include <string.h>
+#include <errno.h>
+#include <unistd.h>
+#include <sys/syscall.h>
+
+static uint64_t data_stack[DATA_STACK_SZ];
+static uint64_t *sp = data_stack;
+static uint64_t mcl_argc;
+static char **mcl_argv;
+static char **mcl_envp;
+
+#define PUSH(x) (*sp++ = (uint64_t)(x))
+#define POP() (*--sp)
+#define BINOP(op) do { sp--; sp[-1] = sp[-1] op sp[0]; } while (0)
+#define CMP(op) do { sp--; sp[-1] = (int64_t)sp[-1] op (int64_t)sp[0]; } while (0)
+
+static inline uint64_t load8(uint64_t p) { uint8_t v; memcpy(&v, (void *)(uintptr_t)p, 1); return v; }
+static inline uint64_t load32(uint64_t p) { uint32_t v; memcpy(&v, (void *)(uintptr_t)p, 4); return v; }
+static inline uint64_t load64(uint64_t p) { uint64_t v; memcpy(&v, (void *)(uintptr_t)p, 8); return v; }
+static inline void store8(uint64_t p, uint64_t v) { uint8_t x = (uint8_t)v; memcpy((void *)(uintptr_t)p, &x, 1); }
+static inline void store32(uint64_t p, uint64_t v) { uint32_t x = (uint32_t)v; memcpy((void *)(uintptr_t)p, &x, 4); }
+static inline void store64(uint64_t p, uint64_t v) { memcpy((void *)(uintptr_t)p, &v, 8); }
+
+static uint64_t mcl_syscall(uint64_t n, uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e, uint64_t f) {
+    long r = syscall((long)n, a, b, c, d, e, f);
+    /* return -errno like the raw syscall instruction does */
+    return r == -1 ? (uint64_t)(int64_t)-errno : (uint64_t)r;
+}
+
+static void dbg_print(uint64_t v) {
+    char buf[32];
+    char *p = buf + sizeof(buf);
+    *--p = '\n';
+    do {
+        *--p = (char)('0' + v % 10);
+        v /= 10;
+    } while (v);
+    syscall(SYS_write, 1, p, buf + sizeof(buf) - p);
+}
+";
+
+/// Escape `s` for a C string literal, bytes that are not printable ASCII are
+/// written as three digit octal escapes so they work in any source charset
+fn c_string(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'\\' | b'"' => {
+                out.push('\\');
+                out.push(char::from(b));
+            }
+            b' '..=b'~' => out.push(char::from(b)),
+            _ => out.push_str(&format!("\\{b:03o}")),
+        }
+    }
+    out
+}
+
+/// Turn an mclang word into a valid C identifier
+fn mangle(name: &str) -> String {
+    let mut s = String::from("mcl_");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            s.push(c);
+        } else {
+            s.push_str(&format!("_{:02x}", c as u32));
+        }
+    }
+    s
+}
+
+pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
+    let debug = args.get_opt_level()? < 1;
+
+    let mut of_c = PathBuf::from(&args.out_file);
+    let mut of_a = if args.out_file == *crate::DEFAULT_OUT_FILE {
+        PathBuf::from("/tmp/mclang_comp.c")
+    } else {
+        PathBuf::from(&args.out_file)
+    };
+
+    of_c.set_extension("");
+    of_a.set_extension("c");
+
+    let mut writer: Vec<u8> = Vec::new();
+    let mut memories:  Vec<Memory> = Vec::new();
+    let mut constants:  HashMap<String, Constant> = HashMap::new();
+    let mut functions: Vec<Function> = Vec::new();
+    let mut strings: Vec<String> = Vec::new();
+
+    let mut in_function = false;
+    let mut line = 0;
+
+    let mut ti = 0;
+    while ti < tokens.len() {
+        let token = &tokens[ti];
+
+        // goto labels only exist inside of functions
+        if in_function {
+            if debug {
+                writeln!(writer, "addr_{ti}:;")?;
+                if token.typ == OpType::Instruction(InstructionType::PushInt) {
+                    writeln!(writer, "    // -- {:?} {}", token.typ, token.value)?;
+                } else if matches!(token.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) {
+                    writeln!(writer, "    // -- {:?} {}", token.typ, token.text.escape_debug())?;
+                } else {
+                    writeln!(writer, "    // -- {:?}", token.typ)?;
+                }
+            } else {
+                let after_block = ti > 0 && (
+                    tokens[ti-1].typ == OpType::Keyword(KeywordType::Else) ||
+                    tokens[ti-1].typ == OpType::Keyword(KeywordType::End)
+                );
+                let is_target = matches!(token.typ, OpType::Keyword(KeywordType::End | KeywordType::While));
+
+                if after_block || is_target {
+                    writeln!(writer, "addr_{ti}:;")?;
+                }
+            }
+
+            // point gdb and the sanitizers at the mclang source
+            if token.loc.1 != line {
+                line = token.loc.1;
+                writeln!(writer, "#line {} \"{}\"", line, c_string(&token.loc.0))?;
+            }
+        }
+
+        match token.typ.clone() {
+            OpType::Instruction(instruction) => {
+                match instruction {
+                    InstructionType::PushInt => {
+                        writeln!(writer, "    PUSH({}ull);", token.value)?;
+                    },
+                    InstructionType::PushStr => {
+                        writeln!(writer, "    PUSH({}ull);", token.text.len())?;
+                        writeln!(writer, "    PUSH((uintptr_t)str_{});", strings.len())?;
+                        strings.push(token.text.clone());
+                    }
+                    InstructionType::PushCStr => {
+                        writeln!(writer, "    PUSH((uintptr_t)str_{});", strings.len())?;
+                        strings.push(format!("{}\0", token.text));
+                    }
+                    InstructionType::Drop => {
+                        writeln!(writer, "    sp--;")?;
+                    },
+                    InstructionType::Print => {
+                        writeln!(writer, "    dbg_print(POP());")?;
+                    },
+                    InstructionType::Dup => {
+                        writeln!(writer, "    sp[0] = sp[-1]; sp++;")?;
+                    },
+                    InstructionType::Rot => {
+                        writeln!(writer, "    {{ uint64_t a = sp[-3]; sp[-3] = sp[-2]; sp[-2] = sp[-1]; sp[-1] = a; }}")?;
+                    },
+                    InstructionType::Swap => {
+                        writeln!(writer, "    {{ uint64_t a = sp[-2]; sp[-2] = sp[-1]; sp[-1] = a; }}")?;
+                    },
+                    InstructionType::Over => {
+                        writeln!(writer, "    sp[0] = sp[-2]; sp++;")?;
+                    },
+                    InstructionType::Load8 => {
+                        writeln!(writer, "    sp[-1] = load8(sp[-1]);")?;
+                    }
+                    InstructionType::Load32 => {
+                        writeln!(writer, "    sp[-1] = load32(sp[-1]);")?;
+                    }
+                    InstructionType::Load64 => {
+                        writeln!(writer, "    sp[-1] = load64(sp[-1]);")?;
+                    }
+                    InstructionType::Store8 => {
+                        writeln!(writer, "    sp -= 2; store8(sp[0], sp[1]);")?;
+                    }
+                    InstructionType::Store32 => {
+                        writeln!(writer, "    sp -= 2; store32(sp[0], sp[1]);")?;
+                    }
+                    InstructionType::Store64 => {
+                        writeln!(writer, "    sp -= 2; store64(sp[0], sp[1]);")?;
+                    }
+
+                    // math
+                    InstructionType::Plus => writeln!(writer, "    BINOP(+);")?,
+                    InstructionType::Minus => writeln!(writer, "    BINOP(-);")?,
+                    InstructionType::Mul => writeln!(writer, "    BINOP(*);")?,
+                    InstructionType::Band => writeln!(writer, "    BINOP(&);")?,
+                    InstructionType::Bor => writeln!(writer, "    BINOP(|);")?,
+                    InstructionType::Equals => writeln!(writer, "    CMP(==);")?,
+                    InstructionType::NotEquals => writeln!(writer, "    CMP(!=);")?,
+                    InstructionType::Lt => writeln!(writer, "    CMP(<);")?,
+                    InstructionType::Gt => writeln!(writer, "    CMP(>);")?,
+                    InstructionType::Le => writeln!(writer, "    CMP(<=);")?,
+                    InstructionType::Ge => writeln!(writer, "    CMP(>=);")?,
+                    InstructionType::Shr => {
+                        writeln!(writer, "    sp--; sp[-1] = sp[-1] >> (sp[0] & 63);")?;
+                    },
+                    InstructionType::Shl => {
+                        writeln!(writer, "    sp--; sp[-1] = sp[-1] << (sp[0] & 63);")?;
+                    },
+                    InstructionType::DivMod => {
+                        writeln!(writer, "    {{ uint64_t b = sp[-1], a = sp[-2]; sp[-2] = a / b; sp[-1] = a % b; }}")?;
+                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
+                        };
+                        // the syscall number is on top, followed by the first argument
+                        let sys_args = (0..6).map(|i| {
+                            if i < argc { format!("sp[-{}]", i + 2) } else { String::from("0") }
+                        }).collect::<Vec<String>>();
+                        writeln!(writer, "    {{ uint64_t r = mcl_syscall(sp[-1], {}); sp -= {}; PUSH(r); }}", sys_args.join(", "), argc + 1)?;
+                    },
+                    InstructionType::Argc => {
+                        writeln!(writer, "    PUSH(mcl_argc);")?;
+                    },
+                    InstructionType::Argv => {
+                        writeln!(writer, "    PUSH((uintptr_t)mcl_argv);")?;
+                    },
+                    InstructionType::Envp => {
+                        writeln!(writer, "    PUSH((uintptr_t)mcl_envp);")?;
+                    },
+                    InstructionType::MemUse => {
+                        writeln!(writer, "    PUSH((uintptr_t)mem_{});", token.addr.unwrap())?;
+                    },
+                    InstructionType::None => {
+                        println!("{token:?}");
+                        unreachable!()
+                    },
+                    InstructionType::FnCall => {
+                        writeln!(writer, "    {}();", mangle(&token.text))?;
+                    },
+                    InstructionType::Return => {
+                        writeln!(writer, "    return;")?;
+                    },
+                    InstructionType::CastBool |
+                    InstructionType::CastPtr |
+                    InstructionType::CastInt |
+                    InstructionType::CastVoid |
+                    InstructionType::TypeBool |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeVoid |
+                    InstructionType::TypeAny |
+                    InstructionType::Returns |
+                    InstructionType::With => (),
+                    InstructionType::ConstUse => {
+                        writeln!(writer, "    PUSH(const_{});", mangle(&token.text))?;
+
+                        let mut c = constants.get(&token.text).unwrap().clone();
+                        c.used = true;
+                        constants.insert(token.text.clone(), c);
+                    },
+                }
+            }
+
+            OpType::Keyword(keyword) => {
+                match keyword {
+                    KeywordType::If |
+                    KeywordType::Do => {
+                        writeln!(writer, "    if (!POP()) goto addr_{};", token.jmp)?;
+                    }
+                    KeywordType::Else => {
+                        writeln!(writer, "    goto addr_{};", token.jmp)?;
+                    },
+                    KeywordType::While => (),
+                    KeywordType::End => {
+                        if ti + 1 != token.jmp {
+                            writeln!(writer, "    goto addr_{};", token.jmp)?;
+                        }
+                    },
+                    KeywordType::Memory => {
+                        memories.push(Memory { size: token.value, loc: token.loc.clone(), id: token.addr.unwrap() });
+                    }
+                    KeywordType::ConstantDef => {
+                        let c = Constant{
+                            loc: token.loc.clone(),
+                            name: token.text.clone(),
+                            value_i: Some(token.value),
+                            value_s: None,
+                            used: debug,
+                        };
+                        constants.insert(token.text.clone(), c);
+                    },
+                    KeywordType::FunctionDef => {
+                        writeln!(writer, "static void {}(void) {{", mangle(&token.text))?;
+                        functions.push(Function { loc: token.loc.clone(), name: token.text.clone(), exter: false});
+                        in_function = true;
+                        line = 0;
+                    },
+                    KeywordType::FunctionDone => {
+                        writeln!(writer, "}}")?;
+                        in_function = false;
+                    }
+                    KeywordType::FunctionThen => (),
+                    KeywordType::Function |
+                    KeywordType::Include |
+                    KeywordType::Inline |
+                    KeywordType::Export |
+                    KeywordType::Constant => unreachable!(),
+                    KeywordType::FunctionDefExported => {
+                        lerror!(&token.loc, "Exported functions are not supported by the c target");
+                        return Err(eyre!(""));
+                    },
+                }
+            }
+        }
+        ti += 1;
+    }
+
+    if !functions.iter().any(|f| f.name == "main") && !args.lib_mode {
+        crate::errors::missing_main_fn();
+        return Err(eyre!(""));
+    }
+
+    let mut out: Vec<u8> = Vec::new();
+    writeln!(out, "#define DATA_STACK_SZ {DATA_STACK_SZ}")?;
+    write!(out, "{PRELUDE}")?;
+    writeln!(out)?;
+
+    for (i, s) in strings.iter().enumerate() {
+        let s_list = s.bytes().map(|c| c.to_string()).chain(Some(String::from("0"))).collect::<Vec<String>>().join(",");
+        writeln!(out, "static const uint8_t str_{}[] = {{{}}}; // {}", i, s_list, s.escape_default())?;
+    }
+
+    // sorted so the same program always gives the same output
+    let mut constants = constants.into_values().collect::<Vec<_>>();
+    constants.sort_by(|a, b| a.name.cmp(&b.name));
+    for c in constants {
+        if !c.used {
+            continue;
+        }
+
+        if let Some(v) = &c.value_i {
+            writeln!(out, "static const uint64_t const_{} = {}ull;", mangle(&c.name), v)?;
+        } else if c.value_s.is_some() {
+            lerror!(&c.loc, "String constants are not supported by the c target");
+            return Err(eyre!(""));
+        } else {
+            unreachable!();
+        }
+    }
+
+    for m in memories {
+        writeln!(out, "static uint8_t mem_{}[{}];", m.id, m.size.max(1))?;
+    }
+
+    for f in &functions {
+        writeln!(out, "static void {}(void);", mangle(&f.name))?;
+    }
+    writeln!(out)?;
+    out.extend_from_slice(&writer);
+
+    if !args.lib_mode {
+        writeln!(out)?;
+        writeln!(out, "int main(int argc, char **argv, char **envp) {{")?;
+        writeln!(out, "    mcl_argc = (uint64_t)argc;")?;
+        writeln!(out, "    mcl_argv = argv;")?;
+        writeln!(out, "    mcl_envp = envp;")?;
+        writeln!(out, "    {}();", mangle("main"))?;
+        writeln!(out, "    return 0;")?;
+        writeln!(out, "}}")?;
+    }
+
+    fs::write(&of_a, out)?;
+
+    match args.emit {
+        Emit::Asm => return Ok(0),
+        Emit::Obj => {
+            c_compile(&of_a, &of_c.with_extension("o"), false, debug, args.quiet)?;
+            return Ok(0);
+        }
+        Emit::Exe => c_compile(&of_a, &of_c, true, debug, args.quiet)?,
+        Emit::Llvm | Emit::Qbe => unreachable!()
+    }
+
+    if args.run {
+        let c = linux_x86_64_run(&of_c, &args.program_args, args.quiet)?;
+        return Ok(c);
+    }
+
+    Ok(0)
+}
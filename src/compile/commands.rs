@@ -3,8 +3,15 @@ use std::process::{Command, Stdio};
 use color_eyre::Result;
 use crate::info;
 
-pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quiet: bool) -> Result<()> {
-    
+/// Assemble with nasm and link with ld, returns false when nasm is not installed
+pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quiet: bool) -> Result<bool> {
+    if cfg!(not(target_os = "windows")) && Command::new("nasm").arg("-v").stdout(Stdio::null()).status().is_err() {
+        if !quiet {
+            info!("nasm not found, only wrote {}", of_a.display());
+        }
+        return Ok(false);
+    }
+
     let nasm_args = [
         "-felf64",
         of_a.to_str().unwrap(),
@@ -20,7 +27,7 @@ pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quie
 
 
     let mut proc = if cfg!(target_os = "windows") {
-        return Ok(());
+        return Ok(true);
     } else {
         Command::new("nasm")
                 .args(nasm_args)
@@ -39,7 +46,7 @@ pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quie
 
 
     let mut proc2 = if cfg!(target_os = "windows") {
-        return Ok(());
+        return Ok(true);
     } else {
         Command::new("ld")
                 .args(ld_args)
@@ -57,14 +64,17 @@ pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quie
     
 
     
-    Ok(())
+    Ok(true)
 }
 
 pub fn linux_x86_64_run(bin: &Path, args: &[String], quiet: bool) -> Result<i32> {
 
-    let bin = PathBuf::from(
-        format!("./{}", bin.to_string_lossy())
-    );
+    // a bare file name would be looked up in PATH
+    let bin = if bin.is_relative() && !bin.starts_with(".") {
+        PathBuf::from(".").join(bin)
+    } else {
+        bin.to_path_buf()
+    };
 
     let mut proc = if cfg!(target_os = "windows") {
         return Ok(0);
@@ -85,4 +95,188 @@ pub fn linux_x86_64_run(bin: &Path, args: &[String], quiet: bool) -> Result<i32>
     }
 
     Ok(exit.code().unwrap_or(0))
-}
\ No newline at end of file
+}
+
+fn aarch64_tool(name: &str) -> String {
+    if cfg!(target_arch = "aarch64") {
+        name.to_string()
+    } else {
+        format!("aarch64-linux-gnu-{name}")
+    }
+}
+
+fn run_tool(tool: &str, args: &[&str], quiet: bool) -> Result<()> {
+    if !quiet {
+        info!("running '{tool} {}'", args.join(" "));
+    }
+    let exit = Command::new(tool)
+            .args(args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()?
+            .wait()?;
+    if !quiet {
+        info!("{tool} process exited with code {}", exit);
+    }
+    if !exit.success() {
+        return Err(eyre::eyre!("{tool} failed"));
+    }
+    Ok(())
+}
+
+/// Assemble `of_a` into `of_o`, and link it into `of_c` when `link` is set
+pub fn linux_aarch64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, link: bool, quiet: bool) -> Result<()> {
+    let as_args = [
+        of_a.to_str().unwrap(),
+        "-o",
+        of_o.to_str().unwrap()
+    ];
+    run_tool(&aarch64_tool("as"), &as_args, quiet)?;
+
+    if link {
+        let ld_args = [
+            of_o.to_str().unwrap(),
+            "-o",
+            of_c.to_str().unwrap()
+        ];
+        run_tool(&aarch64_tool("ld"), &ld_args, quiet)?;
+    }
+    Ok(())
+}
+
+/// Run an aarch64 binary, through qemu-user when the host is not aarch64
+pub fn linux_aarch64_run(bin: &Path, args: &[String], quiet: bool) -> Result<i32> {
+    if cfg!(target_arch = "aarch64") {
+        return linux_x86_64_run(bin, args, quiet);
+    }
+
+    let mut proc = Command::new("qemu-aarch64")
+            .arg(bin)
+            .args(args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+    if !quiet {
+        info!("running qemu-aarch64 {} {}", bin.to_string_lossy(), args.join(" "));
+    }
+    let exit = proc.wait()?;
+    if !quiet {
+        info!("{} process exited with code {}", bin.to_string_lossy(), exit);
+    }
+
+    Ok(exit.code().unwrap_or(0))
+}
+
+/// Compile a c file with `$CC`, falling back to `cc`
+pub fn c_compile(of_a: &Path, of_c: &Path, link: bool, debug: bool, quiet: bool) -> Result<()> {
+    let cc = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let mut cc_args = vec![
+        if debug { "-O0" } else { "-O2" },
+        "-g",
+        of_a.to_str().unwrap(),
+        "-o",
+        of_c.to_str().unwrap()
+    ];
+    if !link {
+        cc_args.push("-c");
+    }
+    run_tool(&cc, &cc_args, quiet)
+}
+
+/// Assemble a wat file into a wasm module with wabt's wat2wasm
+pub fn wasm_assemble(of_a: &Path, of_c: &Path, quiet: bool) -> Result<()> {
+    let args = [
+        of_a.to_str().unwrap(),
+        "-o",
+        of_c.to_str().unwrap()
+    ];
+    run_tool("wat2wasm", &args, quiet)
+}
+
+/// Run a wasm module with wasmtime
+pub fn wasm_run(bin: &Path, args: &[String], quiet: bool) -> Result<i32> {
+    let mut proc = Command::new("wasmtime")
+            .arg(bin)
+            .args(args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+    if !quiet {
+        info!("running wasmtime {} {}", bin.to_string_lossy(), args.join(" "));
+    }
+    let exit = proc.wait()?;
+    if !quiet {
+        info!("{} process exited with code {}", bin.to_string_lossy(), exit);
+    }
+
+    Ok(exit.code().unwrap_or(0))
+}
+
+/// Compile llvm ir with `$LLC` (llc by default) and link it with ld,
+/// returns false when llc is not installed and only the ir got written
+pub fn llvm_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, debug: bool, quiet: bool) -> Result<bool> {
+    let llc = std::env::var("LLC").unwrap_or_else(|_| String::from("llc"));
+    if Command::new(&llc).arg("--version").stdout(Stdio::null()).status().is_err() {
+        if !quiet {
+            info!("{llc} not found, only wrote {}", of_a.display());
+        }
+        return Ok(false);
+    }
+
+    let llc_args = [
+        if debug { "-O0" } else { "-O2" },
+        "-filetype=obj",
+        "-relocation-model=static",
+        of_a.to_str().unwrap(),
+        "-o",
+        of_o.to_str().unwrap()
+    ];
+    run_tool(&llc, &llc_args, quiet)?;
+
+    let ld_args = [
+        of_o.to_str().unwrap(),
+        "-o",
+        of_c.to_str().unwrap()
+    ];
+    run_tool("ld", &ld_args, quiet)?;
+    Ok(true)
+}
+
+/// Compile qbe il with `$QBE` (qbe by default), append `runtime` to the
+/// assembly and assemble and link it, returns false when qbe is not installed
+pub fn qbe_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, runtime: &str, quiet: bool) -> Result<bool> {
+    let qbe = std::env::var("QBE").unwrap_or_else(|_| String::from("qbe"));
+    if Command::new(&qbe).arg("-h").stdout(Stdio::null()).stderr(Stdio::null()).status().is_err() {
+        if !quiet {
+            info!("{qbe} not found, only wrote {}", of_a.display());
+        }
+        return Ok(false);
+    }
+
+    let of_s = of_a.with_extension("s");
+    let qbe_args = [
+        "-o",
+        of_s.to_str().unwrap(),
+        of_a.to_str().unwrap()
+    ];
+    run_tool(&qbe, &qbe_args, quiet)?;
+
+    let mut asm = std::fs::read_to_string(&of_s)?;
+    asm.push_str(runtime);
+    std::fs::write(&of_s, asm)?;
+
+    let as_args = [
+        of_s.to_str().unwrap(),
+        "-o",
+        of_o.to_str().unwrap()
+    ];
+    run_tool("as", &as_args, quiet)?;
+
+    let ld_args = [
+        of_o.to_str().unwrap(),
+        "-o",
+        of_c.to_str().unwrap()
+    ];
+    run_tool("ld", &ld_args, quiet)?;
+    Ok(true)
+}
@@ -0,0 +1,358 @@
+//! ELF64 writer for objects produced by `assembler`.
+//!
+//! Executables are static, non PIE and loaded at `BASE_ADDR` with one read/execute
+//! segment for the headers and .text, and one read/write segment for .data and .bss.
+use std::collections::HashMap;
+
+use color_eyre::Result;
+use eyre::eyre;
+
+use crate::error;
+use super::assembler::{Object, Section, RelocKind};
+
+const BASE_ADDR: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+
+const EHDR_SZ: u64 = 64;
+const PHDR_SZ: u64 = 56;
+const SHDR_SZ: u64 = 64;
+const SYM_SZ: u64 = 24;
+const RELA_SZ: u64 = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_64: u64 = 1;
+const R_X86_64_PC32: u64 = 2;
+const R_X86_64_32S: u64 = 11;
+
+// section header indexes, shared by both file types
+const SHN_TEXT: u16 = 1;
+const SHN_DATA: u16 = 2;
+const SHN_BSS: u16 = 3;
+
+#[derive(Default)]
+struct Buf(Vec<u8>);
+
+impl Buf {
+    fn u8(&mut self, v: u8) { self.0.push(v); }
+    fn u16(&mut self, v: u16) { self.0.extend(v.to_le_bytes()); }
+    fn u32(&mut self, v: u32) { self.0.extend(v.to_le_bytes()); }
+    fn u64(&mut self, v: u64) { self.0.extend(v.to_le_bytes()); }
+    fn len(&self) -> u64 { self.0.len() as u64 }
+    fn pad_to(&mut self, off: u64) {
+        #[allow(clippy::cast_possible_truncation)]
+        self.0.resize(off as usize, 0);
+    }
+}
+
+struct StrTab(Vec<u8>);
+
+impl StrTab {
+    fn new() -> Self { Self(vec![0]) }
+    #[allow(clippy::cast_possible_truncation)]
+    fn add(&mut self, s: &str) -> u32 {
+        let at = self.0.len() as u32;
+        self.0.extend(s.bytes());
+        self.0.push(0);
+        at
+    }
+}
+
+struct Shdr {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64
+}
+
+fn align_up(v: u64, a: u64) -> u64 {
+    v.div_ceil(a) * a
+}
+
+fn shndx(s: Section) -> u16 {
+    match s {
+        Section::Text => SHN_TEXT,
+        Section::Data => SHN_DATA,
+        Section::Bss => SHN_BSS
+    }
+}
+
+fn write_ehdr(b: &mut Buf, typ: u16, entry: u64, phnum: u16, shoff: u64, shnum: u16) {
+    b.0.extend([0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    b.0.extend([0; 8]);
+    b.u16(typ);
+    b.u16(EM_X86_64);
+    b.u32(1);
+    b.u64(entry);
+    b.u64(if phnum > 0 { EHDR_SZ } else { 0 });
+    b.u64(shoff);
+    b.u32(0);
+    b.u16(EHDR_SZ as u16);
+    b.u16(if phnum > 0 { PHDR_SZ as u16 } else { 0 });
+    b.u16(phnum);
+    b.u16(SHDR_SZ as u16);
+    b.u16(shnum);
+    b.u16(shnum - 1);
+}
+
+fn write_shdrs(b: &mut Buf, shdrs: &[Shdr]) {
+    b.0.extend([0; SHDR_SZ as usize]);
+    for s in shdrs {
+        b.u32(s.name);
+        b.u32(s.typ);
+        b.u64(s.flags);
+        b.u64(s.addr);
+        b.u64(s.offset);
+        b.u64(s.size);
+        b.u32(s.link);
+        b.u32(s.info);
+        b.u64(s.align);
+        b.u64(s.entsize);
+    }
+}
+
+/// Symbol table of `obj`, locals first as required by the spec.
+/// Returns the table, the string table, the index of the first global and
+/// the index of every symbol by name
+fn symtab(obj: &Object, addrs: [u64; 3]) -> (Buf, StrTab, u32, HashMap<String, u32>) {
+    let mut tab = Buf::default();
+    let mut strs = StrTab::new();
+    let mut index = HashMap::new();
+    tab.0.extend([0; SYM_SZ as usize]);
+
+    let mut n = 1;
+    for sec in [SHN_TEXT, SHN_DATA, SHN_BSS] {
+        tab.u32(0);
+        tab.u8(STB_LOCAL << 4 | STT_SECTION);
+        tab.u8(0);
+        tab.u16(sec);
+        tab.u64(addrs[sec as usize - 1]);
+        tab.u64(0);
+        n += 1;
+    }
+
+    let mut first_global = n;
+    for global in [false, true] {
+        if global {
+            first_global = n;
+        }
+        for s in obj.symbols.iter().filter(|s| s.global == global) {
+            tab.u32(strs.add(&s.name));
+            tab.u8(if global { STB_GLOBAL } else { STB_LOCAL } << 4 | STT_NOTYPE);
+            tab.u8(0);
+            tab.u16(shndx(s.section));
+            tab.u64(addrs[shndx(s.section) as usize - 1] + s.offset as u64);
+            tab.u64(0);
+            index.insert(s.name.clone(), n);
+            n += 1;
+        }
+    }
+    (tab, strs, first_global, index)
+}
+
+fn section_headers(names: &mut StrTab, text: (u64, u64, u64), data: (u64, u64, u64), bss: (u64, u64)) -> Vec<Shdr> {
+    vec![
+        Shdr { name: names.add(".text"), typ: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: text.0, offset: text.1, size: text.2, link: 0, info: 0, align: 16, entsize: 0 },
+        Shdr { name: names.add(".data"), typ: SHT_PROGBITS, flags: SHF_ALLOC | SHF_WRITE, addr: data.0, offset: data.1, size: data.2, link: 0, info: 0, align: 8, entsize: 0 },
+        Shdr { name: names.add(".bss"), typ: SHT_NOBITS, flags: SHF_ALLOC | SHF_WRITE, addr: bss.0, offset: data.1 + data.2, size: bss.1, link: 0, info: 0, align: 16, entsize: 0 },
+    ]
+}
+
+fn patch(buf: &mut [u8], at: usize, kind: RelocKind, value: i64) -> Result<()> {
+    match kind {
+        RelocKind::Abs64 => buf[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+        RelocKind::Abs32S | RelocKind::Pc32 => {
+            let Ok(v) = i32::try_from(value) else {
+                error!("elf: relocation value {value:#x} does not fit in 32 bits");
+                return Err(eyre!("Relocation overflow"));
+            };
+            buf[at..at + 4].copy_from_slice(&v.to_le_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Link `obj` into a static executable starting at `_start`
+pub fn executable(obj: &Object) -> Result<Vec<u8>> {
+    let text_off = EHDR_SZ + 2 * PHDR_SZ;
+    let text_addr = BASE_ADDR + text_off;
+    let text_len = obj.text.len() as u64;
+
+    let data_off = align_up(text_off + text_len, 16);
+    let data_addr = align_up(BASE_ADDR + data_off, PAGE) + data_off % PAGE;
+    let data_len = obj.data.len() as u64;
+    let bss_addr = align_up(data_addr + data_len, 16);
+    let bss_len = obj.bss_size as u64;
+    let addrs = [text_addr, data_addr, bss_addr];
+
+    let addr_of = |name: &str| -> Result<u64> {
+        let Some(s) = obj.symbol(name) else {
+            error!("elf: undefined symbol '{name}'");
+            return Err(eyre!("Undefined symbol"));
+        };
+        Ok(addrs[shndx(s.section) as usize - 1] + s.offset as u64)
+    };
+
+    let mut text = obj.text.clone();
+    let mut data = obj.data.clone();
+    for r in &obj.relocs {
+        #[allow(clippy::cast_possible_wrap)]
+        let s = addr_of(&r.symbol)? as i64;
+        let (buf, base) = match r.section {
+            Section::Text => (&mut text, text_addr),
+            Section::Data => (&mut data, data_addr),
+            Section::Bss => unreachable!()
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        let p = (base + r.offset as u64) as i64;
+        let v = match r.kind {
+            RelocKind::Abs64 | RelocKind::Abs32S => s + r.addend,
+            RelocKind::Pc32 => s + r.addend - p
+        };
+        patch(buf, r.offset, r.kind, v)?;
+    }
+
+    let entry = if obj.symbol("_start").is_some() { addr_of("_start")? } else { text_addr };
+
+    let mut names = StrTab::new();
+    let mut shdrs = section_headers(&mut names, (text_addr, text_off, text_len), (data_addr, data_off, data_len), (bss_addr, bss_len));
+    let (syms, strs, first_global, _) = symtab(obj, addrs);
+
+    let mut b = Buf::default();
+    let sym_off = align_up(data_off + data_len, 8);
+    let str_off = sym_off + syms.len();
+    shdrs.push(Shdr { name: names.add(".symtab"), typ: SHT_SYMTAB, flags: 0, addr: 0, offset: sym_off, size: syms.len(), link: 5, info: first_global, align: 8, entsize: SYM_SZ });
+    shdrs.push(Shdr { name: names.add(".strtab"), typ: SHT_STRTAB, flags: 0, addr: 0, offset: str_off, size: strs.0.len() as u64, link: 0, info: 0, align: 1, entsize: 0 });
+    let shstr_name = names.add(".shstrtab");
+    let shstr_off = str_off + strs.0.len() as u64;
+    shdrs.push(Shdr { name: shstr_name, typ: SHT_STRTAB, flags: 0, addr: 0, offset: shstr_off, size: names.0.len() as u64, link: 0, info: 0, align: 1, entsize: 0 });
+    let shoff = align_up(shstr_off + names.0.len() as u64, 8);
+
+    #[allow(clippy::cast_possible_truncation)]
+    write_ehdr(&mut b, ET_EXEC, entry, 2, shoff, shdrs.len() as u16 + 1);
+
+    // headers and .text
+    b.u32(1);
+    b.u32(4 | 1);
+    b.u64(0);
+    b.u64(BASE_ADDR);
+    b.u64(BASE_ADDR);
+    b.u64(text_off + text_len);
+    b.u64(text_off + text_len);
+    b.u64(PAGE);
+
+    // .data and .bss
+    b.u32(1);
+    b.u32(4 | 2);
+    b.u64(data_off);
+    b.u64(data_addr);
+    b.u64(data_addr);
+    b.u64(data_len);
+    b.u64(bss_addr + bss_len - data_addr);
+    b.u64(PAGE);
+
+    b.0.extend(&text);
+    b.pad_to(data_off);
+    b.0.extend(&data);
+    b.pad_to(sym_off);
+    b.0.extend(&syms.0);
+    b.0.extend(&strs.0);
+    b.0.extend(&names.0);
+    b.pad_to(shoff);
+    write_shdrs(&mut b, &shdrs);
+
+    Ok(b.0)
+}
+
+/// Write `obj` as a relocatable object that can be linked with ld
+pub fn relocatable(obj: &Object) -> Result<Vec<u8>> {
+    let (syms, strs, first_global, index) = symtab(obj, [0; 3]);
+
+    let mut text = obj.text.clone();
+    let mut rela: [Buf; 2] = [Buf::default(), Buf::default()];
+    for r in &obj.relocs {
+        let Some(s) = obj.symbol(&r.symbol) else {
+            error!("elf: undefined symbol '{}'", r.symbol);
+            return Err(eyre!("Undefined symbol"));
+        };
+        // relative references inside .text do not need the linker
+        if r.kind == RelocKind::Pc32 && r.section == Section::Text && s.section == Section::Text {
+            #[allow(clippy::cast_possible_wrap)]
+            let v = s.offset as i64 + r.addend - r.offset as i64;
+            patch(&mut text, r.offset, r.kind, v)?;
+            continue;
+        }
+        let typ = match r.kind {
+            RelocKind::Abs64 => R_X86_64_64,
+            RelocKind::Abs32S => R_X86_64_32S,
+            RelocKind::Pc32 => R_X86_64_PC32
+        };
+        let b = &mut rela[usize::from(r.section != Section::Text)];
+        b.u64(r.offset as u64);
+        b.u64(u64::from(index[&r.symbol]) << 32 | typ);
+        #[allow(clippy::cast_sign_loss)]
+        b.u64(r.addend as u64);
+    }
+
+    let text_off = EHDR_SZ;
+    let text_len = text.len() as u64;
+    let data_off = align_up(text_off + text_len, 16);
+    let data_len = obj.data.len() as u64;
+    let rela_text_off = align_up(data_off + data_len, 8);
+    let rela_data_off = rela_text_off + rela[0].len();
+    let sym_off = rela_data_off + rela[1].len();
+    let str_off = sym_off + syms.len();
+
+    let mut names = StrTab::new();
+    let mut shdrs = section_headers(&mut names, (0, text_off, text_len), (0, data_off, data_len), (0, obj.bss_size as u64));
+    // 4: .rela.text, 5: .rela.data, 6: .symtab
+    shdrs.push(Shdr { name: names.add(".rela.text"), typ: SHT_RELA, flags: SHF_INFO_LINK, addr: 0, offset: rela_text_off, size: rela[0].len(), link: 6, info: u32::from(SHN_TEXT), align: 8, entsize: RELA_SZ });
+    shdrs.push(Shdr { name: names.add(".rela.data"), typ: SHT_RELA, flags: SHF_INFO_LINK, addr: 0, offset: rela_data_off, size: rela[1].len(), link: 6, info: u32::from(SHN_DATA), align: 8, entsize: RELA_SZ });
+    shdrs.push(Shdr { name: names.add(".symtab"), typ: SHT_SYMTAB, flags: 0, addr: 0, offset: sym_off, size: syms.len(), link: 7, info: first_global, align: 8, entsize: SYM_SZ });
+    shdrs.push(Shdr { name: names.add(".strtab"), typ: SHT_STRTAB, flags: 0, addr: 0, offset: str_off, size: strs.0.len() as u64, link: 0, info: 0, align: 1, entsize: 0 });
+    let shstr_name = names.add(".shstrtab");
+    let shstr_off = str_off + strs.0.len() as u64;
+    shdrs.push(Shdr { name: shstr_name, typ: SHT_STRTAB, flags: 0, addr: 0, offset: shstr_off, size: names.0.len() as u64, link: 0, info: 0, align: 1, entsize: 0 });
+    let shoff = align_up(shstr_off + names.0.len() as u64, 8);
+
+    let mut b = Buf::default();
+    #[allow(clippy::cast_possible_truncation)]
+    write_ehdr(&mut b, ET_REL, 0, 0, shoff, shdrs.len() as u16 + 1);
+    b.0.extend(&text);
+    b.pad_to(data_off);
+    b.0.extend(&obj.data);
+    b.pad_to(rela_text_off);
+    b.0.extend(&rela[0].0);
+    b.0.extend(&rela[1].0);
+    b.0.extend(&syms.0);
+    b.0.extend(&strs.0);
+    b.0.extend(&names.0);
+    b.pad_to(shoff);
+    write_shdrs(&mut b, &shdrs);
+
+    Ok(b.0)
+}
@@ -0,0 +1,518 @@
+use std::{fs, path::PathBuf, io::{Write, BufWriter}, collections::HashMap};
+use crate::{constants::{Operator, OpType, KeywordType}, Args, Emit, lerror};
+use color_eyre::Result;
+use crate::compile::commands::{linux_aarch64_compile_and_link, linux_aarch64_run};
+use crate::constants::InstructionType;
+use super::{Constant, Memory, Function};
+use eyre::eyre;
+
+/*
+ * Register usage
+ * x28: data stack pointer, full descending, sp cannot be used since it has to stay 16 byte aligned
+ * x27: return stack pointer, same as rbp in the x86_64 backend
+ * x0-x9: scratch
+ */
+
+/// Size of the data stack in bytes
+const DATA_STACK_SZ: usize = 1024 * 1024;
+
+/// Marks syscalls that are only available as their *at variant, the
+/// arguments get shifted by one and AT_FDCWD is passed as the first one
+const AT_FDCWD_FLAG: u16 = 0x8000;
+
+/// mclang programs use x86_64 syscall numbers (see include/linux.mcl),
+/// they get translated to the arm64 ones at runtime through this table
+const SYSCALLS: [(usize, u16); 86] = [
+    (0, 63), (1, 64), (2, 56 | AT_FDCWD_FLAG), (3, 57), (4, 79 | AT_FDCWD_FLAG), (5, 80),
+    (8, 62), (9, 222), (10, 226), (11, 215), (12, 214), (13, 134), (14, 135), (15, 139),
+    (16, 29), (17, 67), (18, 68), (19, 65), (20, 66), (21, 48 | AT_FDCWD_FLAG), (22, 59),
+    (24, 124), (25, 216), (26, 227), (27, 232), (28, 233), (32, 23), (33, 24), (35, 101),
+    (36, 102), (38, 103), (39, 172), (40, 71), (41, 198), (42, 203), (43, 202), (44, 206),
+    (45, 207), (46, 211), (47, 212), (48, 210), (49, 200), (50, 201), (51, 204), (52, 205),
+    (53, 199), (54, 208), (55, 209), (59, 221), (60, 93), (61, 260), (62, 129), (63, 160),
+    (72, 25), (73, 32), (74, 82), (75, 83), (76, 45), (77, 46), (79, 17), (80, 49), (81, 50),
+    (83, 34 | AT_FDCWD_FLAG), (87, 35 | AT_FDCWD_FLAG), (89, 78 | AT_FDCWD_FLAG),
+    (90, 53 | AT_FDCWD_FLAG), (91, 52), (93, 55), (95, 166), (96, 169), (97, 163), (98, 165),
+    (99, 179), (100, 153), (102, 174), (104, 176), (107, 175), (108, 177), (110, 173),
+    (112, 157), (186, 178), (217, 61), (228, 113), (231, 94), (257, 56), (318, 278),
+];
+
+const SYSCALL_TABLE_LEN: usize = 319;
+
+fn push(writer: &mut impl Write, reg: &str) -> Result<()> {
+    writeln!(writer, "    str {reg}, [x28, #-8]!")?;
+    Ok(())
+}
+
+fn pop(writer: &mut impl Write, reg: &str) -> Result<()> {
+    writeln!(writer, "    ldr {reg}, [x28], #8")?;
+    Ok(())
+}
+
+/// Load a 64 bit immediate with movz/movk
+fn load_imm(writer: &mut impl Write, reg: &str, value: usize) -> Result<()> {
+    writeln!(writer, "    movz {reg}, #{}", value & 0xffff)?;
+    for shift in [16, 32, 48] {
+        let part = (value >> shift) & 0xffff;
+        if part != 0 {
+            writeln!(writer, "    movk {reg}, #{part}, lsl #{shift}")?;
+        }
+    }
+    Ok(())
+}
+
+fn load_addr(writer: &mut impl Write, reg: &str, label: &str) -> Result<()> {
+    writeln!(writer, "    adrp {reg}, {label}")?;
+    writeln!(writer, "    add {reg}, {reg}, :lo12:{label}")?;
+    Ok(())
+}
+
+pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
+    let debug = args.get_opt_level()? < 1;
+
+    let mut of_c = PathBuf::from(&args.out_file);
+    let (mut of_o, mut of_a) = if args.out_file == *crate::DEFAULT_OUT_FILE {
+        let of_o = PathBuf::from("/tmp/mclang_comp.o");
+        let of_a = PathBuf::from("/tmp/mclang_comp.s");
+        (of_o, of_a)
+    } else {
+        let of_o = PathBuf::from(&args.out_file);
+        let of_a = PathBuf::from(&args.out_file);
+        (of_o, of_a)
+    };
+
+    of_c.set_extension("");
+    of_o.set_extension("o");
+    of_a.set_extension("s");
+
+    let file = fs::File::create(&of_a)?;
+    let mut writer = BufWriter::new(&file);
+    let mut memories:  Vec<Memory> = Vec::new();
+    let mut constants:  HashMap<String, Constant> = HashMap::new();
+    let mut functions: Vec<Function> = Vec::new();
+    let mut strings: Vec<String> = Vec::new();
+
+    writeln!(writer, ".text")?;
+
+    writeln!(writer, "_dbg_print:")?;
+    writeln!(writer, "    sub sp, sp, #32")?;
+    writeln!(writer, "    add x2, sp, #31")?;
+    writeln!(writer, "    mov w3, #10")?;
+    writeln!(writer, "    strb w3, [x2]")?;
+    writeln!(writer, "    mov x4, #10")?;
+    writeln!(writer, "1:")?;
+    writeln!(writer, "    udiv x5, x0, x4")?;
+    writeln!(writer, "    msub x6, x5, x4, x0")?;
+    writeln!(writer, "    add w6, w6, #48")?;
+    writeln!(writer, "    sub x2, x2, #1")?;
+    writeln!(writer, "    strb w6, [x2]")?;
+    writeln!(writer, "    mov x0, x5")?;
+    writeln!(writer, "    cbnz x0, 1b")?;
+    writeln!(writer, "    mov x1, x2")?;
+    writeln!(writer, "    add x3, sp, #32")?;
+    writeln!(writer, "    sub x2, x3, x1")?;
+    writeln!(writer, "    mov x0, #1")?;
+    writeln!(writer, "    mov x8, #64")?;
+    writeln!(writer, "    svc #0")?;
+    writeln!(writer, "    add sp, sp, #32")?;
+    writeln!(writer, "    ret")?;
+
+    // x8: x86_64 syscall number, x0-x5: arguments
+    writeln!(writer, "_mcl_syscall:")?;
+    writeln!(writer, "    cmp x8, #{SYSCALL_TABLE_LEN}")?;
+    writeln!(writer, "    b.hs 1f")?;
+    load_addr(&mut writer, "x9", "syscall_table")?;
+    writeln!(writer, "    ldrh w8, [x9, x8, lsl #1]")?;
+    writeln!(writer, "    mov w10, #0xffff")?;
+    writeln!(writer, "    cmp w8, w10")?;
+    writeln!(writer, "    b.eq 1f")?;
+    writeln!(writer, "    tbz w8, #15, 2f")?;
+    writeln!(writer, "    and w8, w8, #0x7fff")?;
+    writeln!(writer, "    mov x5, x4")?;
+    writeln!(writer, "    mov x4, x3")?;
+    writeln!(writer, "    mov x3, x2")?;
+    writeln!(writer, "    mov x2, x1")?;
+    writeln!(writer, "    mov x1, x0")?;
+    writeln!(writer, "    mov x0, #-100")?;
+    writeln!(writer, "2:")?;
+    writeln!(writer, "    svc #0")?;
+    writeln!(writer, "    ret")?;
+    writeln!(writer, "1:")?;
+    writeln!(writer, "    mov x0, #-38")?;
+    writeln!(writer, "    ret")?;
+
+    if !args.lib_mode {
+        writeln!(writer, ".global _start")?;
+        writeln!(writer, "_start:")?;
+        // argc, the argv pointers and the envp pointers are on the stack
+        writeln!(writer, "    mov x0, sp")?;
+        load_addr(&mut writer, "x1", "args_ptr")?;
+        writeln!(writer, "    str x0, [x1]")?;
+        load_addr(&mut writer, "x27", "ret_stack")?;
+        load_addr(&mut writer, "x28", "data_stack_end")?;
+        writeln!(writer, "    bl main")?;
+        writeln!(writer, "    b end")?;
+    }
+
+    let mut ti = 0;
+    while ti < tokens.len() {
+        let token = &tokens[ti];
+        if debug {
+            writeln!(writer, "addr_{ti}:")?;
+            if token.typ == OpType::Instruction(InstructionType::PushInt) {
+                writeln!(writer, "    // -- {:?} {}", token.typ, token.value)?;
+            } else if matches!(token.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) {
+                writeln!(writer, "    // -- {:?} {}", token.typ, token.text.escape_debug())?;
+            } else {
+                writeln!(writer, "    // -- {:?}", token.typ)?;
+            }
+        } else {
+            let after_block = ti > 0 && (
+                tokens[ti-1].typ == OpType::Keyword(KeywordType::Else) ||
+                tokens[ti-1].typ == OpType::Keyword(KeywordType::End)
+            );
+            let is_target = matches!(token.typ, OpType::Keyword(KeywordType::End | KeywordType::While));
+
+            if after_block || is_target {
+                writeln!(writer, "addr_{ti}:")?;
+            }
+        }
+
+        match token.typ.clone() {
+            OpType::Instruction(instruction) => {
+                match instruction {
+                    InstructionType::PushInt => {
+                        load_imm(&mut writer, "x0", token.value)?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::PushStr => {
+                        load_imm(&mut writer, "x0", token.text.len())?;
+                        push(&mut writer, "x0")?;
+                        load_addr(&mut writer, "x0", &format!("str_{}", strings.len()))?;
+                        push(&mut writer, "x0")?;
+                        strings.push(token.text.clone());
+                    }
+                    InstructionType::PushCStr => {
+                        load_addr(&mut writer, "x0", &format!("str_{}", strings.len()))?;
+                        push(&mut writer, "x0")?;
+                        strings.push(format!("{}\0", token.text));
+                    }
+                    InstructionType::Drop => {
+                        writeln!(writer, "    add x28, x28, #8")?;
+                    },
+                    InstructionType::Print => {
+                        pop(&mut writer, "x0")?;
+                        writeln!(writer, "    bl _dbg_print")?;
+                    },
+                    InstructionType::Dup => {
+                        writeln!(writer, "    ldr x0, [x28]")?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::Rot => {
+                        pop(&mut writer, "x0")?;
+                        pop(&mut writer, "x1")?;
+                        pop(&mut writer, "x2")?;
+                        push(&mut writer, "x1")?;
+                        push(&mut writer, "x0")?;
+                        push(&mut writer, "x2")?;
+                    },
+                    InstructionType::Swap => {
+                        pop(&mut writer, "x0")?;
+                        pop(&mut writer, "x1")?;
+                        push(&mut writer, "x0")?;
+                        push(&mut writer, "x1")?;
+                    },
+                    InstructionType::Over => {
+                        writeln!(writer, "    ldr x0, [x28, #8]")?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::Load8 |
+                    InstructionType::Load32 |
+                    InstructionType::Load64 => {
+                        let ld = match instruction {
+                            InstructionType::Load8 => "ldrb w1",
+                            InstructionType::Load32 => "ldr w1",
+                            _ => "ldr x1"
+                        };
+                        pop(&mut writer, "x0")?;
+                        writeln!(writer, "    {ld}, [x0]")?;
+                        push(&mut writer, "x1")?;
+                    }
+                    InstructionType::Store8 |
+                    InstructionType::Store32 |
+                    InstructionType::Store64 => {
+                        let st = match instruction {
+                            InstructionType::Store8 => "strb w1",
+                            InstructionType::Store32 => "str w1",
+                            _ => "str x1"
+                        };
+                        pop(&mut writer, "x1")?;
+                        pop(&mut writer, "x0")?;
+                        writeln!(writer, "    {st}, [x0]")?;
+                    }
+
+                    // math
+                    InstructionType::Plus |
+                    InstructionType::Minus |
+                    InstructionType::Mul |
+                    InstructionType::Band |
+                    InstructionType::Bor |
+                    InstructionType::Shr |
+                    InstructionType::Shl => {
+                        let op = match instruction {
+                            InstructionType::Plus => "add",
+                            InstructionType::Minus => "sub",
+                            InstructionType::Mul => "mul",
+                            InstructionType::Band => "and",
+                            InstructionType::Bor => "orr",
+                            InstructionType::Shr => "lsr",
+                            _ => "lsl"
+                        };
+                        pop(&mut writer, "x0")?;
+                        pop(&mut writer, "x1")?;
+                        writeln!(writer, "    {op} x0, x1, x0")?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::Equals |
+                    InstructionType::NotEquals |
+                    InstructionType::Gt |
+                    InstructionType::Lt |
+                    InstructionType::Ge |
+                    InstructionType::Le => {
+                        let cond = match instruction {
+                            InstructionType::Equals => "eq",
+                            InstructionType::NotEquals => "ne",
+                            InstructionType::Gt => "gt",
+                            InstructionType::Lt => "lt",
+                            InstructionType::Ge => "ge",
+                            _ => "le"
+                        };
+                        pop(&mut writer, "x0")?;
+                        pop(&mut writer, "x1")?;
+                        writeln!(writer, "    cmp x1, x0")?;
+                        writeln!(writer, "    cset x0, {cond}")?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::DivMod => {
+                        pop(&mut writer, "x0")?;
+                        pop(&mut writer, "x1")?;
+                        writeln!(writer, "    udiv x2, x1, x0")?;
+                        writeln!(writer, "    msub x3, x2, x0, x1")?;
+                        push(&mut writer, "x2")?;
+                        push(&mut writer, "x3")?;
+                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
+                        };
+                        pop(&mut writer, "x8")?;
+                        for i in 0..argc {
+                            pop(&mut writer, &format!("x{i}"))?;
+                        }
+                        // unused arguments end up as flags of the *at variants
+                        for i in argc..5 {
+                            writeln!(writer, "    mov x{i}, xzr")?;
+                        }
+                        writeln!(writer, "    bl _mcl_syscall")?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::MemUse => {
+                        load_addr(&mut writer, "x0", &format!("mem_{}", token.addr.unwrap()))?;
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::Argc |
+                    InstructionType::Argv |
+                    InstructionType::Envp => {
+                        load_addr(&mut writer, "x0", "args_ptr")?;
+                        writeln!(writer, "    ldr x0, [x0]")?;
+                        match instruction {
+                            InstructionType::Argc => writeln!(writer, "    ldr x0, [x0]")?,
+                            InstructionType::Argv => writeln!(writer, "    add x0, x0, #8")?,
+                            _ => {
+                                writeln!(writer, "    ldr x1, [x0]")?;
+                                writeln!(writer, "    add x0, x0, x1, lsl #3")?;
+                                writeln!(writer, "    add x0, x0, #16")?;
+                            }
+                        }
+                        push(&mut writer, "x0")?;
+                    },
+                    InstructionType::None => {
+                        println!("{token:?}");
+                        unreachable!()
+                    },
+                    InstructionType::FnCall => {
+                        writeln!(writer, "    bl {}", token.text)?;
+                    },
+                    InstructionType::Return => {
+                        writeln!(writer, "    ldr x30, [x27, #-8]!")?;
+                        writeln!(writer, "    ret")?;
+                    },
+                    InstructionType::CastBool |
+                    InstructionType::CastPtr |
+                    InstructionType::CastInt |
+                    InstructionType::CastVoid |
+                    InstructionType::TypeBool |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeVoid |
+                    InstructionType::TypeAny |
+                    InstructionType::Returns |
+                    InstructionType::With => (),
+                    InstructionType::ConstUse => {
+                        writeln!(writer, "    adrp x0, const_{}", token.text)?;
+                        writeln!(writer, "    ldr x0, [x0, :lo12:const_{}]", token.text)?;
+                        push(&mut writer, "x0")?;
+
+                        let mut c = constants.get(&token.text).unwrap().clone();
+                        c.used = true;
+                        constants.insert(token.text.clone(), c);
+                    },
+                }
+            }
+
+            OpType::Keyword(keyword) => {
+                match keyword {
+                    KeywordType::If |
+                    KeywordType::Do => {
+                        pop(&mut writer, "x0")?;
+                        writeln!(writer, "    cbz x0, addr_{}", token.jmp)?;
+                    }
+                    KeywordType::Else => {
+                        writeln!(writer, "    b addr_{}", token.jmp)?;
+                    },
+                    KeywordType::While => (),
+                    KeywordType::End => {
+                        if ti + 1 != token.jmp {
+                            writeln!(writer, "    b addr_{}", token.jmp)?;
+                        }
+                    },
+                    KeywordType::Memory => {
+                        memories.push(Memory { size: token.value, loc: token.loc.clone(), id: token.addr.unwrap() });
+                    }
+                    KeywordType::ConstantDef => {
+                        let c = Constant{
+                            loc: token.loc.clone(),
+                            name: token.text.clone(),
+                            value_i: Some(token.value),
+                            value_s: None,
+                            used: debug,
+                        };
+                        constants.insert(token.text.clone(), c);
+                    },
+                    KeywordType::FunctionDef => {
+                        writeln!(writer, "{}:", token.text)?;
+                        writeln!(writer, "    str x30, [x27], #8")?;
+                        functions.push(Function { loc: token.loc.clone(), name: token.text.clone(), exter: false});
+                    },
+                    KeywordType::FunctionDone => {
+                        writeln!(writer, "    ldr x30, [x27, #-8]!")?;
+                        writeln!(writer, "    ret")?;
+                    }
+                    KeywordType::FunctionThen => (),
+                    KeywordType::Function |
+                    KeywordType::Include |
+                    KeywordType::Inline |
+                    KeywordType::Export |
+                    KeywordType::Constant => unreachable!(),
+                    KeywordType::FunctionDefExported => {
+                        lerror!(&token.loc, "Exported functions are not supported on linux_aarch64");
+                        return Err(eyre!(""));
+                    },
+                }
+            }
+        }
+        ti += 1;
+    }
+    writeln!(writer, "addr_{ti}:")?;
+    if !args.lib_mode {
+        writeln!(writer, "end:")?;
+        writeln!(writer, "    mov x0, #0")?;
+        writeln!(writer, "    mov x8, #93")?;
+        writeln!(writer, "    svc #0")?;
+    }
+
+    writeln!(writer, ".data")?;
+    writeln!(writer, ".balign 8")?;
+    // sorted so the same program always gives the same output
+    let mut constants = constants.into_values().collect::<Vec<_>>();
+    constants.sort_by(|a, b| a.name.cmp(&b.name));
+    for c in constants {
+        if !c.used {
+            continue;
+        }
+
+        if let Some(v) = &c.value_i {
+            writeln!(writer, "const_{}: .quad {}", c.name, v)?;
+        } else if c.value_s.is_some() {
+            lerror!(&c.loc, "String constants are not supported on linux_aarch64");
+            return Err(eyre!(""));
+        } else {
+            unreachable!();
+        }
+    }
+
+    let mut table = vec![u16::MAX; SYSCALL_TABLE_LEN];
+    for (x86, arm) in SYSCALLS {
+        table[x86] = arm;
+    }
+    writeln!(writer, "syscall_table:")?;
+    for chunk in table.chunks(16) {
+        let nums = chunk.iter().map(ToString::to_string).collect::<Vec<String>>();
+        writeln!(writer, "    .hword {}", nums.join(","))?;
+    }
+
+    for (i, s) in strings.iter().enumerate() {
+        if s.is_empty() {
+            writeln!(writer, "str_{i}:")?;
+            continue;
+        }
+        let s_list = s.bytes().map(|c| c.to_string()).collect::<Vec<String>>().join(",");
+        writeln!(writer, "str_{}: .byte {} // {}", i, s_list, s.escape_default())?;
+    }
+
+    writeln!(writer, ".bss")?;
+    for s in memories {
+        writeln!(writer, "mem_{}: .skip {}", s.id, s.size)?;
+    }
+    writeln!(writer, ".balign 16")?;
+    writeln!(writer, "ret_stack: .skip {}", 256 * 8)?;
+    writeln!(writer, "args_ptr: .skip 8")?;
+    writeln!(writer, "data_stack: .skip {DATA_STACK_SZ}")?;
+    writeln!(writer, "data_stack_end:")?;
+
+    writer.flush()?;
+
+    if !functions.iter().any(|f| f.name == "main") && !args.lib_mode {
+        crate::errors::missing_main_fn();
+        return Err(eyre!(""));
+    }
+
+    match args.emit {
+        Emit::Asm => return Ok(0),
+        Emit::Obj => {
+            linux_aarch64_compile_and_link(&of_a, &of_c.with_extension("o"), &of_c, false, args.quiet)?;
+            return Ok(0);
+        }
+        Emit::Exe => linux_aarch64_compile_and_link(&of_a, &of_o, &of_c, true, args.quiet)?,
+        Emit::Llvm | Emit::Qbe => unreachable!()
+    }
+
+    if args.run {
+        let c = linux_aarch64_run(&of_c, &args.program_args, args.quiet)?;
+        return Ok(c);
+    }
+
+    Ok(0)
+}
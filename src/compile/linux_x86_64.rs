@@ -1,14 +1,16 @@
 use std::{fs, path::PathBuf, io::{Write, BufWriter}, collections::HashMap};
-use crate::{constants::{Operator, OpType, KeywordType}, Args, warn, lerror};
+use crate::{constants::{Operator, OpType, KeywordType}, Args, Emit, warn, lerror, info, error};
 use color_eyre::Result;
 use crate::compile::commands::linux_x86_64_compile_and_link;
 use crate::constants::InstructionType;
-use super::{commands::linux_x86_64_run, Constant, Memory, Function};
+use super::{commands::linux_x86_64_run, assembler, elf, stack_cache::StackCache, peephole, Constant, Memory, Function};
 use eyre::eyre;
 
 
 pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
     let debug = args.get_opt_level()? < 1;
+    let use_cache = args.get_opt_level()? >= 2;
+    let checked = args.checked;
 
     let mut of_c = PathBuf::from(&args.out_file);
     let (mut of_o, mut of_a) = if args.out_file == *crate::DEFAULT_OUT_FILE {
@@ -27,13 +29,15 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
 
     let mut should_push_ret = false;
 
-    let file = fs::File::create(&of_a)?;
-    let mut writer = BufWriter::new(&file);
+    let mut writer = BufWriter::new(Vec::new());
     let mut memories:  Vec<Memory> = Vec::new();
     let mut constants:  HashMap<String, Constant> = HashMap::new();
     let mut functions: Vec<Function> = Vec::new();
     // println!("{}", tokens.len());
     let mut strings: Vec<String> = Vec::new();
+    let mut cache = StackCache::new();
+    // the messages of the checks in --checked mode, `check_{index}` prints one
+    let mut checks: Vec<String> = Vec::new();
     
     writeln!(writer, "BITS 64")?;
     writeln!(writer, "segment .text")?;
@@ -72,9 +76,11 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
     writeln!(writer, "    add     rsp, 40")?;
     writeln!(writer, "    ret")?;
 
-    if crate::config::ENABLE_EXPORTED_FUNCTIONS && !args.lib_mode {
+    if !args.lib_mode {
         writeln!(writer, "global _start")?;
         writeln!(writer, "_start:")?; 
+        // argc, the argv pointers and the envp pointers are on the stack
+        writeln!(writer, "    mov qword [args_ptr], rsp")?;
         writeln!(writer, "    lea rbp, [rel ret_stack]")?;
         writeln!(writer, "    call main")?;
         writeln!(writer, "    jmp end")?;
@@ -90,34 +96,42 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
             writeln!(writer, "addr_{ti}:")?;
             if token.typ == OpType::Instruction(InstructionType::PushInt) {
                 writeln!(writer, "    ;; -- {:?} {}", token.typ, token.value)?;
-            } else if token.typ == OpType::Instruction(InstructionType::PushStr) {
+            } else if matches!(token.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) {
                 writeln!(writer, "    ;; -- {:?} {}", token.typ, token.text.escape_debug())?;
             } else {
                 writeln!(writer, "    ;; -- {:?}", token.typ)?;
             }
         } else {
-            if ti > 0 {
-                if tokens[ti-1].typ == OpType::Keyword(KeywordType::Else) ||
-                tokens[ti-1].typ == OpType::Keyword(KeywordType::End){
-                    writeln!(writer, "addr_{ti}:")?;
-                }
+            let after_block = ti > 0 && (
+                tokens[ti-1].typ == OpType::Keyword(KeywordType::Else) ||
+                tokens[ti-1].typ == OpType::Keyword(KeywordType::End)
+            );
+            let before_end = ti + 1 < tokens.len() && tokens[ti+1].typ == OpType::Keyword(KeywordType::End);
+            let is_target = matches!(token.typ, OpType::Keyword(KeywordType::End | KeywordType::While));
+
+            // jump targets have to start with the whole stack in memory
+            if after_block || is_target {
+                cache.flush(&mut writer)?;
             }
 
-            if ti + 1 < tokens.len() && tokens[ti+1].typ == OpType::Keyword(KeywordType::End) {
+            // only write the label once, nasm does not allow redefinitions
+            if after_block || before_end || is_target {
                 writeln!(writer, "addr_{ti}:")?;
             }
-            
-            if let OpType::Keyword(keyword) = &token.typ {
-                match keyword {
-                    &KeywordType::End |
-                    &KeywordType::While => {
-                        writeln!(writer, "addr_{ti}:")?;
-                    }
-                    _ => ()
-                }
-            }
+        }
 
+        // checked ops always go through the stack so the checks only live here
+        let needs_check = checked && matches!(token.typ, OpType::Instruction(
+            InstructionType::Load8 | InstructionType::Load32 | InstructionType::Load64 |
+            InstructionType::Store8 | InstructionType::Store32 | InstructionType::Store64 |
+            InstructionType::DivMod
+        ));
+        if use_cache && !needs_check && cache.compile_op(&mut writer, token, &mut strings, &mut constants)? {
+            ti += 1;
+            continue;
         }
+        cache.flush(&mut writer)?;
+
         match token.typ.clone() {
             // stack
 
@@ -136,6 +150,12 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                         strings.push(token.text.clone());
                         ti += 1;
                     }
+                    InstructionType::PushCStr => {
+                        writeln!(writer, "    mov rax, str_{}", strings.len())?;
+                        writeln!(writer, "    push rax")?;
+                        strings.push(format!("{}\0", token.text));
+                        ti += 1;
+                    }
                     InstructionType::Drop => {
                         writeln!(writer, "    pop rax")?;
                         ti += 1;
@@ -183,6 +203,9 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                     },
                     InstructionType::Load8 => {
                         writeln!(writer, "    pop rax")?;
+                        if checked {
+                            check_mem_access(&mut writer, &mut checks, token, 1)?;
+                        }
                         writeln!(writer, "    xor rbx, rbx")?;
                         writeln!(writer, "    mov bl, byte [rax]")?;
                         writeln!(writer, "    push rbx")?;
@@ -192,13 +215,19 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                     InstructionType::Store8 => {
                         writeln!(writer, "    pop rbx")?;
                         writeln!(writer, "    pop rax")?;
+                        if checked {
+                            check_mem_access(&mut writer, &mut checks, token, 1)?;
+                        }
                         writeln!(writer, "    mov byte [rax], bl")?;
                         ti += 1;
                     }
                     InstructionType::Load32 => {
                         writeln!(writer, "    pop rax")?;
+                        if checked {
+                            check_mem_access(&mut writer, &mut checks, token, 4)?;
+                        }
                         writeln!(writer, "    xor rbx, rbx")?;
-                        writeln!(writer, "    mov bl, dword [rax]")?;
+                        writeln!(writer, "    mov ebx, dword [rax]")?;
                         writeln!(writer, "    push rbx")?;
                         ti += 1;
                     }
@@ -206,13 +235,19 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                     InstructionType::Store32 => {
                         writeln!(writer, "    pop rbx")?;
                         writeln!(writer, "    pop rax")?;
-                        writeln!(writer, "    mov dword[rax], bl")?;
+                        if checked {
+                            check_mem_access(&mut writer, &mut checks, token, 4)?;
+                        }
+                        writeln!(writer, "    mov dword [rax], ebx")?;
                         ti += 1;
                     }
                     InstructionType::Load64 => {
                         writeln!(writer, "    pop rax")?;
+                        if checked {
+                            check_mem_access(&mut writer, &mut checks, token, 8)?;
+                        }
                         writeln!(writer, "    xor rbx, rbx")?;
-                        writeln!(writer, "    mov bl, qword [rax]")?;
+                        writeln!(writer, "    mov rbx, qword [rax]")?;
                         writeln!(writer, "    push rbx")?;
                         ti += 1;
                     }
@@ -220,7 +255,10 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                     InstructionType::Store64 => {
                         writeln!(writer, "    pop rbx")?;
                         writeln!(writer, "    pop rax")?;
-                        writeln!(writer, "    mov qword [rax], bl")?;
+                        if checked {
+                            check_mem_access(&mut writer, &mut checks, token, 8)?;
+                        }
+                        writeln!(writer, "    mov qword [rax], rbx")?;
                         ti += 1;
                     }
         
@@ -331,6 +369,10 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                         writeln!(writer, "    xor rdx, rdx")?;
                         writeln!(writer, "    pop rbx")?;
                         writeln!(writer, "    pop rax")?;
+                        if checked {
+                            writeln!(writer, "    test rbx, rbx")?;
+                            writeln!(writer, "    jz {}", check_failed(&mut checks, token, "Division by zero"))?;
+                        }
                         writeln!(writer, "    div rbx")?;
                         writeln!(writer, "    push rax")?;
                         writeln!(writer, "    push rdx")?;
@@ -411,6 +453,24 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                         writeln!(writer, "    push mem_{}", token.addr.unwrap())?;
                         ti += 1;
                     },
+                    InstructionType::Argc => {
+                        writeln!(writer, "    mov rax, qword [args_ptr]")?;
+                        writeln!(writer, "    push qword [rax]")?;
+                        ti += 1;
+                    },
+                    InstructionType::Argv => {
+                        writeln!(writer, "    mov rax, qword [args_ptr]")?;
+                        writeln!(writer, "    add rax, 8")?;
+                        writeln!(writer, "    push rax")?;
+                        ti += 1;
+                    },
+                    InstructionType::Envp => {
+                        writeln!(writer, "    mov rax, qword [args_ptr]")?;
+                        writeln!(writer, "    mov rbx, qword [rax]")?;
+                        writeln!(writer, "    lea rax, [rax+rbx*8+16]")?;
+                        writeln!(writer, "    push rax")?;
+                        ti += 1;
+                    },
                     InstructionType::None => {
                         println!("{token:?}");
                         unreachable!()
@@ -479,7 +539,7 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                     }
                     KeywordType::End => {
                         if ti + 1 != token.jmp {
-                            // writeln!(writer, "    jmp addr_{}", token.jmp)?;
+                            writeln!(writer, "    jmp addr_{}", token.jmp)?;
                         }
                         ti += 1;
                     },
@@ -503,6 +563,9 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
                     },
                     KeywordType::FunctionDef => {
                         writeln!(writer, "{}:", token.text)?;
+                        if checked {
+                            check_ret_stack(&mut writer, &mut checks, token)?;
+                        }
                         writeln!(writer, "    pop rbx")?;
                         writeln!(writer, "    mov qword [rbp], rbx")?;
                         writeln!(writer, "    add rbp, 8")?;
@@ -537,6 +600,9 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
 
                         writeln!(writer, "global {}", token.text)?;
                         writeln!(writer, "{}:", token.text)?;
+                        if checked {
+                            check_ret_stack(&mut writer, &mut checks, token)?;
+                        }
                         
                         writeln!(writer, "    pop rbx")?;
                         writeln!(writer, "    mov qword [rbp], rbx")?;
@@ -584,20 +650,33 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
         }
     }
     writeln!(writer, "addr_{ti}:")?;
-    if crate::config::ENABLE_EXPORTED_FUNCTIONS && !args.lib_mode {
+    if !args.lib_mode {
         writeln!(writer, "end:")?;
         writeln!(writer, "    mov rax, 60")?;
         writeln!(writer, "    mov rdi, 0")?;
         writeln!(writer, "    syscall")?;
     }
+    if checked {
+        write_check_routines(&mut writer, &checks, &memories)?;
+    }
     writeln!(writer, "segment .data")?;
     for (i, s) in strings.iter().enumerate() {
-        let s_chars = s.chars().map(|c| (c as u32).to_string()).collect::<Vec<String>>();
-        let s_list = s_chars.join(",");
+        if s.is_empty() {
+            writeln!(writer, "    str_{i}:")?;
+            continue;
+        }
+        let s_list = s.bytes().map(|c| c.to_string()).collect::<Vec<String>>().join(",");
         writeln!(writer, "    str_{}: db {} ; {}", i, s_list, s.escape_default())?;
     }
+    for (i, msg) in checks.iter().enumerate() {
+        let bytes = msg.bytes().map(|b| b.to_string()).collect::<Vec<String>>();
+        writeln!(writer, "    check_msg_{}: db {}", i, bytes.join(","))?;
+    }
     
-    for (_, c) in constants {
+    // sorted so the same program always gives the same output
+    let mut constants = constants.into_values().collect::<Vec<_>>();
+    constants.sort_by(|a, b| a.name.cmp(&b.name));
+    for c in constants {
         if !c.used {
             continue;
         }
@@ -617,23 +696,63 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
     for s in memories {
         writeln!(writer, "    mem_{}: resb {}", s.id, s.size)?;
     }
-    writeln!(writer, "    ret_stack: resq 256")?;
+    writeln!(writer, "    ret_stack: resq {}", crate::RET_STACK_SZ)?;
+    writeln!(writer, "    args_ptr: resq 1")?;
     
     // for t in tokens {
     //     println!("{t:?}");
     // }
 
-    writer.flush()?;
+    let mut code = String::from_utf8(writer.into_inner()?)?;
+
+    if use_cache {
+        let (optimised, counts) = peephole::optimise(&code);
+        if args.stats {
+            info!("peephole: {} -> {} lines", code.lines().count(), optimised.lines().count());
+            for (name, n) in counts {
+                info!("peephole: {name} applied {n} times");
+            }
+        }
+        code = optimised;
+    }
 
-    
     pre_compile_steps(
-        String::from_utf8_lossy(writer.buffer()).to_string().as_str(),
+        code.as_str(),
         functions
     )?;
 
-    linux_x86_64_compile_and_link(&of_a, &of_o, &of_c, args.quiet)?;
+    match args.emit {
+        Emit::Asm => {
+            fs::write(&of_a, &code)?;
+            if !linux_x86_64_compile_and_link(&of_a, &of_o, &of_c, args.quiet)? {
+                if args.run {
+                    error!("nasm not found, can not run {}", of_a.display());
+                    return Err(eyre!(""));
+                }
+                return Ok(0);
+            }
+        }
+        Emit::Obj => {
+            let obj = assembler::assemble(&code)?;
+            let of_o = of_c.with_extension("o");
+            fs::write(&of_o, elf::relocatable(&obj)?)?;
+            if !args.quiet {
+                info!("wrote object file {}", of_o.display());
+            }
+            return Ok(0);
+        }
+        Emit::Exe => {
+            let obj = assembler::assemble(&code)?;
+            write_executable(&of_c, &elf::executable(&obj)?)?;
+            if !args.quiet {
+                info!("wrote executable {}", of_c.display());
+            }
+        }
+        Emit::Llvm | Emit::Qbe => unreachable!()
+    }
+
     if args.run {
-        let c = linux_x86_64_run(&of_c, &[], args.quiet)?;
+        let c = linux_x86_64_run(&of_c, &args.program_args, args.quiet)?;
         return Ok(c);
     }
 
@@ -642,6 +761,94 @@ pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
 }
 
 
+fn write_executable(path: &PathBuf, bin: &[u8]) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    // remove first so a running binary with the same name does not make this fail
+    let _ = fs::remove_file(path);
+    fs::write(path, bin)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+/// Label of a new check that reports `what` at the location of `token` and
+/// exits with `CHECK_FAILED_EXIT`
+fn check_failed(checks: &mut Vec<String>, token: &Operator, what: &str) -> String {
+    checks.push(format!("{}:{}:{} error: {what}\n", token.loc.0, token.loc.1, token.loc.2));
+    format!("check_{}", checks.len() - 1)
+}
+
+/// Fail if the `size` bytes at the address in rax start in a memory block but
+/// do not end in it, or start in the bss between or after the blocks
+fn check_mem_access(writer: &mut impl Write, checks: &mut Vec<String>, token: &Operator, size: usize) -> Result<()> {
+    writeln!(writer, "    mov rcx, {size}")?;
+    writeln!(writer, "    call check_mem")?;
+    writeln!(writer, "    test rcx, rcx")?;
+    writeln!(writer, "    jnz {}", check_failed(checks, token, "Invalid memory access"))?;
+    Ok(())
+}
+
+/// Fail if a call would not fit on the return stack anymore, at the start of
+/// a function so rbx still has to be popped
+fn check_ret_stack(writer: &mut impl Write, checks: &mut Vec<String>, token: &Operator) -> Result<()> {
+    writeln!(writer, "    mov rax, ret_stack+{}", crate::RET_STACK_SZ * 8)?;
+    writeln!(writer, "    cmp rbp, rax")?;
+    writeln!(writer, "    jae {}", check_failed(checks, token, "Return stack overflow"))?;
+    Ok(())
+}
+
+/// `check_mem` and the code every failed check jumps to
+fn write_check_routines(writer: &mut impl Write, checks: &[String], memories: &[Memory]) -> Result<()> {
+    // rax is the address and rcx the size of the access, rcx is 0 afterwards
+    // if it is fine. The blocks are laid out in order in the bss followed by
+    // the return stack and args_ptr
+    let first = memories.first().map_or_else(|| String::from("ret_stack"), |m| format!("mem_{}", m.id));
+    writeln!(writer, "check_mem:")?;
+    writeln!(writer, "    add rcx, rax")?;
+    writeln!(writer, "    mov rsi, {first}")?;
+    writeln!(writer, "    cmp rax, rsi")?;
+    writeln!(writer, "    jae .blocks")?;
+    writeln!(writer, "    cmp rcx, rsi")?;
+    writeln!(writer, "    ja .bad")?;
+    writeln!(writer, "    jmp .ok")?;
+    writeln!(writer, ".blocks:")?;
+    for m in memories {
+        writeln!(writer, "    mov rsi, mem_{}+{}", m.id, m.size)?;
+        writeln!(writer, "    cmp rax, rsi")?;
+        writeln!(writer, "    jae .after_{}", m.id)?;
+        writeln!(writer, "    mov rdi, mem_{}", m.id)?;
+        writeln!(writer, "    cmp rax, rdi")?;
+        writeln!(writer, "    jb .bad")?;
+        writeln!(writer, "    cmp rcx, rsi")?;
+        writeln!(writer, "    ja .bad")?;
+        writeln!(writer, "    jmp .ok")?;
+        writeln!(writer, ".after_{}:", m.id)?;
+    }
+    writeln!(writer, "    mov rsi, args_ptr+8")?;
+    writeln!(writer, "    cmp rax, rsi")?;
+    writeln!(writer, "    jae .ok")?;
+    writeln!(writer, ".bad:")?;
+    writeln!(writer, "    mov rcx, 1")?;
+    writeln!(writer, "    ret")?;
+    writeln!(writer, ".ok:")?;
+    writeln!(writer, "    xor rcx, rcx")?;
+    writeln!(writer, "    ret")?;
+
+    for (i, msg) in checks.iter().enumerate() {
+        writeln!(writer, "check_{i}:")?;
+        writeln!(writer, "    mov rsi, check_msg_{i}")?;
+        writeln!(writer, "    mov rdx, {}", msg.len())?;
+        writeln!(writer, "    jmp check_fail")?;
+    }
+    writeln!(writer, "check_fail:")?;
+    writeln!(writer, "    mov rax, 1")?;
+    writeln!(writer, "    mov rdi, 2")?;
+    writeln!(writer, "    syscall")?;
+    writeln!(writer, "    mov rax, 60")?;
+    writeln!(writer, "    mov rdi, {}", crate::CHECK_FAILED_EXIT)?;
+    writeln!(writer, "    syscall")?;
+    Ok(())
+}
+
 fn pre_compile_steps(_code: &str, functions: Vec<Function>) -> Result<()> {
     let mut has_main = false;
 
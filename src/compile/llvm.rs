@@ -0,0 +1,537 @@
+use std::{fs, path::PathBuf, io::Write, collections::HashMap};
+use crate::{constants::{Operator, OpType, KeywordType}, Args, lerror, error};
+use color_eyre::Result;
+use crate::compile::commands::{llvm_compile_and_link, linux_x86_64_run};
+use crate::constants::InstructionType;
+use super::{Constant, Memory, Function};
+use eyre::eyre;
+
+/*
+ * The data stack is a global i64 array shared by all functions. Every
+ * function keeps its own copy of the stack pointer in an alloca, so mem2reg
+ * turns it into an ssa value and pushes can be forwarded to the pops.
+ * The copy gets synced with @sp around calls and on return.
+ * Every operator starts its own basic block, simplifycfg merges them again.
+ */
+
+/// Size of the data stack in cells
+const DATA_STACK_SZ: usize = 128 * 1024;
+
+const SYSCALL_REGS: [&str; 6] = ["rdi", "rsi", "rdx", "r10", "r8", "r9"];
+
+const PRELUDE: &str = r#"declare void @llvm.trap() cold noreturn nounwind
+
+define internal void @dbg_print(i64 %v) {
+entry:
+  %buf = alloca [32 x i8], align 1
+  %nl = getelementptr [32 x i8], [32 x i8]* %buf, i64 0, i64 31
+  store i8 10, i8* %nl
+  br label %loop
+loop:
+  %n = phi i64 [ %v, %entry ], [ %q, %loop ]
+  %i = phi i64 [ 31, %entry ], [ %j, %loop ]
+  %j = sub i64 %i, 1
+  %q = udiv i64 %n, 10
+  %r = urem i64 %n, 10
+  %c64 = add i64 %r, 48
+  %c = trunc i64 %c64 to i8
+  %p = getelementptr [32 x i8], [32 x i8]* %buf, i64 0, i64 %j
+  store i8 %c, i8* %p
+  %more = icmp ne i64 %q, 0
+  br i1 %more, label %loop, label %done
+done:
+  %addr = ptrtoint i8* %p to i64
+  %len = sub i64 32, %j
+  %ret = call i64 asm sideeffect "syscall", "={rax},{rax},{rdi},{rsi},{rdx},~{rcx},~{r11},~{memory}"(i64 1, i64 1, i64 %addr, i64 %len)
+  ret void
+}
+"#;
+
+struct Builder {
+    out: Vec<u8>,
+    tmp: usize,
+}
+
+impl Builder {
+    fn tmp(&mut self) -> String {
+        self.tmp += 1;
+        format!("%t{}", self.tmp)
+    }
+
+    fn slot(&mut self, depth: usize) -> Result<String> {
+        let sp = self.tmp();
+        writeln!(self.out, "  {sp} = load i64, i64* %sp")?;
+        let idx = self.tmp();
+        writeln!(self.out, "  {idx} = sub i64 {sp}, {depth}")?;
+        let ptr = self.tmp();
+        writeln!(self.out, "  {ptr} = getelementptr [{DATA_STACK_SZ} x i64], [{DATA_STACK_SZ} x i64]* @data_stack, i64 0, i64 {idx}")?;
+        Ok(ptr)
+    }
+
+    fn move_sp(&mut self, by: i64) -> Result<()> {
+        let sp = self.tmp();
+        writeln!(self.out, "  {sp} = load i64, i64* %sp")?;
+        let new = self.tmp();
+        writeln!(self.out, "  {new} = add i64 {sp}, {by}")?;
+        writeln!(self.out, "  store i64 {new}, i64* %sp")?;
+        Ok(())
+    }
+
+    fn push(&mut self, v: &str) -> Result<()> {
+        let ptr = self.slot(0)?;
+        writeln!(self.out, "  store i64 {v}, i64* {ptr}")?;
+        self.move_sp(1)
+    }
+
+    fn pop(&mut self) -> Result<String> {
+        self.move_sp(-1)?;
+        let ptr = self.slot(0)?;
+        let v = self.tmp();
+        writeln!(self.out, "  {v} = load i64, i64* {ptr}")?;
+        Ok(v)
+    }
+
+    /// Read the value `depth` cells below the top without popping it
+    fn peek(&mut self, depth: usize) -> Result<String> {
+        let ptr = self.slot(depth + 1)?;
+        let v = self.tmp();
+        writeln!(self.out, "  {v} = load i64, i64* {ptr}")?;
+        Ok(v)
+    }
+
+    fn binop(&mut self, op: &str) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let r = self.tmp();
+        writeln!(self.out, "  {r} = {op} i64 {a}, {b}")?;
+        self.push(&r)
+    }
+
+    fn shift(&mut self, op: &str) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        // x86_64 only uses the low 6 bits of the count, llvm makes bigger ones poison
+        let m = self.tmp();
+        writeln!(self.out, "  {m} = and i64 {b}, 63")?;
+        let r = self.tmp();
+        writeln!(self.out, "  {r} = {op} i64 {a}, {m}")?;
+        self.push(&r)
+    }
+
+    fn cmp(&mut self, cond: &str) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let c = self.tmp();
+        writeln!(self.out, "  {c} = icmp {cond} i64 {a}, {b}")?;
+        let r = self.tmp();
+        writeln!(self.out, "  {r} = zext i1 {c} to i64")?;
+        self.push(&r)
+    }
+
+    fn load(&mut self, ty: &str) -> Result<()> {
+        let a = self.pop()?;
+        let p = self.tmp();
+        writeln!(self.out, "  {p} = inttoptr i64 {a} to {ty}*")?;
+        let v = self.tmp();
+        writeln!(self.out, "  {v} = load {ty}, {ty}* {p}, align 1")?;
+        if ty == "i64" {
+            return self.push(&v);
+        }
+        let r = self.tmp();
+        writeln!(self.out, "  {r} = zext {ty} {v} to i64")?;
+        self.push(&r)
+    }
+
+    fn store(&mut self, ty: &str) -> Result<()> {
+        let v = self.pop()?;
+        let a = self.pop()?;
+        let p = self.tmp();
+        writeln!(self.out, "  {p} = inttoptr i64 {a} to {ty}*")?;
+        let v = if ty == "i64" { v } else {
+            let t = self.tmp();
+            writeln!(self.out, "  {t} = trunc i64 {v} to {ty}")?;
+            t
+        };
+        writeln!(self.out, "  store {ty} {v}, {ty}* {p}, align 1")?;
+        Ok(())
+    }
+
+    fn sync_out(&mut self) -> Result<()> {
+        let sp = self.tmp();
+        writeln!(self.out, "  {sp} = load i64, i64* %sp")?;
+        writeln!(self.out, "  store i64 {sp}, i64* @sp")?;
+        Ok(())
+    }
+
+    fn sync_in(&mut self) -> Result<()> {
+        let sp = self.tmp();
+        writeln!(self.out, "  {sp} = load i64, i64* @sp")?;
+        writeln!(self.out, "  store i64 {sp}, i64* %sp")?;
+        Ok(())
+    }
+}
+
+/// Name of the llvm function for an mclang function
+fn mangle(name: &str) -> String {
+    format!("@\"mcl.{}\"", escape(name))
+}
+
+/// Escape bytes for an llvm string or quoted name
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if (0x20..0x7f).contains(&b) && b != b'"' && b != b'\\' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{b:02X}"));
+        }
+    }
+    out
+}
+
+pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
+    let debug = args.get_opt_level()? < 1;
+
+    let mut of_c = PathBuf::from(&args.out_file);
+    let (mut of_o, mut of_a) = if args.out_file == *crate::DEFAULT_OUT_FILE {
+        let of_o = PathBuf::from("/tmp/mclang_comp.o");
+        let of_a = PathBuf::from("/tmp/mclang_comp.ll");
+        (of_o, of_a)
+    } else {
+        let of_o = PathBuf::from(&args.out_file);
+        let of_a = PathBuf::from(&args.out_file);
+        (of_o, of_a)
+    };
+
+    of_c.set_extension("");
+    of_o.set_extension("o");
+    of_a.set_extension("ll");
+
+    let mut b = Builder { out: Vec::new(), tmp: 0 };
+    let mut memories:  Vec<Memory> = Vec::new();
+    let mut constants:  HashMap<String, Constant> = HashMap::new();
+    let mut functions: Vec<Function> = Vec::new();
+    let mut strings: Vec<String> = Vec::new();
+
+    let mut in_function = false;
+    // set after a terminator, the next block cannot be fallen into
+    let mut terminated = false;
+
+    let mut ti = 0;
+    while ti < tokens.len() {
+        let token = &tokens[ti];
+
+        if in_function {
+            if !terminated {
+                writeln!(b.out, "  br label %addr_{ti}")?;
+            }
+            terminated = false;
+            writeln!(b.out, "addr_{ti}:")?;
+            if debug {
+                if token.typ == OpType::Instruction(InstructionType::PushInt) {
+                    writeln!(b.out, "  ; -- {:?} {}", token.typ, token.value)?;
+                } else if matches!(token.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) {
+                    writeln!(b.out, "  ; -- {:?} {}", token.typ, token.text.escape_debug())?;
+                } else {
+                    writeln!(b.out, "  ; -- {:?}", token.typ)?;
+                }
+            }
+        }
+
+        match token.typ.clone() {
+            OpType::Instruction(instruction) => {
+                match instruction {
+                    InstructionType::PushInt => {
+                        b.push(&token.value.to_string())?;
+                    },
+                    InstructionType::PushStr => {
+                        b.push(&token.text.len().to_string())?;
+                        let len = token.text.len();
+                        b.push(&format!("ptrtoint ([{len} x i8]* @str_{} to i64)", strings.len()))?;
+                        strings.push(token.text.clone());
+                    }
+                    InstructionType::PushCStr => {
+                        let len = token.text.len() + 1;
+                        b.push(&format!("ptrtoint ([{len} x i8]* @str_{} to i64)", strings.len()))?;
+                        strings.push(format!("{}\0", token.text));
+                    }
+                    InstructionType::Drop => {
+                        b.move_sp(-1)?;
+                    },
+                    InstructionType::Print => {
+                        let v = b.pop()?;
+                        writeln!(b.out, "  call void @dbg_print(i64 {v})")?;
+                    },
+                    InstructionType::Dup => {
+                        let v = b.peek(0)?;
+                        b.push(&v)?;
+                    },
+                    InstructionType::Rot => {
+                        let c = b.pop()?;
+                        let bb = b.pop()?;
+                        let a = b.pop()?;
+                        b.push(&bb)?;
+                        b.push(&c)?;
+                        b.push(&a)?;
+                    },
+                    InstructionType::Swap => {
+                        let v1 = b.pop()?;
+                        let v2 = b.pop()?;
+                        b.push(&v1)?;
+                        b.push(&v2)?;
+                    },
+                    InstructionType::Over => {
+                        let v = b.peek(1)?;
+                        b.push(&v)?;
+                    },
+                    InstructionType::Load8 => b.load("i8")?,
+                    InstructionType::Load32 => b.load("i32")?,
+                    InstructionType::Load64 => b.load("i64")?,
+                    InstructionType::Store8 => b.store("i8")?,
+                    InstructionType::Store32 => b.store("i32")?,
+                    InstructionType::Store64 => b.store("i64")?,
+
+                    // math
+                    InstructionType::Plus => b.binop("add")?,
+                    InstructionType::Minus => b.binop("sub")?,
+                    InstructionType::Mul => b.binop("mul")?,
+                    InstructionType::Band => b.binop("and")?,
+                    InstructionType::Bor => b.binop("or")?,
+                    InstructionType::Shr => b.shift("lshr")?,
+                    InstructionType::Shl => b.shift("shl")?,
+                    InstructionType::Equals => b.cmp("eq")?,
+                    InstructionType::NotEquals => b.cmp("ne")?,
+                    InstructionType::Lt => b.cmp("slt")?,
+                    InstructionType::Gt => b.cmp("sgt")?,
+                    InstructionType::Le => b.cmp("sle")?,
+                    InstructionType::Ge => b.cmp("sge")?,
+                    InstructionType::DivMod => {
+                        let d = b.pop()?;
+                        let n = b.pop()?;
+                        // dividing by zero is undefined in llvm, trap like the div of x86_64 does
+                        let zero = b.tmp();
+                        writeln!(b.out, "  {zero} = icmp eq i64 {d}, 0")?;
+                        writeln!(b.out, "  br i1 {zero}, label %addr_{ti}_div_zero, label %addr_{ti}_div")?;
+                        writeln!(b.out, "addr_{ti}_div_zero:")?;
+                        writeln!(b.out, "  call void @llvm.trap()")?;
+                        writeln!(b.out, "  unreachable")?;
+                        writeln!(b.out, "addr_{ti}_div:")?;
+                        let q = b.tmp();
+                        writeln!(b.out, "  {q} = udiv i64 {n}, {d}")?;
+                        let r = b.tmp();
+                        writeln!(b.out, "  {r} = urem i64 {n}, {d}")?;
+                        b.push(&q)?;
+                        b.push(&r)?;
+                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
+                        };
+                        let mut vals = vec![b.pop()?];
+                        for _ in 0..argc {
+                            vals.push(b.pop()?);
+                        }
+                        let mut constraints = vec![String::from("={rax}"), String::from("{rax}")];
+                        constraints.extend(SYSCALL_REGS[..argc].iter().map(|r| format!("{{{r}}}")));
+                        constraints.extend(["~{rcx}", "~{r11}", "~{memory}"].map(String::from));
+                        let operands = vals.iter().map(|v| format!("i64 {v}")).collect::<Vec<String>>();
+                        let r = b.tmp();
+                        writeln!(b.out, "  {r} = call i64 asm sideeffect \"syscall\", \"{}\"({})", constraints.join(","), operands.join(", "))?;
+                        b.push(&r)?;
+                    },
+                    InstructionType::Argc |
+                    InstructionType::Argv |
+                    InstructionType::Envp => {
+                        let a = b.tmp();
+                        writeln!(b.out, "  {a} = load i64, i64* @args_ptr")?;
+                        let p = b.tmp();
+                        writeln!(b.out, "  {p} = inttoptr i64 {a} to i64*")?;
+                        let argc = b.tmp();
+                        writeln!(b.out, "  {argc} = load i64, i64* {p}")?;
+                        let v = match instruction {
+                            InstructionType::Argc => argc,
+                            InstructionType::Argv => {
+                                let r = b.tmp();
+                                writeln!(b.out, "  {r} = add i64 {a}, 8")?;
+                                r
+                            }
+                            _ => {
+                                let o = b.tmp();
+                                writeln!(b.out, "  {o} = shl i64 {argc}, 3")?;
+                                let e = b.tmp();
+                                writeln!(b.out, "  {e} = add i64 {a}, {o}")?;
+                                let r = b.tmp();
+                                writeln!(b.out, "  {r} = add i64 {e}, 16")?;
+                                r
+                            }
+                        };
+                        b.push(&v)?;
+                    },
+                    InstructionType::MemUse => {
+                        let id = token.addr.unwrap();
+                        let size = memories.iter().find(|m| m.id == id).map_or(1, |m| m.size.max(1));
+                        b.push(&format!("ptrtoint ([{size} x i8]* @mem_{id} to i64)"))?;
+                    },
+                    InstructionType::None => {
+                        println!("{token:?}");
+                        unreachable!()
+                    },
+                    InstructionType::FnCall => {
+                        b.sync_out()?;
+                        writeln!(b.out, "  call void {}()", mangle(&token.text))?;
+                        b.sync_in()?;
+                    },
+                    InstructionType::Return => {
+                        b.sync_out()?;
+                        writeln!(b.out, "  ret void")?;
+                        terminated = true;
+                    },
+                    InstructionType::CastBool |
+                    InstructionType::CastPtr |
+                    InstructionType::CastInt |
+                    InstructionType::CastVoid |
+                    InstructionType::TypeBool |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeVoid |
+                    InstructionType::TypeAny |
+                    InstructionType::Returns |
+                    InstructionType::With => (),
+                    InstructionType::ConstUse => {
+                        let c = constants.get(&token.text).unwrap();
+                        b.push(&c.value_i.unwrap().to_string())?;
+                    },
+                }
+            }
+
+            OpType::Keyword(keyword) => {
+                match keyword {
+                    KeywordType::If |
+                    KeywordType::Do => {
+                        let v = b.pop()?;
+                        let c = b.tmp();
+                        writeln!(b.out, "  {c} = icmp ne i64 {v}, 0")?;
+                        writeln!(b.out, "  br i1 {c}, label %addr_{}, label %addr_{}", ti + 1, token.jmp)?;
+                        terminated = true;
+                    }
+                    KeywordType::Else |
+                    KeywordType::End => {
+                        writeln!(b.out, "  br label %addr_{}", token.jmp)?;
+                        terminated = true;
+                    },
+                    KeywordType::While => (),
+                    KeywordType::Memory => {
+                        memories.push(Memory { size: token.value, loc: token.loc.clone(), id: token.addr.unwrap() });
+                    }
+                    KeywordType::ConstantDef => {
+                        let c = Constant{
+                            loc: token.loc.clone(),
+                            name: token.text.clone(),
+                            value_i: Some(token.value),
+                            value_s: None,
+                            used: debug,
+                        };
+                        constants.insert(token.text.clone(), c);
+                    },
+                    KeywordType::FunctionDef => {
+                        writeln!(b.out, "define internal void {}() {{", mangle(&token.text))?;
+                        writeln!(b.out, "entry:")?;
+                        writeln!(b.out, "  %sp = alloca i64")?;
+                        b.sync_in()?;
+                        functions.push(Function { loc: token.loc.clone(), name: token.text.clone(), exter: false});
+                        in_function = true;
+                        terminated = false;
+                    },
+                    KeywordType::FunctionDone => {
+                        b.sync_out()?;
+                        writeln!(b.out, "  ret void")?;
+                        writeln!(b.out, "}}")?;
+                        in_function = false;
+                        terminated = true;
+                    }
+                    KeywordType::FunctionThen => (),
+                    KeywordType::Function |
+                    KeywordType::Include |
+                    KeywordType::Inline |
+                    KeywordType::Export |
+                    KeywordType::Constant => unreachable!(),
+                    KeywordType::FunctionDefExported => {
+                        lerror!(&token.loc, "Exported functions are not supported with --emit llvm");
+                        return Err(eyre!(""));
+                    },
+                }
+            }
+        }
+        ti += 1;
+    }
+
+    if !functions.iter().any(|f| f.name == "main") && !args.lib_mode {
+        crate::errors::missing_main_fn();
+        return Err(eyre!(""));
+    }
+
+    let mut out: Vec<u8> = Vec::new();
+    writeln!(out, "target triple = \"x86_64-unknown-linux-gnu\"")?;
+    writeln!(out)?;
+    writeln!(out, "@data_stack = internal global [{DATA_STACK_SZ} x i64] zeroinitializer, align 16")?;
+    writeln!(out, "@sp = internal global i64 0")?;
+    writeln!(out, "@args_ptr = internal global i64 0")?;
+
+    for (i, s) in strings.iter().enumerate() {
+        writeln!(out, "@str_{i} = private unnamed_addr constant [{} x i8] c\"{}\"", s.len(), escape(s))?;
+    }
+
+    for m in &memories {
+        writeln!(out, "@mem_{} = internal global [{} x i8] zeroinitializer, align 8", m.id, m.size.max(1))?;
+    }
+    writeln!(out)?;
+    write!(out, "{PRELUDE}")?;
+    writeln!(out)?;
+
+    out.extend_from_slice(&b.out);
+
+    if !args.lib_mode {
+        // the entry point is called so the stack has the alignment llvm expects
+        writeln!(out)?;
+        writeln!(out, "module asm \".globl _start\"")?;
+        writeln!(out, "module asm \"_start:\"")?;
+        // argc, the argv pointers and the envp pointers are on the stack
+        writeln!(out, "module asm \"    movq %rsp, %rdi\"")?;
+        writeln!(out, "module asm \"    call mcl_start\"")?;
+        writeln!(out, "define void @mcl_start(i64 %args) noreturn {{")?;
+        writeln!(out, "  store i64 %args, i64* @args_ptr")?;
+        writeln!(out, "  call void {}()", mangle("main"))?;
+        writeln!(out, "  %r = call i64 asm sideeffect \"syscall\", \"={{rax}},{{rax}},{{rdi}},~{{rcx}},~{{r11}},~{{memory}}\"(i64 60, i64 0)")?;
+        writeln!(out, "  unreachable")?;
+        writeln!(out, "}}")?;
+    }
+
+    fs::write(&of_a, out)?;
+
+    if !llvm_compile_and_link(&of_a, &of_o, &of_c, debug, args.quiet)? {
+        if args.run {
+            error!("llc not found, can not run {}", of_a.display());
+            return Err(eyre!(""));
+        }
+        return Ok(0);
+    }
+
+    if args.run {
+        let c = linux_x86_64_run(&of_c, &args.program_args, args.quiet)?;
+        return Ok(c);
+    }
+
+    Ok(0)
+}
@@ -1,8 +1,18 @@
 use crate::constants::Loc;
 
 pub mod linux_x86_64;
+pub mod linux_aarch64;
+pub mod c;
+pub mod wasm32_wasi;
+pub mod llvm;
+pub mod qbe;
 pub mod commands;
+pub mod assembler;
+pub mod elf;
+pub mod stack_cache;
+pub mod peephole;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub loc: Loc,
@@ -13,6 +23,7 @@ pub struct Constant {
     // extern: bool
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Memory {
     pub size: usize,
@@ -20,6 +31,7 @@ pub struct Memory {
     pub id: usize
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Function {
     pub loc: Loc,
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+/*
+ * Used by linux_x86_64 at -O1, runs over the generated nasm source and
+ * rewrites short instruction sequences, mostly what is left of values that
+ * get pushed just to be popped again right after.
+ * Patterns only match consecutive instructions, labels and comments are never
+ * part of a match (except for an explicit `$l:`), so nothing is ever moved
+ * across a jump target.
+ */
+
+/// A pattern line is an instruction or a label, operands can be captures:
+/// `$r`, `$s`: a 64 bit general purpose register other than rsp
+/// `$i`: an immediate or a symbol
+/// `$l`: a label
+/// anything else starting with `$`: any operand
+/// A capture used twice has to match the same text both times.
+struct Pattern {
+    name: &'static str,
+    find: &'static [&'static str],
+    replace: &'static [&'static str],
+    /// Extra condition on the captures
+    guard: Option<fn(&Captures) -> bool>,
+}
+
+type Captures = HashMap<&'static str, String>;
+
+/// Tried in order at every instruction, the first one that matches wins
+const PATTERNS: &[Pattern] = &[
+    // `dup drop`, a flush right before an op that pops again
+    Pattern { name: "push-pop", find: &["push $r", "pop $r"], replace: &[], guard: None },
+    Pattern { name: "push-pop-mov", find: &["push $x", "pop $r"], replace: &["mov $r, $x"], guard: None },
+    Pattern { name: "pop-push", find: &["pop $r", "push $r"], replace: &["mov $r, qword [rsp]"], guard: None },
+    // `mov rax, N; push rax; pop rbx` ends up here after push-pop-mov
+    Pattern { name: "mov-imm-forward", find: &["mov $r, $i", "mov $s, $r"], replace: &["mov $r, $i", "mov $s, $i"], guard: None },
+    Pattern { name: "dead-mov", find: &["mov $r, $x", "mov $r, $y"], replace: &["mov $r, $y"], guard: Some(|c| !reads(&c["$y"], &c["$r"])) },
+    Pattern { name: "dead-mov-pop", find: &["mov $r, $x", "pop $r"], replace: &["pop $r"], guard: None },
+    Pattern { name: "mov-self", find: &["mov $r, $r"], replace: &[], guard: None },
+    Pattern { name: "jmp-next", find: &["jmp $l", "$l:"], replace: &["$l:"], guard: None },
+];
+
+/// 64 bit registers with their 32, 16 and 8 bit parts
+const REGS: [[&str; 4]; 15] = [
+    ["rax", "eax", "ax", "al"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["rbp", "ebp", "bp", "bpl"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Instr(String, Vec<String>),
+    Label(String),
+    Other,
+}
+
+impl Line {
+    fn parse(line: &str) -> Self {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') {
+            return Self::Other;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            return match trimmed.strip_suffix(':') {
+                Some(name) => Self::Label(name.to_string()),
+                None => Self::Other,
+            };
+        }
+        let (op, rest) = trimmed.split_once(char::is_whitespace).unwrap_or((trimmed, ""));
+        let operands = rest.split(',')
+            .map(|o| o.trim().to_string())
+            .filter(|o| !o.is_empty())
+            .collect();
+        Self::Instr(op.to_string(), operands)
+    }
+
+    fn to_asm(&self) -> String {
+        match self {
+            Self::Instr(op, operands) if operands.is_empty() => format!("    {op}"),
+            Self::Instr(op, operands) => format!("    {op} {}", operands.join(", ")),
+            Self::Label(name) => format!("{name}:"),
+            Self::Other => unreachable!(),
+        }
+    }
+}
+
+fn is_reg(s: &str) -> bool {
+    REGS.iter().any(|r| r[0] == s)
+}
+
+fn is_imm(s: &str) -> bool {
+    let s = s.strip_prefix('-').unwrap_or(s);
+    if s.parse::<u64>().is_ok() {
+        return true;
+    }
+    !is_reg(s) && !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Does the operand `op` use any part of the register `reg`
+fn reads(op: &str, reg: &str) -> bool {
+    let Some(parts) = REGS.iter().find(|r| r[0] == reg) else {
+        return true;
+    };
+    op.split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|w| parts.contains(&w))
+}
+
+fn capture(caps: &mut Captures, name: &'static str, value: &str) -> bool {
+    let ok = match name {
+        "$r" | "$s" => is_reg(value),
+        "$i" => is_imm(value),
+        _ => true,
+    };
+    if !ok {
+        return false;
+    }
+    match caps.get(name) {
+        Some(v) => v == value,
+        None => {
+            caps.insert(name, value.to_string());
+            true
+        }
+    }
+}
+
+fn match_line(pat: &'static str, line: &Line, caps: &mut Captures) -> bool {
+    match (Line::parse(&format!("    {pat}")), line) {
+        (_, Line::Label(name)) => {
+            let Some(p) = pat.strip_suffix(':') else {
+                return false;
+            };
+            if p.starts_with('$') {
+                capture(caps, p, name)
+            } else {
+                p == name
+            }
+        }
+        (Line::Instr(pop, pops), Line::Instr(op, ops)) => {
+            if pop != *op || pops.len() != ops.len() {
+                return false;
+            }
+            let pat_ops = pat.split_once(' ').map_or(Vec::new(), |(_, r)| r.split(", ").collect::<Vec<_>>());
+            pat_ops.iter().zip(ops).all(|(p, o)| {
+                if p.starts_with('$') {
+                    capture(caps, p, o)
+                } else {
+                    p == o
+                }
+            })
+        }
+        _ => false,
+    }
+}
+
+fn substitute(pat: &str, caps: &Captures) -> Line {
+    let mut s = pat.to_string();
+    for (k, v) in caps {
+        s = s.replace(k, v);
+    }
+    if let Some(name) = s.strip_suffix(':') {
+        Line::Label(name.to_string())
+    } else {
+        Line::parse(&format!("    {s}"))
+    }
+}
+
+/// Run all patterns over `code` until none of them match anymore, returns the
+/// new code and how often each pattern got applied
+pub fn optimise(code: &str) -> (String, Vec<(&'static str, usize)>) {
+    let mut lines: Vec<(String, Line)> = code.lines()
+        .map(|l| (l.to_string(), Line::parse(l)))
+        .collect();
+    let mut counts: Vec<(&'static str, usize)> = PATTERNS.iter().map(|p| (p.name, 0)).collect();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let mut applied = false;
+        for (pi, p) in PATTERNS.iter().enumerate() {
+            if i + p.find.len() > lines.len() {
+                continue;
+            }
+            let mut caps = Captures::new();
+            let matched = p.find.iter()
+                .zip(&lines[i..])
+                .all(|(pat, (_, line))| match_line(pat, line, &mut caps));
+            if !matched || !p.guard.is_none_or(|g| g(&caps)) {
+                continue;
+            }
+            let new = p.replace.iter().map(|r| {
+                let l = substitute(r, &caps);
+                (l.to_asm(), l)
+            });
+            lines.splice(i..i + p.find.len(), new);
+            counts[pi].1 += 1;
+            applied = true;
+            break;
+        }
+        if applied {
+            // the line before can start a new match now
+            i = i.saturating_sub(1);
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut out = lines.into_iter().map(|(l, _)| l).collect::<Vec<_>>().join("\n");
+    out.push('\n');
+    (out, counts)
+}
@@ -0,0 +1,457 @@
+use std::{fs, path::PathBuf, io::Write, collections::HashMap};
+use crate::{constants::{Operator, OpType, KeywordType}, Args, lerror, error};
+use color_eyre::Result;
+use crate::compile::commands::{qbe_compile_and_link, linux_x86_64_run};
+use crate::constants::InstructionType;
+use super::{Constant, Memory, Function};
+use eyre::eyre;
+
+/*
+ * The data stack is a global array, $sp holds the address one past the top.
+ * Functions keep it in the %sp temporary, qbe accepts temporaries that get
+ * assigned more than once and turns them into ssa itself, so the stack
+ * traffic inside a function ends up in registers.
+ * %sp gets synced with $sp around calls and on return.
+ */
+
+/// Size of the data stack in bytes
+const DATA_STACK_SZ: usize = 1024 * 1024;
+
+const PRELUDE: &str = r"function $dbg_print(l %v) {
+@start
+    %n =l copy %v
+    %buf =l alloc8 32
+    %p =l add %buf, 31
+    storeb 10, %p
+@loop
+    %p =l sub %p, 1
+    %r =l urem %n, 10
+    %r =l add %r, 48
+    storeb %r, %p
+    %n =l udiv %n, 10
+    %more =w cnel %n, 0
+    jnz %more, @loop, @done
+@done
+    %end =l add %buf, 32
+    %len =l sub %end, %p
+    %x =l call $mcl_syscall(l 1, l 1, l %p, l %len)
+    ret
+}
+";
+
+/// Appended to the assembly qbe outputs, qbe has no way to do syscalls
+const RUNTIME: &str = r"
+.text
+mcl_syscall:
+    movq %rdi, %rax
+    movq %rsi, %rdi
+    movq %rdx, %rsi
+    movq %rcx, %rdx
+    movq %r8, %r10
+    movq %r9, %r8
+    movq 8(%rsp), %r9
+    syscall
+    ret
+";
+
+const START: &str = r"
+.globl _start
+_start:
+    movq %rsp, %rdi
+    call mcl_start
+";
+
+struct Builder {
+    out: Vec<u8>,
+    tmp: usize,
+}
+
+impl Builder {
+    fn tmp(&mut self) -> String {
+        self.tmp += 1;
+        format!("%t{}", self.tmp)
+    }
+
+    fn push(&mut self, v: &str) -> Result<()> {
+        writeln!(self.out, "    storel {v}, %sp")?;
+        writeln!(self.out, "    %sp =l add %sp, 8")?;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<String> {
+        let v = self.tmp();
+        writeln!(self.out, "    %sp =l sub %sp, 8")?;
+        writeln!(self.out, "    {v} =l loadl %sp")?;
+        Ok(v)
+    }
+
+    /// Read the value `depth` cells below the top without popping it
+    fn peek(&mut self, depth: usize) -> Result<String> {
+        let p = self.tmp();
+        writeln!(self.out, "    {p} =l sub %sp, {}", (depth + 1) * 8)?;
+        let v = self.tmp();
+        writeln!(self.out, "    {v} =l loadl {p}")?;
+        Ok(v)
+    }
+
+    fn binop(&mut self, op: &str) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let r = self.tmp();
+        writeln!(self.out, "    {r} =l {op} {a}, {b}")?;
+        self.push(&r)
+    }
+
+    fn load(&mut self, op: &str) -> Result<()> {
+        let a = self.pop()?;
+        let v = self.tmp();
+        writeln!(self.out, "    {v} =l {op} {a}")?;
+        self.push(&v)
+    }
+
+    fn store(&mut self, op: &str) -> Result<()> {
+        let v = self.pop()?;
+        let a = self.pop()?;
+        writeln!(self.out, "    {op} {v}, {a}")?;
+        Ok(())
+    }
+
+    fn sync_out(&mut self) -> Result<()> {
+        writeln!(self.out, "    storel %sp, $sp")?;
+        Ok(())
+    }
+
+    fn sync_in(&mut self) -> Result<()> {
+        writeln!(self.out, "    %sp =l loadl $sp")?;
+        Ok(())
+    }
+}
+
+/// Turn an mclang word into a valid qbe global name
+fn mangle(name: &str) -> String {
+    let mut s = String::from("$mcl.");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            s.push(c);
+        } else {
+            s.push_str(&format!("_{:02x}", c as u32));
+        }
+    }
+    s
+}
+
+pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
+    let debug = args.get_opt_level()? < 1;
+
+    let mut of_c = PathBuf::from(&args.out_file);
+    let (mut of_o, mut of_a) = if args.out_file == *crate::DEFAULT_OUT_FILE {
+        let of_o = PathBuf::from("/tmp/mclang_comp.o");
+        let of_a = PathBuf::from("/tmp/mclang_comp.ssa");
+        (of_o, of_a)
+    } else {
+        let of_o = PathBuf::from(&args.out_file);
+        let of_a = PathBuf::from(&args.out_file);
+        (of_o, of_a)
+    };
+
+    of_c.set_extension("");
+    of_o.set_extension("o");
+    of_a.set_extension("ssa");
+
+    let mut b = Builder { out: Vec::new(), tmp: 0 };
+    let mut memories:  Vec<Memory> = Vec::new();
+    let mut constants:  HashMap<String, Constant> = HashMap::new();
+    let mut functions: Vec<Function> = Vec::new();
+    let mut strings: Vec<String> = Vec::new();
+
+    let mut in_function = false;
+
+    let mut ti = 0;
+    while ti < tokens.len() {
+        let token = &tokens[ti];
+
+        // every operator gets a block, qbe falls through into the next one
+        if in_function {
+            writeln!(b.out, "@addr_{ti}")?;
+            if debug {
+                if token.typ == OpType::Instruction(InstructionType::PushInt) {
+                    writeln!(b.out, "    # -- {:?} {}", token.typ, token.value)?;
+                } else if matches!(token.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) {
+                    writeln!(b.out, "    # -- {:?} {}", token.typ, token.text.escape_debug())?;
+                } else {
+                    writeln!(b.out, "    # -- {:?}", token.typ)?;
+                }
+            }
+        }
+
+        match token.typ.clone() {
+            OpType::Instruction(instruction) => {
+                match instruction {
+                    InstructionType::PushInt => {
+                        // qbe constants are 64 bit, bigger values wrap around
+                        b.push(&(token.value as i64).to_string())?;
+                    },
+                    InstructionType::PushStr => {
+                        b.push(&token.text.len().to_string())?;
+                        b.push(&format!("$str_{}", strings.len()))?;
+                        strings.push(token.text.clone());
+                    }
+                    InstructionType::PushCStr => {
+                        b.push(&format!("$str_{}", strings.len()))?;
+                        strings.push(format!("{}\0", token.text));
+                    }
+                    InstructionType::Drop => {
+                        writeln!(b.out, "    %sp =l sub %sp, 8")?;
+                    },
+                    InstructionType::Print => {
+                        let v = b.pop()?;
+                        writeln!(b.out, "    call $dbg_print(l {v})")?;
+                    },
+                    InstructionType::Dup => {
+                        let v = b.peek(0)?;
+                        b.push(&v)?;
+                    },
+                    InstructionType::Rot => {
+                        let c = b.pop()?;
+                        let bb = b.pop()?;
+                        let a = b.pop()?;
+                        b.push(&bb)?;
+                        b.push(&c)?;
+                        b.push(&a)?;
+                    },
+                    InstructionType::Swap => {
+                        let v1 = b.pop()?;
+                        let v2 = b.pop()?;
+                        b.push(&v1)?;
+                        b.push(&v2)?;
+                    },
+                    InstructionType::Over => {
+                        let v = b.peek(1)?;
+                        b.push(&v)?;
+                    },
+                    InstructionType::Load8 => b.load("loadub")?,
+                    InstructionType::Load32 => b.load("loaduw")?,
+                    InstructionType::Load64 => b.load("loadl")?,
+                    InstructionType::Store8 => b.store("storeb")?,
+                    InstructionType::Store32 => b.store("storew")?,
+                    InstructionType::Store64 => b.store("storel")?,
+
+                    // math
+                    InstructionType::Plus => b.binop("add")?,
+                    InstructionType::Minus => b.binop("sub")?,
+                    InstructionType::Mul => b.binop("mul")?,
+                    InstructionType::Band => b.binop("and")?,
+                    InstructionType::Bor => b.binop("or")?,
+                    InstructionType::Shr => b.binop("shr")?,
+                    InstructionType::Shl => b.binop("shl")?,
+                    InstructionType::Equals => b.binop("ceql")?,
+                    InstructionType::NotEquals => b.binop("cnel")?,
+                    InstructionType::Lt => b.binop("csltl")?,
+                    InstructionType::Gt => b.binop("csgtl")?,
+                    InstructionType::Le => b.binop("cslel")?,
+                    InstructionType::Ge => b.binop("csgel")?,
+                    InstructionType::DivMod => {
+                        let d = b.pop()?;
+                        let n = b.pop()?;
+                        let q = b.tmp();
+                        writeln!(b.out, "    {q} =l udiv {n}, {d}")?;
+                        let r = b.tmp();
+                        writeln!(b.out, "    {r} =l urem {n}, {d}")?;
+                        b.push(&q)?;
+                        b.push(&r)?;
+                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
+                        };
+                        let mut vals = vec![b.pop()?];
+                        for _ in 0..argc {
+                            vals.push(b.pop()?);
+                        }
+                        let operands = vals.iter().map(|v| format!("l {v}")).collect::<Vec<String>>();
+                        let r = b.tmp();
+                        writeln!(b.out, "    {r} =l call $mcl_syscall({})", operands.join(", "))?;
+                        b.push(&r)?;
+                    },
+                    InstructionType::Argc |
+                    InstructionType::Argv |
+                    InstructionType::Envp => {
+                        let a = b.tmp();
+                        writeln!(b.out, "    {a} =l loadl $args_ptr")?;
+                        let r = b.tmp();
+                        match instruction {
+                            InstructionType::Argc => writeln!(b.out, "    {r} =l loadl {a}")?,
+                            InstructionType::Argv => writeln!(b.out, "    {r} =l add {a}, 8")?,
+                            _ => {
+                                let argc = b.tmp();
+                                writeln!(b.out, "    {argc} =l loadl {a}")?;
+                                let o = b.tmp();
+                                writeln!(b.out, "    {o} =l mul {argc}, 8")?;
+                                let e = b.tmp();
+                                writeln!(b.out, "    {e} =l add {a}, {o}")?;
+                                writeln!(b.out, "    {r} =l add {e}, 16")?;
+                            }
+                        }
+                        b.push(&r)?;
+                    },
+                    InstructionType::MemUse => {
+                        b.push(&format!("$mem_{}", token.addr.unwrap()))?;
+                    },
+                    InstructionType::None => {
+                        println!("{token:?}");
+                        unreachable!()
+                    },
+                    InstructionType::FnCall => {
+                        b.sync_out()?;
+                        writeln!(b.out, "    call {}()", mangle(&token.text))?;
+                        b.sync_in()?;
+                    },
+                    InstructionType::Return => {
+                        b.sync_out()?;
+                        writeln!(b.out, "    ret")?;
+                    },
+                    InstructionType::CastBool |
+                    InstructionType::CastPtr |
+                    InstructionType::CastInt |
+                    InstructionType::CastVoid |
+                    InstructionType::TypeBool |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeVoid |
+                    InstructionType::TypeAny |
+                    InstructionType::Returns |
+                    InstructionType::With => (),
+                    InstructionType::ConstUse => {
+                        let c = constants.get(&token.text).unwrap();
+                        b.push(&(c.value_i.unwrap() as i64).to_string())?;
+                    },
+                }
+            }
+
+            OpType::Keyword(keyword) => {
+                match keyword {
+                    KeywordType::If |
+                    KeywordType::Do => {
+                        // jnz only looks at the low 32 bits
+                        let v = b.pop()?;
+                        let c = b.tmp();
+                        writeln!(b.out, "    {c} =w cnel {v}, 0")?;
+                        writeln!(b.out, "    jnz {c}, @addr_{}, @addr_{}", ti + 1, token.jmp)?;
+                    }
+                    KeywordType::Else |
+                    KeywordType::End => {
+                        writeln!(b.out, "    jmp @addr_{}", token.jmp)?;
+                    },
+                    KeywordType::While => (),
+                    KeywordType::Memory => {
+                        memories.push(Memory { size: token.value, loc: token.loc.clone(), id: token.addr.unwrap() });
+                    }
+                    KeywordType::ConstantDef => {
+                        let c = Constant{
+                            loc: token.loc.clone(),
+                            name: token.text.clone(),
+                            value_i: Some(token.value),
+                            value_s: None,
+                            used: debug,
+                        };
+                        constants.insert(token.text.clone(), c);
+                    },
+                    KeywordType::FunctionDef => {
+                        writeln!(b.out, "function {}() {{", mangle(&token.text))?;
+                        writeln!(b.out, "@start")?;
+                        b.sync_in()?;
+                        functions.push(Function { loc: token.loc.clone(), name: token.text.clone(), exter: false});
+                        in_function = true;
+                    },
+                    KeywordType::FunctionDone => {
+                        b.sync_out()?;
+                        writeln!(b.out, "    ret")?;
+                        writeln!(b.out, "}}")?;
+                        in_function = false;
+                    }
+                    KeywordType::FunctionThen => (),
+                    KeywordType::Function |
+                    KeywordType::Include |
+                    KeywordType::Inline |
+                    KeywordType::Export |
+                    KeywordType::Constant => unreachable!(),
+                    KeywordType::FunctionDefExported => {
+                        lerror!(&token.loc, "Exported functions are not supported with --emit qbe");
+                        return Err(eyre!(""));
+                    },
+                }
+            }
+        }
+        ti += 1;
+    }
+
+    if !functions.iter().any(|f| f.name == "main") && !args.lib_mode {
+        crate::errors::missing_main_fn();
+        return Err(eyre!(""));
+    }
+
+    let mut out: Vec<u8> = Vec::new();
+    writeln!(out, "data $data_stack = align 16 {{ z {DATA_STACK_SZ} }}")?;
+    writeln!(out, "data $sp = align 8 {{ l $data_stack }}")?;
+    writeln!(out, "data $args_ptr = align 8 {{ l 0 }}")?;
+
+    for (i, s) in strings.iter().enumerate() {
+        if s.is_empty() {
+            writeln!(out, "data $str_{i} = {{ b 0 }}")?;
+            continue;
+        }
+        let s_list = s.bytes().map(|c| format!("b {c}")).collect::<Vec<String>>().join(", ");
+        writeln!(out, "data $str_{i} = {{ {s_list} }} # {}", s.escape_default())?;
+    }
+
+    for m in &memories {
+        writeln!(out, "data $mem_{} = align 8 {{ z {} }}", m.id, m.size.max(1))?;
+    }
+    writeln!(out)?;
+    write!(out, "{PRELUDE}")?;
+    writeln!(out)?;
+
+    out.extend_from_slice(&b.out);
+
+    let mut runtime = String::from(RUNTIME);
+    if !args.lib_mode {
+        writeln!(out)?;
+        writeln!(out, "function $mcl_start(l %args) {{")?;
+        writeln!(out, "@start")?;
+        writeln!(out, "    storel %args, $args_ptr")?;
+        writeln!(out, "    call {}()", mangle("main"))?;
+        writeln!(out, "    %r =l call $mcl_syscall(l 60, l 0)")?;
+        writeln!(out, "    ret")?;
+        writeln!(out, "}}")?;
+        runtime.push_str(START);
+    }
+
+    fs::write(&of_a, out)?;
+
+    if !qbe_compile_and_link(&of_a, &of_o, &of_c, &runtime, args.quiet)? {
+        if args.run {
+            error!("qbe not found, can not run {}", of_a.display());
+            return Err(eyre!(""));
+        }
+        return Ok(0);
+    }
+
+    if args.run {
+        let c = linux_x86_64_run(&of_c, &args.program_args, args.quiet)?;
+        return Ok(c);
+    }
+
+    Ok(0)
+}
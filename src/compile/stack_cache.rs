@@ -0,0 +1,283 @@
+use std::{io::Write, collections::HashMap};
+use crate::constants::{Operator, OpType, KeywordType, InstructionType};
+use color_eyre::Result;
+use super::Constant;
+
+/*
+ * Used by linux_x86_64 at -O1, keeps the top of the data stack in registers
+ * while compiling straight line code.
+ * The cached values sit on top of the hardware stack, regs[0] is the one
+ * directly above rsp. Before anything that is not handled here and before
+ * every jump target the cache gets flushed, so blocks always start and end
+ * with the whole stack in memory.
+ */
+
+/// Registers nothing else in the generated code touches, they also survive
+/// syscalls and `_dbg_print`
+const CACHE_REGS: [&str; 4] = ["r12", "r13", "r14", "r15"];
+
+/// Registers a syscall takes its number and arguments in
+const SYSCALL_REGS: [&str; 7] = ["rax", "rdi", "rsi", "rdx", "r10", "r8", "r9"];
+
+#[derive(Debug, Default)]
+pub struct StackCache {
+    regs: Vec<&'static str>,
+}
+
+impl StackCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a free register, spilling the bottom most cached value if there is none
+    fn alloc(&mut self, writer: &mut impl Write) -> Result<&'static str> {
+        if self.regs.len() == CACHE_REGS.len() {
+            let r = self.regs.remove(0);
+            writeln!(writer, "    push {r}")?;
+            return Ok(r);
+        }
+        Ok(CACHE_REGS.iter().find(|r| !self.regs.contains(r)).unwrap())
+    }
+
+    fn push(&mut self, writer: &mut impl Write, src: &str) -> Result<()> {
+        let r = self.alloc(writer)?;
+        writeln!(writer, "    mov {r}, {src}")?;
+        self.regs.push(r);
+        Ok(())
+    }
+
+    /// Make sure the top `n` values are in registers
+    fn ensure(&mut self, writer: &mut impl Write, n: usize) -> Result<()> {
+        while self.regs.len() < n {
+            let r = *CACHE_REGS.iter().find(|r| !self.regs.contains(r)).unwrap();
+            writeln!(writer, "    pop {r}")?;
+            self.regs.insert(0, r);
+        }
+        Ok(())
+    }
+
+    /// Move the top of the stack into `dst`
+    fn pop_into(&mut self, writer: &mut impl Write, dst: &str) -> Result<()> {
+        if let Some(r) = self.regs.pop() {
+            writeln!(writer, "    mov {dst}, {r}")?;
+        } else {
+            writeln!(writer, "    pop {dst}")?;
+        }
+        Ok(())
+    }
+
+    fn top(&self, n: usize) -> &'static str {
+        self.regs[self.regs.len() - 1 - n]
+    }
+
+    /// Write all cached values back to the hardware stack
+    pub fn flush(&mut self, writer: &mut impl Write) -> Result<()> {
+        for r in self.regs.drain(..) {
+            writeln!(writer, "    push {r}")?;
+        }
+        Ok(())
+    }
+
+    /// Compile `token` using the cache, returns false if it has to be compiled
+    /// normally after a flush
+    pub fn compile_op(&mut self, writer: &mut impl Write, token: &Operator, strings: &mut Vec<String>, constants: &mut HashMap<String, Constant>) -> Result<bool> {
+        match &token.typ {
+            OpType::Instruction(instruction) => {
+                match instruction {
+                    InstructionType::PushInt => {
+                        self.push(writer, &token.value.to_string())?;
+                    },
+                    InstructionType::PushStr => {
+                        self.push(writer, &token.text.len().to_string())?;
+                        self.push(writer, &format!("str_{}", strings.len()))?;
+                        strings.push(token.text.clone());
+                    },
+                    InstructionType::PushCStr => {
+                        self.push(writer, &format!("str_{}", strings.len()))?;
+                        strings.push(format!("{}\0", token.text));
+                    },
+                    InstructionType::MemUse => {
+                        self.push(writer, &format!("mem_{}", token.addr.unwrap()))?;
+                    },
+                    InstructionType::Argc |
+                    InstructionType::Argv |
+                    InstructionType::Envp => {
+                        let r = self.alloc(writer)?;
+                        writeln!(writer, "    mov rax, qword [args_ptr]")?;
+                        match instruction {
+                            InstructionType::Argc => writeln!(writer, "    mov {r}, qword [rax]")?,
+                            InstructionType::Argv => writeln!(writer, "    lea {r}, [rax+8]")?,
+                            _ => {
+                                writeln!(writer, "    mov {r}, qword [rax]")?;
+                                writeln!(writer, "    lea {r}, [rax+{r}*8+16]")?;
+                            }
+                        }
+                        self.regs.push(r);
+                    },
+                    InstructionType::ConstUse => {
+                        self.push(writer, &format!("qword [const_{}]", token.text))?;
+                        let mut c = constants.get(&token.text).unwrap().clone();
+                        c.used = true;
+                        constants.insert(token.text.clone(), c);
+                    },
+                    InstructionType::Drop => {
+                        if self.regs.pop().is_none() {
+                            writeln!(writer, "    add rsp, 8")?;
+                        }
+                    },
+                    InstructionType::Dup => {
+                        self.ensure(writer, 1)?;
+                        let a = self.top(0);
+                        self.push(writer, a)?;
+                    },
+                    InstructionType::Over => {
+                        self.ensure(writer, 2)?;
+                        let a = self.top(1);
+                        self.push(writer, a)?;
+                    },
+                    InstructionType::Swap => {
+                        self.ensure(writer, 2)?;
+                        let l = self.regs.len();
+                        self.regs.swap(l - 1, l - 2);
+                    },
+                    InstructionType::Rot => {
+                        self.ensure(writer, 3)?;
+                        let l = self.regs.len();
+                        self.regs[l - 3..].rotate_left(1);
+                    },
+                    InstructionType::Print => {
+                        self.pop_into(writer, "rdi")?;
+                        writeln!(writer, "    call _dbg_print")?;
+                    },
+                    InstructionType::Load8 |
+                    InstructionType::Load32 |
+                    InstructionType::Load64 => {
+                        self.ensure(writer, 1)?;
+                        let a = self.top(0);
+                        match instruction {
+                            InstructionType::Load8 => writeln!(writer, "    movzx {a}, byte [{a}]")?,
+                            InstructionType::Load32 => writeln!(writer, "    mov {a}d, dword [{a}]")?,
+                            _ => writeln!(writer, "    mov {a}, qword [{a}]")?,
+                        }
+                    },
+                    InstructionType::Store8 |
+                    InstructionType::Store32 |
+                    InstructionType::Store64 => {
+                        self.ensure(writer, 2)?;
+                        let v = self.regs.pop().unwrap();
+                        let a = self.regs.pop().unwrap();
+                        match instruction {
+                            InstructionType::Store8 => writeln!(writer, "    mov byte [{a}], {v}b")?,
+                            InstructionType::Store32 => writeln!(writer, "    mov dword [{a}], {v}d")?,
+                            _ => writeln!(writer, "    mov qword [{a}], {v}")?,
+                        }
+                    },
+                    InstructionType::Plus |
+                    InstructionType::Minus |
+                    InstructionType::Mul |
+                    InstructionType::Band |
+                    InstructionType::Bor => {
+                        self.ensure(writer, 2)?;
+                        let b = self.regs.pop().unwrap();
+                        let a = self.top(0);
+                        let op = match instruction {
+                            InstructionType::Plus => "add",
+                            InstructionType::Minus => "sub",
+                            InstructionType::Mul => "imul",
+                            InstructionType::Band => "and",
+                            _ => "or",
+                        };
+                        writeln!(writer, "    {op} {a}, {b}")?;
+                    },
+                    InstructionType::Shl |
+                    InstructionType::Shr => {
+                        self.ensure(writer, 2)?;
+                        let b = self.regs.pop().unwrap();
+                        let a = self.top(0);
+                        let op = if *instruction == InstructionType::Shl { "shl" } else { "shr" };
+                        writeln!(writer, "    mov rcx, {b}")?;
+                        writeln!(writer, "    {op} {a}, cl")?;
+                    },
+                    InstructionType::Equals |
+                    InstructionType::NotEquals |
+                    InstructionType::Lt |
+                    InstructionType::Gt |
+                    InstructionType::Le |
+                    InstructionType::Ge => {
+                        self.ensure(writer, 2)?;
+                        let b = self.regs.pop().unwrap();
+                        let a = self.top(0);
+                        let cc = match instruction {
+                            InstructionType::Equals => "e",
+                            InstructionType::NotEquals => "ne",
+                            InstructionType::Lt => "l",
+                            InstructionType::Gt => "g",
+                            InstructionType::Le => "le",
+                            _ => "ge",
+                        };
+                        writeln!(writer, "    cmp {a}, {b}")?;
+                        writeln!(writer, "    set{cc} al")?;
+                        writeln!(writer, "    movzx {a}, al")?;
+                    },
+                    InstructionType::DivMod => {
+                        self.ensure(writer, 2)?;
+                        let b = self.top(0);
+                        let a = self.top(1);
+                        writeln!(writer, "    xor rdx, rdx")?;
+                        writeln!(writer, "    mov rax, {a}")?;
+                        writeln!(writer, "    div {b}")?;
+                        writeln!(writer, "    mov {a}, rax")?;
+                        writeln!(writer, "    mov {b}, rdx")?;
+                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
+                        };
+                        for reg in &SYSCALL_REGS[..=argc] {
+                            self.pop_into(writer, reg)?;
+                        }
+                        writeln!(writer, "    syscall")?;
+                        self.push(writer, "rax")?;
+                    },
+                    InstructionType::CastBool |
+                    InstructionType::CastPtr |
+                    InstructionType::CastInt |
+                    InstructionType::CastVoid |
+                    InstructionType::TypeBool |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeVoid |
+                    InstructionType::TypeAny |
+                    InstructionType::Returns |
+                    InstructionType::With => (),
+                    InstructionType::FnCall |
+                    InstructionType::Return |
+                    InstructionType::None => return Ok(false),
+                }
+            },
+            OpType::Keyword(KeywordType::If | KeywordType::Do) => {
+                // the condition can stay in its register, the rest gets flushed
+                let Some(cond) = self.regs.pop() else {
+                    return Ok(false);
+                };
+                self.flush(writer)?;
+                writeln!(writer, "    test {cond}, {cond}")?;
+                writeln!(writer, "    jz addr_{}", token.jmp)?;
+            },
+            OpType::Keyword(_) => return Ok(false),
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,437 @@
+use std::{fs, path::PathBuf, io::Write, collections::HashMap};
+use crate::{constants::{Operator, OpType, KeywordType}, Args, Emit, lerror};
+use color_eyre::Result;
+use crate::compile::commands::{wasm_assemble, wasm_run};
+use crate::constants::InstructionType;
+use super::{Constant, Function};
+use eyre::eyre;
+
+/*
+ * Linear memory layout
+ * 16..24: iovec used for fd_write and fd_read
+ * 24..32: number of bytes written or read
+ * 32..64: buffer for _dbg_print
+ * 64..: memory blocks, then string literals, then the data stack
+ * after that: argc, argv and envp laid out like linux does on the stack,
+ *   in pages added at startup
+ *
+ * The data stack lives in linear memory and grows up from $sp, so every
+ * operator leaves the wasm operand stack empty and blocks need no types.
+ */
+
+const IOV: usize = 16;
+const NBYTES: usize = 24;
+const PRINT_BUF_END: usize = 64;
+const DATA_START: usize = 64;
+
+/// Size of the data stack in bytes
+const DATA_STACK_SZ: usize = 1024 * 1024;
+
+const PAGE_SZ: usize = 64 * 1024;
+
+// linux_x86_64 syscall numbers that get translated into wasi calls
+const SYS_READ: usize = 0;
+const SYS_WRITE: usize = 1;
+const SYS_EXIT: usize = 60;
+const SYS_EXIT_GROUP: usize = 231;
+const ENOSYS: usize = 38;
+
+/// Turn an mclang word into a valid wat identifier
+fn mangle(name: &str) -> String {
+    let mut s = String::from("$");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || "!#%&'*+-./:<=>?@^_`|~".contains(c) {
+            s.push(c);
+        } else {
+            s.push_str(&format!("${:02x}", c as u32));
+        }
+    }
+    s
+}
+
+/// Escape bytes for a wat string literal
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if (0x20..0x7f).contains(&b) && b != b'"' && b != b'\\' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{b:02x}"));
+        }
+    }
+    out
+}
+
+fn binop(writer: &mut impl Write, op: &str) -> Result<()> {
+    writeln!(writer, "    call $pop local.set $b call $pop local.get $b {op} call $push")?;
+    Ok(())
+}
+
+fn cmp(writer: &mut impl Write, op: &str) -> Result<()> {
+    writeln!(writer, "    call $pop local.set $b call $pop local.get $b {op} i64.extend_i32_u call $push")?;
+    Ok(())
+}
+
+pub fn compile(tokens: &[Operator], args: &Args) -> Result<i32>{
+    let debug = args.get_opt_level()? < 1;
+
+    let mut of_c = PathBuf::from(&args.out_file);
+    let mut of_a = if args.out_file == *crate::DEFAULT_OUT_FILE {
+        PathBuf::from("/tmp/mclang_comp.wat")
+    } else {
+        PathBuf::from(&args.out_file)
+    };
+
+    of_c.set_extension("wasm");
+    of_a.set_extension("wat");
+
+    let mut writer: Vec<u8> = Vec::new();
+    let mut constants:  HashMap<String, Constant> = HashMap::new();
+    let mut functions: Vec<Function> = Vec::new();
+    let mut strings: Vec<(usize, String)> = Vec::new();
+
+    // memory blocks can be used before the definition gets reached
+    let mut data_end = DATA_START;
+    let mut memories: HashMap<usize, usize> = HashMap::new();
+    for token in tokens.iter().filter(|t| t.typ == OpType::Keyword(KeywordType::Memory)) {
+        memories.insert(token.addr.unwrap(), data_end);
+        data_end = (data_end + token.value + 7) & !7;
+    }
+
+    let mut ti = 0;
+    while ti < tokens.len() {
+        let token = &tokens[ti];
+        if debug {
+            if token.typ == OpType::Instruction(InstructionType::PushInt) {
+                writeln!(writer, "    ;; -- {:?} {}", token.typ, token.value)?;
+            } else if matches!(token.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) {
+                writeln!(writer, "    ;; -- {:?} {}", token.typ, token.text.escape_debug())?;
+            } else {
+                writeln!(writer, "    ;; -- {:?}", token.typ)?;
+            }
+        }
+
+        match token.typ.clone() {
+            OpType::Instruction(instruction) => {
+                match instruction {
+                    InstructionType::PushInt => {
+                        writeln!(writer, "    i64.const {} call $push", token.value)?;
+                    },
+                    InstructionType::PushStr => {
+                        writeln!(writer, "    i64.const {} call $push", token.text.len())?;
+                        writeln!(writer, "    i64.const {data_end} call $push")?;
+                        strings.push((data_end, token.text.clone()));
+                        data_end += token.text.len();
+                    }
+                    InstructionType::PushCStr => {
+                        writeln!(writer, "    i64.const {data_end} call $push")?;
+                        strings.push((data_end, format!("{}\0", token.text)));
+                        data_end += token.text.len() + 1;
+                    }
+                    InstructionType::Drop => {
+                        writeln!(writer, "    call $pop drop")?;
+                    },
+                    InstructionType::Print => {
+                        writeln!(writer, "    call $pop call $dbg_print")?;
+                    },
+                    InstructionType::Dup => {
+                        writeln!(writer, "    call $pop local.tee $a call $push local.get $a call $push")?;
+                    },
+                    InstructionType::Rot => {
+                        writeln!(writer, "    call $pop local.set $c call $pop local.set $b call $pop local.set $a")?;
+                        writeln!(writer, "    local.get $b call $push local.get $c call $push local.get $a call $push")?;
+                    },
+                    InstructionType::Swap => {
+                        writeln!(writer, "    call $pop local.set $b call $pop local.set $a")?;
+                        writeln!(writer, "    local.get $b call $push local.get $a call $push")?;
+                    },
+                    InstructionType::Over => {
+                        writeln!(writer, "    call $pop local.set $b call $pop local.set $a")?;
+                        writeln!(writer, "    local.get $a call $push local.get $b call $push local.get $a call $push")?;
+                    },
+                    InstructionType::Load8 => {
+                        writeln!(writer, "    call $pop i32.wrap_i64 i64.load8_u call $push")?;
+                    }
+                    InstructionType::Load32 => {
+                        writeln!(writer, "    call $pop i32.wrap_i64 i64.load32_u call $push")?;
+                    }
+                    InstructionType::Load64 => {
+                        writeln!(writer, "    call $pop i32.wrap_i64 i64.load call $push")?;
+                    }
+                    InstructionType::Store8 => {
+                        writeln!(writer, "    call $pop local.set $b call $pop i32.wrap_i64 local.get $b i64.store8")?;
+                    }
+                    InstructionType::Store32 => {
+                        writeln!(writer, "    call $pop local.set $b call $pop i32.wrap_i64 local.get $b i64.store32")?;
+                    }
+                    InstructionType::Store64 => {
+                        writeln!(writer, "    call $pop local.set $b call $pop i32.wrap_i64 local.get $b i64.store")?;
+                    }
+
+                    // math
+                    InstructionType::Plus => binop(&mut writer, "i64.add")?,
+                    InstructionType::Minus => binop(&mut writer, "i64.sub")?,
+                    InstructionType::Mul => binop(&mut writer, "i64.mul")?,
+                    InstructionType::Band => binop(&mut writer, "i64.and")?,
+                    InstructionType::Bor => binop(&mut writer, "i64.or")?,
+                    InstructionType::Shr => binop(&mut writer, "i64.shr_u")?,
+                    InstructionType::Shl => binop(&mut writer, "i64.shl")?,
+                    InstructionType::Equals => cmp(&mut writer, "i64.eq")?,
+                    InstructionType::NotEquals => cmp(&mut writer, "i64.ne")?,
+                    InstructionType::Lt => cmp(&mut writer, "i64.lt_s")?,
+                    InstructionType::Gt => cmp(&mut writer, "i64.gt_s")?,
+                    InstructionType::Le => cmp(&mut writer, "i64.le_s")?,
+                    InstructionType::Ge => cmp(&mut writer, "i64.ge_s")?,
+                    InstructionType::DivMod => {
+                        writeln!(writer, "    call $pop local.set $b call $pop local.set $a")?;
+                        writeln!(writer, "    local.get $a local.get $b i64.div_u call $push")?;
+                        writeln!(writer, "    local.get $a local.get $b i64.rem_u call $push")?;
+                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
+                        };
+                        writeln!(writer, "    call $pop local.set $d")?;
+                        for (i, local) in ["$a", "$b", "$c"].iter().enumerate() {
+                            if i < argc {
+                                writeln!(writer, "    call $pop local.set {local}")?;
+                            } else {
+                                writeln!(writer, "    i64.const 0 local.set {local}")?;
+                            }
+                        }
+                        // none of the supported syscalls take more than 3 arguments
+                        for _ in 3..argc {
+                            writeln!(writer, "    call $pop drop")?;
+                        }
+                        writeln!(writer, "    local.get $d local.get $a local.get $b local.get $c call $syscall call $push")?;
+                    },
+                    InstructionType::Argc => {
+                        writeln!(writer, "    global.get $args i64.load call $push")?;
+                    },
+                    InstructionType::Argv => {
+                        writeln!(writer, "    global.get $args i32.const 8 i32.add i64.extend_i32_u call $push")?;
+                    },
+                    InstructionType::Envp => {
+                        writeln!(writer, "    global.get $args global.get $args i32.load i32.const 8 i32.mul i32.add i32.const 16 i32.add i64.extend_i32_u call $push")?;
+                    },
+                    InstructionType::MemUse => {
+                        writeln!(writer, "    i64.const {} call $push", memories[&token.addr.unwrap()])?;
+                    },
+                    InstructionType::None => {
+                        println!("{token:?}");
+                        unreachable!()
+                    },
+                    InstructionType::FnCall => {
+                        writeln!(writer, "    call {}", mangle(&token.text))?;
+                    },
+                    InstructionType::Return => {
+                        writeln!(writer, "    return")?;
+                    },
+                    InstructionType::CastBool |
+                    InstructionType::CastPtr |
+                    InstructionType::CastInt |
+                    InstructionType::CastVoid |
+                    InstructionType::TypeBool |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeVoid |
+                    InstructionType::TypeAny |
+                    InstructionType::Returns |
+                    InstructionType::With => (),
+                    InstructionType::ConstUse => {
+                        let c = constants.get(&token.text).unwrap();
+                        writeln!(writer, "    i64.const {} call $push", c.value_i.unwrap())?;
+                    },
+                }
+            }
+
+            OpType::Keyword(keyword) => {
+                match keyword {
+                    KeywordType::If => {
+                        writeln!(writer, "    call $pop i64.const 0 i64.ne if")?;
+                    }
+                    KeywordType::Else => {
+                        writeln!(writer, "    else")?;
+                    },
+                    KeywordType::While => {
+                        writeln!(writer, "    block loop")?;
+                    }
+                    KeywordType::Do => {
+                        writeln!(writer, "    call $pop i64.eqz br_if 1")?;
+                    }
+                    KeywordType::End => {
+                        // a while loops end jumps back to the condition
+                        if token.jmp < ti {
+                            writeln!(writer, "    br 0 end end")?;
+                        } else {
+                            writeln!(writer, "    end")?;
+                        }
+                    },
+                    KeywordType::Memory => (),
+                    KeywordType::ConstantDef => {
+                        let c = Constant{
+                            loc: token.loc.clone(),
+                            name: token.text.clone(),
+                            value_i: Some(token.value),
+                            value_s: None,
+                            used: debug,
+                        };
+                        constants.insert(token.text.clone(), c);
+                    },
+                    KeywordType::FunctionDef => {
+                        writeln!(writer, "  (func {}", mangle(&token.text))?;
+                        writeln!(writer, "    (local $a i64) (local $b i64) (local $c i64) (local $d i64)")?;
+                        functions.push(Function { loc: token.loc.clone(), name: token.text.clone(), exter: false});
+                    },
+                    KeywordType::FunctionDone => {
+                        writeln!(writer, "  )")?;
+                    }
+                    KeywordType::FunctionThen => (),
+                    KeywordType::Function |
+                    KeywordType::Include |
+                    KeywordType::Inline |
+                    KeywordType::Export |
+                    KeywordType::Constant => unreachable!(),
+                    KeywordType::FunctionDefExported => {
+                        lerror!(&token.loc, "Exported functions are not supported on wasm32-wasi");
+                        return Err(eyre!(""));
+                    },
+                }
+            }
+        }
+        ti += 1;
+    }
+
+    if !functions.iter().any(|f| f.name == "main") && !args.lib_mode {
+        crate::errors::missing_main_fn();
+        return Err(eyre!(""));
+    }
+
+    let stack_start = (data_end + 15) & !15;
+    let pages = (stack_start + DATA_STACK_SZ).div_ceil(PAGE_SZ);
+
+    let mut out: Vec<u8> = Vec::new();
+    writeln!(out, "(module")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"fd_write\" (func $fd_write (param i32 i32 i32 i32) (result i32)))")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"fd_read\" (func $fd_read (param i32 i32 i32 i32) (result i32)))")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"args_sizes_get\" (func $args_sizes_get (param i32 i32) (result i32)))")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"args_get\" (func $args_get (param i32 i32) (result i32)))")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"environ_sizes_get\" (func $environ_sizes_get (param i32 i32) (result i32)))")?;
+    writeln!(out, "  (import \"wasi_snapshot_preview1\" \"environ_get\" (func $environ_get (param i32 i32) (result i32)))")?;
+    writeln!(out, "  (memory (export \"memory\") {pages})")?;
+    writeln!(out, "  (global $sp (mut i32) (i32.const {stack_start}))")?;
+    writeln!(out, "  (global $args (mut i32) (i32.const 0))")?;
+
+    for (addr, s) in &strings {
+        writeln!(out, "  (data (i32.const {addr}) \"{}\")", escape(s))?;
+    }
+
+    writeln!(out, "  (func $push (param $v i64)")?;
+    writeln!(out, "    global.get $sp local.get $v i64.store")?;
+    writeln!(out, "    global.get $sp i32.const 8 i32.add global.set $sp)")?;
+    writeln!(out, "  (func $pop (result i64)")?;
+    writeln!(out, "    global.get $sp i32.const 8 i32.sub global.set $sp")?;
+    writeln!(out, "    global.get $sp i64.load)")?;
+
+    writeln!(out, "  (func $dbg_print (param $v i64) (local $p i32)")?;
+    writeln!(out, "    i32.const {} local.tee $p i32.const 10 i32.store8", PRINT_BUF_END - 1)?;
+    writeln!(out, "    loop")?;
+    writeln!(out, "      local.get $p i32.const 1 i32.sub local.tee $p")?;
+    writeln!(out, "      local.get $v i64.const 10 i64.rem_u i64.const 48 i64.add i64.store8")?;
+    writeln!(out, "      local.get $v i64.const 10 i64.div_u local.tee $v")?;
+    writeln!(out, "      i64.const 0 i64.ne br_if 0")?;
+    writeln!(out, "    end")?;
+    writeln!(out, "    i32.const {IOV} local.get $p i32.store")?;
+    writeln!(out, "    i32.const {} i32.const {PRINT_BUF_END} local.get $p i32.sub i32.store", IOV + 4)?;
+    writeln!(out, "    i32.const 1 i32.const {IOV} i32.const 1 i32.const {NBYTES} call $fd_write drop)")?;
+
+    // fd, buf, count like read(2) and write(2), errors are returned as -errno
+    for (name, import) in [("$sys_write", "$fd_write"), ("$sys_read", "$fd_read")] {
+        writeln!(out, "  (func {name} (param $fd i64) (param $buf i64) (param $count i64) (result i64) (local $err i32)")?;
+        writeln!(out, "    i32.const {IOV} local.get $buf i32.wrap_i64 i32.store")?;
+        writeln!(out, "    i32.const {} local.get $count i32.wrap_i64 i32.store", IOV + 4)?;
+        writeln!(out, "    local.get $fd i32.wrap_i64 i32.const {IOV} i32.const 1 i32.const {NBYTES} call {import} local.tee $err")?;
+        writeln!(out, "    if (result i64) i64.const 0 local.get $err i64.extend_i32_u i64.sub")?;
+        writeln!(out, "    else i32.const {NBYTES} i64.load32_u end)")?;
+    }
+
+    writeln!(out, "  (func $syscall (param $n i64) (param $a i64) (param $b i64) (param $c i64) (result i64)")?;
+    writeln!(out, "    local.get $n i64.const {SYS_WRITE} i64.eq if")?;
+    writeln!(out, "      local.get $a local.get $b local.get $c call $sys_write return")?;
+    writeln!(out, "    end")?;
+    writeln!(out, "    local.get $n i64.const {SYS_READ} i64.eq if")?;
+    writeln!(out, "      local.get $a local.get $b local.get $c call $sys_read return")?;
+    writeln!(out, "    end")?;
+    writeln!(out, "    local.get $n i64.const {SYS_EXIT} i64.eq local.get $n i64.const {SYS_EXIT_GROUP} i64.eq i32.or if")?;
+    writeln!(out, "      local.get $a i32.wrap_i64 call $proc_exit")?;
+    writeln!(out, "    end")?;
+    writeln!(out, "    i64.const -{ENOSYS})")?;
+
+    // wasi gives 32 bit pointers, they get widened to the 64 bit ones
+    // programs expect
+    writeln!(out, "  (func $widen (param $src i32) (param $dst i32) (param $n i32)")?;
+    writeln!(out, "    block loop")?;
+    writeln!(out, "      local.get $n i32.eqz br_if 1")?;
+    writeln!(out, "      local.get $dst local.get $src i64.load32_u i64.store")?;
+    writeln!(out, "      local.get $src i32.const 4 i32.add local.set $src")?;
+    writeln!(out, "      local.get $dst i32.const 8 i32.add local.set $dst")?;
+    writeln!(out, "      local.get $n i32.const 1 i32.sub local.set $n")?;
+    writeln!(out, "      br 0")?;
+    writeln!(out, "    end end)")?;
+
+    // argc, 8 bytes for every pointer and the two NULLs, 4 bytes for every
+    // pointer wasi writes, then the strings
+    writeln!(out, "  (func $args_init (local $argc i32) (local $argv_sz i32) (local $envc i32) (local $env_sz i32) (local $base i32) (local $argv32 i32) (local $envp32 i32) (local $buf i32)")?;
+    writeln!(out, "    i32.const {IOV} i32.const {} call $args_sizes_get drop", IOV + 4)?;
+    writeln!(out, "    i32.const {IOV} i32.load local.set $argc i32.const {} i32.load local.set $argv_sz", IOV + 4)?;
+    writeln!(out, "    i32.const {IOV} i32.const {} call $environ_sizes_get drop", IOV + 4)?;
+    writeln!(out, "    i32.const {IOV} i32.load local.set $envc i32.const {} i32.load local.set $env_sz", IOV + 4)?;
+    writeln!(out, "    local.get $argc local.get $envc i32.add i32.const 12 i32.mul i32.const 24 i32.add")?;
+    writeln!(out, "    local.get $argv_sz i32.add local.get $env_sz i32.add")?;
+    writeln!(out, "    i32.const {} i32.add i32.const 16 i32.shr_u memory.grow i32.const 16 i32.shl local.tee $base", PAGE_SZ - 1)?;
+    writeln!(out, "    global.set $args")?;
+    writeln!(out, "    local.get $base local.get $argc i64.extend_i32_u i64.store")?;
+    writeln!(out, "    local.get $base local.get $argc local.get $envc i32.add i32.const 8 i32.mul i32.add i32.const 24 i32.add local.tee $argv32")?;
+    writeln!(out, "    local.get $argc i32.const 4 i32.mul i32.add local.tee $envp32")?;
+    writeln!(out, "    local.get $envc i32.const 4 i32.mul i32.add local.set $buf")?;
+    writeln!(out, "    local.get $argv32 local.get $buf call $args_get drop")?;
+    writeln!(out, "    local.get $envp32 local.get $buf local.get $argv_sz i32.add call $environ_get drop")?;
+    writeln!(out, "    local.get $argv32 local.get $base i32.const 8 i32.add local.get $argc call $widen")?;
+    writeln!(out, "    local.get $envp32 local.get $base local.get $argc i32.const 8 i32.mul i32.add i32.const 16 i32.add local.get $envc call $widen)")?;
+
+    out.extend_from_slice(&writer);
+
+    if !args.lib_mode {
+        writeln!(out, "  (func (export \"_start\") call $args_init call {})", mangle("main"))?;
+    }
+    writeln!(out, ")")?;
+
+    fs::write(&of_a, out)?;
+
+    match args.emit {
+        Emit::Asm => return Ok(0),
+        Emit::Obj |
+        Emit::Exe => wasm_assemble(&of_a, &of_c, args.quiet)?,
+        Emit::Llvm | Emit::Qbe => unreachable!()
+    }
+
+    if args.run {
+        let c = wasm_run(&of_c, &args.program_args, args.quiet)?;
+        return Ok(c);
+    }
+
+    Ok(0)
+}
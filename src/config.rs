@@ -20,6 +20,16 @@ pub const MEM_SZ: usize = 640 * 1000; // 4kb
 pub const STRING_SZ: usize = 640 * 1000; // 4kb
 
 
+/**
+ * Compiling configs
+ * `RET_STACK_SZ` is how many calls deep compiled programs can go
+ * `CHECK_FAILED_EXIT` is the exit code of programs built with --checked
+ * when one of the checks fails
+ */
+pub const RET_STACK_SZ: usize = 256;
+pub const CHECK_FAILED_EXIT: i32 = 101;
+
+
 /**
  * Experimental options
  */
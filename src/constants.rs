@@ -7,6 +7,7 @@ pub enum InstructionType {
     // stack
     PushInt,
     PushStr,
+    PushCStr,
     Drop,
     Print,
     Dup,
@@ -48,6 +49,11 @@ pub enum InstructionType {
     Syscall5,
     Syscall6,
 
+    // program arguments
+    Argc,
+    Argv,
+    Envp,
+
     CastBool,
     CastPtr,
     CastInt,
@@ -102,11 +108,13 @@ pub struct Operator{
     pub typ: OpType,
     pub tok_typ: TokenType,
     pub value: usize,
-    pub text: String, //? only used for OpType::PushStr
-    pub addr: Option<usize>, //? only used for OpType::PushStr
+    pub text: String, //? only used for OpType::PushStr and OpType::PushCStr
+    pub addr: Option<usize>, //? only used for OpType::PushStr and OpType::PushCStr
     pub jmp: usize,
     pub loc: Loc,
-    pub types: (usize, usize)
+    pub types: (usize, usize),
+    /// `///` comments before a fn, const or memory definition
+    pub doc: Option<String>
 }
 
 impl Operator {
@@ -119,7 +127,8 @@ impl Operator {
             text,
             loc: (file, row, col),
             tok_typ,
-            types: (0, 0)
+            types: (0, 0),
+            doc: None
         }
     }
     pub fn set_addr(&mut self, addr: usize) -> Self {
@@ -142,6 +151,7 @@ impl OpType {
 
                     InstructionType::PushInt => "Number",
                     InstructionType::PushStr => "String",
+                    InstructionType::PushCStr => "C string",
                     InstructionType::Print => "_dbg_print",
                     InstructionType::Dup => "dup",
                     InstructionType::Drop => "drop",
@@ -175,6 +185,9 @@ impl OpType {
                     InstructionType::Syscall4 => "syscall4",
                     InstructionType::Syscall5 => "syscall5",
                     InstructionType::Syscall6 => "syscall6",
+                    InstructionType::Argc => "argc",
+                    InstructionType::Argv => "argv",
+                    InstructionType::Envp => "envp",
                     InstructionType::CastBool => "cast(bool",
                     InstructionType::CastPtr => "cast(ptr)",
                     InstructionType::CastInt => "cast(int)",
@@ -223,6 +236,9 @@ pub struct Token {
     pub file: String,
     pub line: usize,
     pub col: usize,
+    /// Byte offsets of the start and the end in the source
+    #[allow(dead_code)]
+    pub span: (usize, usize),
     pub text: String,
     pub typ: TokenType,
     pub value: Option<usize>, //* only used for Memories
@@ -235,7 +251,9 @@ pub enum TokenType {
     Word,
     Int,
     String,
-    Char
+    CString,
+    Char,
+    DocComment
 }
 
 impl Token {
@@ -254,7 +272,9 @@ impl TokenType {
             TokenType::Word => "Word",
             TokenType::Int => "Int",
             TokenType::String => "String",
-            TokenType::Char => "Char"
+            TokenType::CString => "C string",
+            TokenType::Char => "Char",
+            TokenType::DocComment => "Doc comment"
         }.to_string()
     }
 }
@@ -0,0 +1,117 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Write as _, fs, path::{self, PathBuf}};
+
+use crate::constants::{Operator, OpType, KeywordType};
+use color_eyre::Result;
+
+/*
+ * Gets called by `execute` before every op and records which ones ran and
+ * which way every `if` and `do` went. `else` and `end` always jump, so they
+ * are covered by their line. Only ops inside functions count as lines since
+ * the rest never runs, and inlined functions count for the lines of their
+ * definition.
+ *
+ * The output is an LCOV file with one record per source file. Functions are
+ * listed by the line of their `fn`, branches by line and column, and every
+ * line gets the count of its most run op.
+ */
+
+pub struct Coverage {
+    out: PathBuf,
+    counts: Vec<u64>,
+    /// How often the condition of an `if` or `do` was true and false, by index
+    branches: HashMap<usize, [u64; 2]>,
+    in_function: Vec<bool>,
+}
+
+#[derive(Default)]
+struct FileCoverage<'a> {
+    /// Name to line and calls
+    functions: BTreeMap<&'a str, (usize, u64)>,
+    /// Line and column to true and false counts, None if never reached
+    branches: BTreeMap<(usize, usize), Option<[u64; 2]>>,
+    lines: BTreeMap<usize, u64>,
+}
+
+impl Coverage {
+    /// Record coverage of `ops`, written to `out` as LCOV
+    pub fn new(ops: &[Operator], out: &str) -> Self {
+        let mut inside = false;
+        let in_function = ops.iter().map(|op| {
+            match op.typ {
+                OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => inside = true,
+                OpType::Keyword(KeywordType::FunctionDone) => return std::mem::replace(&mut inside, false),
+                _ => (),
+            }
+            inside
+        }).collect();
+
+        let branches = ops.iter().enumerate()
+            .filter(|(_, op)| matches!(op.typ, OpType::Keyword(KeywordType::If | KeywordType::Do)))
+            .map(|(ip, _)| (ip, [0; 2]))
+            .collect();
+
+        Self {
+            out: PathBuf::from(out),
+            counts: vec![0; ops.len()],
+            branches,
+            in_function,
+        }
+    }
+
+    /// Called before `ops[ip]` runs
+    pub fn before(&mut self, ip: usize, stack: &[usize]) {
+        self.counts[ip] += 1;
+        if let Some(taken) = self.branches.get_mut(&ip) {
+            taken[usize::from(stack.last() == Some(&0))] += 1;
+        }
+    }
+
+    /// Write the LCOV file
+    pub fn write(&self, ops: &[Operator]) -> Result<()> {
+        let mut files = BTreeMap::<&str, FileCoverage>::new();
+        for (ip, op) in ops.iter().enumerate().filter(|(ip, _)| self.in_function[*ip]) {
+            let file = files.entry(op.loc.0.as_str()).or_default();
+            let n = self.counts[ip];
+            let line = file.lines.entry(op.loc.1).or_default();
+            *line = (*line).max(n);
+            if let OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) = op.typ {
+                file.functions.insert(op.text.as_str(), (op.loc.1, n));
+            }
+            if let Some(taken) = self.branches.get(&ip) {
+                file.branches.insert((op.loc.1, op.loc.2), (n > 0).then_some(*taken));
+            }
+        }
+
+        let mut s = String::from("TN:\n");
+        for (name, file) in files {
+            let name = path::absolute(name).map_or_else(|_| name.to_string(), |p| p.display().to_string());
+            let _ = writeln!(s, "SF:{name}");
+            for (f, (line, _)) in &file.functions {
+                let _ = writeln!(s, "FN:{line},{f}");
+            }
+            for (f, (_, n)) in &file.functions {
+                let _ = writeln!(s, "FNDA:{n},{f}");
+            }
+            let _ = writeln!(s, "FNF:{}", file.functions.len());
+            let _ = writeln!(s, "FNH:{}", file.functions.values().filter(|(_, n)| *n > 0).count());
+            let mut hit = 0;
+            for ((line, col), taken) in &file.branches {
+                for (branch, n) in [0, 1].into_iter().zip(taken.map_or([None; 2], |t| t.map(Some))) {
+                    hit += usize::from(n.is_some_and(|n| n > 0));
+                    let n = n.map_or_else(|| String::from("-"), |n| n.to_string());
+                    let _ = writeln!(s, "BRDA:{line},{col},{branch},{n}");
+                }
+            }
+            let _ = writeln!(s, "BRF:{}", file.branches.len() * 2);
+            let _ = writeln!(s, "BRH:{hit}");
+            for (line, n) in &file.lines {
+                let _ = writeln!(s, "DA:{line},{n}");
+            }
+            let _ = writeln!(s, "LF:{}", file.lines.len());
+            let _ = writeln!(s, "LH:{}", file.lines.values().filter(|n| **n > 0).count());
+            let _ = writeln!(s, "end_of_record");
+        }
+        fs::write(&self.out, s)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::{fs, io::{self, BufRead, Write}};
+
+use crate::constants::{Operator, OpType, InstructionType, KeywordType};
+use color_eyre::Result;
+
+use super::Defineds;
+
+/*
+ * Gets called by `execute` before every op. Breakpoints on a line stop when
+ * execution enters that line, breakpoints on a function stop at its first op.
+ * Commands come from stdin, or from a script file where every line is a
+ * command. Once the script runs out the program runs to the end.
+ */
+
+const HELP: &str = "\
+commands:
+  break <file:line|function>  (b)  set a breakpoint
+  delete <n>                  (d)  remove breakpoint n
+  step                        (s)  run one operator
+  next                        (n)  run one operator, stepping over function calls
+  continue                    (c)  run until the next breakpoint
+  finish                      (f)  run until the current function returns
+  stack                       (p)  print the data stack
+  rstack                      (bt) print the return stack
+  mem <name>                  (x)  hex dump a memory block
+  where                       (w)  print the current location
+  quit                        (q)  stop the program";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Breakpoint {
+    Line(String, usize),
+    Function(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Step,
+    /// Stop at the next op with a return stack at most this deep
+    Next(usize),
+    /// Stop once the return stack is less deep than this
+    Finish(usize),
+    Continue,
+    /// No more stops, not even on breakpoints
+    Run,
+}
+
+pub struct Debugger {
+    /// Deleted breakpoints stay as None so the numbers do not change
+    breakpoints: Vec<Option<Breakpoint>>,
+    mode: Mode,
+    /// Remaining script lines, reversed
+    script: Option<Vec<String>>,
+    last_line: Option<(String, usize)>,
+}
+
+impl Debugger {
+    /// Read commands from `script` if given, from stdin otherwise
+    pub fn new(script: Option<&str>) -> Result<Self> {
+        let script = match script {
+            Some(path) => {
+                let mut lines = fs::read_to_string(path)?
+                    .lines()
+                    .map(|l| l.trim().to_string())
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .collect::<Vec<_>>();
+                lines.reverse();
+                Some(lines)
+            }
+            None => None,
+        };
+        Ok(Self {
+            breakpoints: Vec::new(),
+            mode: Mode::Step,
+            script,
+            last_line: None,
+        })
+    }
+
+    /// Called before `ops[ip]` runs, returns false if the program should stop
+    pub fn before(&mut self, ops: &[Operator], ip: usize, stack: &[usize], ret_stack: &[usize], mem: &[u8], defineds: &Defineds) -> Result<bool> {
+        let op = &ops[ip];
+        if is_signature(op) {
+            return Ok(true);
+        }
+        let line = (op.loc.0.clone(), op.loc.1);
+        let depth = ret_stack.len();
+
+        let entered_line = self.last_line.as_ref() != Some(&line);
+        self.last_line = Some(line.clone());
+
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(d) => depth <= d,
+            Mode::Finish(d) => depth < d,
+            Mode::Continue => false,
+            Mode::Run => return Ok(true),
+        };
+        let hit = self.breakpoints.iter().position(|b| match b {
+            Some(Breakpoint::Line(file, l)) => entered_line && *l == line.1 && same_file(&line.0, file),
+            Some(Breakpoint::Function(name)) => defineds.functions.get(name).is_some_and(|f| f.id == ip),
+            None => false,
+        });
+        if !stop && hit.is_none() {
+            return Ok(true);
+        }
+
+        if let Some(n) = hit {
+            println!("breakpoint {n} hit");
+        }
+        print_location(op, function_at(ip, defineds));
+
+        loop {
+            let Some(cmd) = self.read_command()? else {
+                self.mode = Mode::Run;
+                return Ok(true);
+            };
+            let (cmd, arg) = cmd.split_once(' ').map_or((cmd.as_str(), ""), |(c, a)| (c, a.trim()));
+            match cmd {
+                "break" | "b" => self.add_breakpoint(arg, ops, defineds),
+                "delete" | "d" => {
+                    match arg.parse::<usize>() {
+                        Ok(n) if self.breakpoints.get(n).is_some_and(Option::is_some) => {
+                            self.breakpoints[n] = None;
+                        }
+                        _ => println!("no breakpoint '{arg}'"),
+                    }
+                }
+                "step" | "s" => {
+                    self.mode = Mode::Step;
+                    return Ok(true);
+                }
+                "next" | "n" => {
+                    self.mode = Mode::Next(depth);
+                    return Ok(true);
+                }
+                "continue" | "c" => {
+                    self.mode = Mode::Continue;
+                    return Ok(true);
+                }
+                "finish" | "f" => {
+                    self.mode = Mode::Finish(depth);
+                    return Ok(true);
+                }
+                "stack" | "p" => {
+                    let s = stack.iter().map(ToString::to_string).collect::<Vec<_>>();
+                    println!("[{}]", s.join(", "));
+                }
+                "rstack" | "bt" => {
+                    println!("#0 {} at {}", function_at(ip, defineds), fmt_loc(op));
+                    for (i, call) in ret_stack.iter().rev().enumerate() {
+                        println!("#{} {} at {}", i + 1, function_at(*call, defineds), fmt_loc(&ops[*call]));
+                    }
+                }
+                "mem" | "x" => {
+                    match defineds.memories.values().find(|m| m.name == arg) {
+                        Some(m) => hex_dump(m.addr, &mem[m.addr..m.addr + m.size]),
+                        None => println!("no memory named '{arg}'"),
+                    }
+                }
+                "where" | "w" => print_location(op, function_at(ip, defineds)),
+                "quit" | "q" => return Ok(false),
+                "help" | "h" => println!("{HELP}"),
+                _ => println!("unknown command '{cmd}', try 'help'"),
+            }
+        }
+    }
+
+    fn read_command(&mut self) -> Result<Option<String>> {
+        if let Some(script) = &mut self.script {
+            let cmd = script.pop();
+            if let Some(cmd) = &cmd {
+                println!("(mdb) {cmd}");
+            }
+            return Ok(cmd);
+        }
+
+        print!("(mdb) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(None);
+        }
+        Ok(Some(line.trim().to_string()))
+    }
+
+    fn add_breakpoint(&mut self, arg: &str, ops: &[Operator], defineds: &Defineds) {
+        let bp = match arg.rsplit_once(':').map(|(f, l)| (f, l.parse::<usize>())) {
+            Some((file, Ok(line))) => {
+                if !ops.iter().any(|op| op.loc.1 == line && same_file(&op.loc.0, file)) {
+                    println!("no code at {arg}");
+                    return;
+                }
+                Breakpoint::Line(file.to_string(), line)
+            }
+            _ => {
+                if !defineds.functions.contains_key(arg) {
+                    println!("no function named '{arg}'");
+                    return;
+                }
+                Breakpoint::Function(arg.to_string())
+            }
+        };
+        println!("breakpoint {} at {arg}", self.breakpoints.len());
+        self.breakpoints.push(Some(bp));
+    }
+}
+
+/// The types after a function definition, nothing happens there
+fn is_signature(op: &Operator) -> bool {
+    matches!(op.typ,
+        OpType::Instruction(
+            InstructionType::With |
+            InstructionType::Returns |
+            InstructionType::TypeBool |
+            InstructionType::TypePtr |
+            InstructionType::TypeInt |
+            InstructionType::TypeVoid |
+            InstructionType::TypeAny
+        ) |
+        OpType::Keyword(KeywordType::FunctionThen)
+    )
+}
+
+/// `path` names the same file as `file` or ends with it
+fn same_file(path: &str, file: &str) -> bool {
+    let path = path.trim_start_matches("./");
+    let file = file.trim_start_matches("./");
+    path == file || path.ends_with(&format!("/{file}"))
+}
+
+/// Name of the function the op at `ip` belongs to
+fn function_at(ip: usize, defineds: &Defineds) -> &str {
+    defineds.functions.values()
+        .filter(|f| f.id <= ip)
+        .max_by_key(|f| f.id)
+        .map_or("<top level>", |f| f.name.as_str())
+}
+
+fn fmt_loc(op: &Operator) -> String {
+    format!("{}:{}:{}", op.loc.0, op.loc.1, op.loc.2)
+}
+
+/// What `op` does, the way it was written
+pub fn describe(op: &Operator) -> String {
+    match op.typ {
+        OpType::Instruction(InstructionType::PushInt) => op.value.to_string(),
+        OpType::Instruction(InstructionType::PushStr) => format!("\"{}\"", op.text.escape_debug()),
+        OpType::Instruction(InstructionType::PushCStr) => format!("\"{}\"c", op.text.escape_debug()),
+        OpType::Instruction(InstructionType::FnCall | InstructionType::MemUse | InstructionType::ConstUse) => op.text.clone(),
+        OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => format!("fn {}", op.text),
+        _ => op.typ.human(),
+    }
+}
+
+fn print_location(op: &Operator, function: &str) {
+    println!("{} in {function}: {}", fmt_loc(op), describe(op));
+}
+
+fn hex_dump(addr: usize, bytes: &[u8]) {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex = row.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(" ");
+        let ascii = row.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect::<String>();
+        println!("{:08x}  {hex:<47}  |{ascii}|", addr + i * 16);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, os::unix::ffi::OsStringExt};
 
 use crate::{constants::{OpType, Loc, InstructionType, KeywordType, Operator}, lerror, error};
 // use crate::util::logger;
@@ -6,7 +6,15 @@ use color_eyre::Result;
 use eyre::eyre;
 
 use super::{Memory, Function, Constant};
+use coverage::Coverage;
+use debugger::Debugger;
+use profiler::Profiler;
+use vfs::Vfs;
 mod syscalls;
+pub mod coverage;
+pub mod debugger;
+pub mod profiler;
+pub mod vfs;
 
 fn stack_pop(stack: &mut Vec<usize>, pos: &Loc) -> Result<usize> {
     if let Some(i) = stack.pop() { Ok(i) } else {
@@ -15,29 +23,184 @@ fn stack_pop(stack: &mut Vec<usize>, pos: &Loc) -> Result<usize> {
     }
 }
 
-pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
-    let mut stack: Vec<usize> = Vec::new();
-    let mut mem: Vec<u64> = vec![0; crate::MEM_SZ + crate::STRING_SZ];
-    let mut string_idx = 0;
-    
-    let prerunned = pre_run(ops);
-    let functions = prerunned.functions;
-    let constants = prerunned.constants;
-    let memories = prerunned.memories;
+/// Limits for running programs that can not be trusted and fakes that make
+/// their output the same every run, all off by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Operators executed by one call to `execute`
+    pub steps: Option<u64>,
+    /// Values on the data stack, and calls on the return stack
+    pub stack: Option<usize>,
+    /// Bytes the program can get with brk and mmap
+    pub memory: Option<usize>,
+    /// getpid always returns the same pid and every clock_gettime moves the
+    /// clocks forward by the same amount, starting at 0
+    pub deterministic: bool,
+}
+
+/// Everything the program changes while running, the repl keeps it around
+/// between lines
+pub struct State {
+    pub stack: Vec<usize>,
+    pub ret_stack: Vec<usize>,
+    /// The address space, memory blocks in the first `MEM_SZ` bytes (after
+    /// `MEM_START`), string literals after them and then the heap
+    mem: Vec<u8>,
+    string_idx: usize,
+    sys: syscalls::Sys,
+    /// Where argc is, followed by the argv and envp pointers
+    args: usize,
+    limits: Limits,
+}
+
+impl State {
+    /// `argv` is what the program gets as its arguments, including its name,
+    /// the environment is the one of the interpreter
+    pub fn new(sandbox: Option<Vfs>, argv: &[String], limits: Limits) -> Self {
+        let mut mem = vec![0; crate::MEM_SZ + crate::STRING_SZ];
+        let mut sys = syscalls::Sys::new(mem.len(), sandbox, &limits);
+        let env = std::env::vars_os().map(|(k, v)| {
+            let mut s = k.into_vec();
+            s.push(b'=');
+            s.extend(v.into_vec());
+            s
+        }).collect::<Vec<_>>();
+        let argv = argv.iter().map(String::as_bytes).collect::<Vec<_>>();
+        let envp = env.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let args = sys.place_args(&argv, &envp, &mut mem);
+        Self {
+            stack: Vec::new(),
+            ret_stack: Vec::new(),
+            mem,
+            string_idx: 0,
+            sys,
+            args,
+            limits,
+        }
+    }
+
+    /// Write the sandbox to its dump directory, if it has both
+    pub fn dump_sandbox(&self) -> Result<()> {
+        if let Some(sandbox) = self.sys.sandbox() {
+            if let Err(e) = sandbox.dump() {
+                error!("Could not dump the sandbox: {e}");
+                return Err(eyre!(""));
+            }
+        }
+        Ok(())
+    }
+
+    /// Give every string literal without an address one and copy it there,
+    /// one after another in program order like the compiled data section,
+    /// C strings get their NUL
+    pub fn place_strings(&mut self, ops: &mut [Operator]) -> Result<()> {
+        let strings = ops.iter_mut().filter(|op| matches!(op.typ, OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr)) && op.addr.is_none());
+        for op in strings {
+            let addr = crate::MEM_SZ + self.string_idx;
+            let len = op.text.len() + usize::from(op.typ == OpType::Instruction(InstructionType::PushCStr));
+            if addr + len > self.mem.len() {
+                lerror!(&op.loc, "Out of string memory, the strings can take up at most {} bytes", crate::STRING_SZ);
+                return Err(eyre!(""));
+            }
+            self.mem[addr..addr + op.text.len()].copy_from_slice(op.text.as_bytes());
+            self.mem[addr + op.text.len()..addr + len].fill(0);
+            self.string_idx += len;
+            op.addr = Some(addr);
+        }
+        Ok(())
+    }
+}
+
+/// Nothing lives below this address, so null pointers get caught
+const MEM_START: usize = 0x1000;
+
+/// The `width` bytes at `addr`, None if they are not all inside one memory
+/// block or the string literals
+fn mem_range(addr: usize, width: usize, len: usize, defineds: &Defineds) -> Option<std::ops::Range<usize>> {
+    let end = addr.checked_add(width)?;
+    let in_block = defineds.memories.values().any(|m| addr >= m.addr && end <= m.addr + m.size);
+    let in_strings = addr >= crate::MEM_SZ && end <= len;
+    (in_block || in_strings).then_some(addr..end)
+}
+
+/// Why `width` bytes at `addr` can not be accessed
+fn describe_addr(addr: usize, width: usize, defineds: &Defineds) -> String {
+    if addr < MEM_START {
+        return format!("address {addr} is a null pointer");
+    }
+    let before = defineds.memories.values()
+        .filter(|m| m.addr <= addr)
+        .max_by_key(|m| m.addr);
+    match before {
+        Some(m) if addr < m.addr + m.size => {
+            format!("{width} bytes at {addr} run past the end of memory '{}' ({} bytes at {})", m.name, m.size, m.addr)
+        }
+        Some(m) if addr < crate::MEM_SZ => {
+            format!("address {addr} is {} bytes past the end of memory '{}' ({} bytes at {})", addr - m.addr - m.size, m.name, m.size, m.addr)
+        }
+        _ => format!("address {addr} is not in any memory block")
+    }
+}
+
+pub fn run(ops: &[crate::constants::Operator], mut debugger: Option<Debugger>, mut profiler: Option<Profiler>, mut coverage: Option<Coverage>, sandbox: Option<Vfs>, argv: &[String], limits: Limits) -> Result<i32>{
+    let prerunned = pre_run(ops)?;
 
-    let mut ret_stack: Vec<usize> = Vec::new();
-    
     // for token in &tokens {
     //     println!("{{typ: \"{:?}\", val: {}, jmp: {}}}", token.typ, token.value, token.jmp);
     // }
         
     // jump to main func    
-    let mut ip = if let Some(i) = functions.get("main") {i.id} else {
+    let ip = if let Some(i) = prerunned.functions.get("main") {i.id} else {
         crate::errors::missing_main_fn();
         return Err(eyre!(""));
     };
-    
+
+    let mut state = State::new(sandbox, argv, limits);
+    let mut ops = ops.to_vec();
+    state.place_strings(&mut ops)?;
+    let ret = execute(&ops, ip, &mut state, &prerunned, debugger.as_mut(), profiler.as_mut(), coverage.as_mut());
+    state.dump_sandbox()?;
+    if let Some(p) = profiler {
+        if let Err(e) = p.write(&ops) {
+            error!("Could not write the profile: {e}");
+            return Err(eyre!(""));
+        }
+    }
+    if let Some(c) = coverage {
+        if let Err(e) = c.write(&ops) {
+            error!("Could not write the coverage: {e}");
+            return Err(eyre!(""));
+        }
+    }
+    ret
+}
+
+/// Run `ops` from `ip` until the end of the program or until the function
+/// `ip` is in returns
+pub fn execute(ops: &[Operator], mut ip: usize, state: &mut State, defineds: &Defineds, mut debugger: Option<&mut Debugger>, mut profiler: Option<&mut Profiler>, mut coverage: Option<&mut Coverage>) -> Result<i32> {
+    let State { stack, ret_stack, mem, sys, args, limits, .. } = state;
+    let functions = &defineds.functions;
+    let constants = &defineds.constants;
+    let memories = &defineds.memories;
+
+    let mut steps = 0;
     while ip < ops.len() {
+        if limits.steps.is_some_and(|max| steps >= max) {
+            lerror!(&ops[ip].loc, "Step limit reached, the program ran {steps} operators");
+            return Err(eyre!(""));
+        }
+        steps += 1;
+        if let Some(p) = profiler.as_deref_mut() {
+            p.before(ip, ret_stack);
+        }
+        if let Some(c) = coverage.as_deref_mut() {
+            c.before(ip, stack);
+        }
+        if let Some(d) = debugger.as_deref_mut() {
+            if !d.before(ops, ip, stack, ret_stack, mem, defineds)? {
+                return Ok(0);
+            }
+        }
         let op = &ops[ip];
         let pos = op.loc.clone();
         match op.typ.clone() {
@@ -48,22 +211,12 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                         ip += 1;
                     },
                     InstructionType::PushStr => {
-                        if  op.addr.is_none() {
-                            stack.push(op.text.len()); // string len
-                            stack.push(string_idx + crate::MEM_SZ);
-                            
-                            for c in op.text.bytes() {
-                                mem[crate::MEM_SZ + string_idx] = u64::from(c);
-                                string_idx += 1;
-                            }
-                        } else {
-                            stack.push(op.text.len()); 
-                            if let Some(addr) = op.addr {
-                                stack.push(addr);
-                            }
-                        }
-        
-        
+                        stack.push(op.text.len());
+                        stack.push(op.addr.expect("strings are placed before running"));
+                        ip += 1;
+                    },
+                    InstructionType::PushCStr => {
+                        stack.push(op.addr.expect("strings are placed before running"));
                         ip += 1;
                     },
                     InstructionType::Drop => {
@@ -71,31 +224,31 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                         ip += 1;
                     },
                     InstructionType::Dup => {
-                        let a = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
                         stack.push(a);
                         stack.push(a);
                         ip += 1;
                     },
         
                     InstructionType::Rot => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        let c = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        let c = stack_pop(stack, &pos)?;
                         stack.push(b);
                         stack.push(a);
                         stack.push(c);
                         ip += 1;
                     }
                     InstructionType::Swap => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
                         stack.push(a);
                         stack.push(b);
                         ip += 1;
                     }
                     InstructionType::Over => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
                         stack.push(b);
                         stack.push(a);
                         stack.push(b);
@@ -103,7 +256,7 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                     }
         
                     InstructionType::Print => {
-                        let a = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
                         println!("{a}");
                         // let _ = io::stdout().flush();
                         ip += 1;
@@ -112,193 +265,183 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                     InstructionType::Load8 |
                     InstructionType::Load32 |
                     InstructionType::Load64 => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        if a > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {a}");
-                            return Ok(1);
-                        }
-                        let byte = mem[a];
-                        stack.push(byte as usize);
-                        ip += 1;
-                    }
-                    #[allow(clippy::cast_possible_truncation)]
-                    InstructionType::Store8 => {
-                        let val = stack_pop(&mut stack, &pos)?;
-                        let addr = stack_pop(&mut stack, &pos)?;
-                        
-                        if addr > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {addr}");
-                            return Ok(1);
-                        }
-
-                        mem[addr] = u64::from(val as u8);
-                        ip += 1;
-                    }
-                    #[allow(clippy::cast_possible_truncation)]
-                    InstructionType::Store32 => {
-                        let val = stack_pop(&mut stack, &pos)?;
-                        let addr = stack_pop(&mut stack, &pos)?;
-                        
-                        if addr > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {addr}");
+                        let addr = stack_pop(stack, &pos)?;
+                        let width = match instruction {
+                            InstructionType::Load8 => 1,
+                            InstructionType::Load32 => 4,
+                            _ => 8
+                        };
+                        let Some(r) = mem_range(addr, width, mem.len(), defineds) else {
+                            lerror!(&op.loc, "Invalid memory access, {}", describe_addr(addr, width, defineds));
                             return Ok(1);
-                        }
-
-                        mem[addr] = u64::from(val as u32);
+                        };
+                        let mut bytes = [0; 8];
+                        bytes[..width].copy_from_slice(&mem[r]);
+                        stack.push(u64::from_le_bytes(bytes) as usize);
                         ip += 1;
                     }
-
-                    #[allow(clippy::cast_possible_truncation)]
+                    InstructionType::Store8 |
+                    InstructionType::Store32 |
                     InstructionType::Store64 => {
-                        let val = stack_pop(&mut stack, &pos)?;
-                        let addr = stack_pop(&mut stack, &pos)?;
-                        
-                        if addr > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {addr}");
+                        let val = stack_pop(stack, &pos)?;
+                        let addr = stack_pop(stack, &pos)?;
+                        let width = match instruction {
+                            InstructionType::Store8 => 1,
+                            InstructionType::Store32 => 4,
+                            _ => 8
+                        };
+                        let Some(r) = mem_range(addr, width, mem.len(), defineds) else {
+                            lerror!(&op.loc, "Invalid memory access, {}", describe_addr(addr, width, defineds));
                             return Ok(1);
-                        }
-
-                        mem[addr] = val as u64;
+                        };
+                        mem[r].copy_from_slice(&val.to_le_bytes()[..width]);
                         ip += 1;
                     }
         
                     // math
                     InstructionType::Plus => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b + a);
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(b.wrapping_add(a));
                         ip += 1;
                     },
                     InstructionType::Minus => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b - a);
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(b.wrapping_sub(a));
                         ip += 1;
                     },
                     InstructionType::Equals => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
                         stack.push(usize::from(b == a));
                         ip += 1;
                     },
                     InstructionType::Gt => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b > a));
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(usize::from((b as i64) > (a as i64)));
                         ip += 1;
                     },
                     InstructionType::Lt => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b < a));
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(usize::from((b as i64) < (a as i64)));
                         ip += 1;
                     },
                     InstructionType::NotEquals => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
                         stack.push(usize::from(b != a));
                         ip += 1;
                     },
                     InstructionType::Ge => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b >= a));
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(usize::from((b as i64) >= (a as i64)));
                         ip += 1;
                     },
                     InstructionType::Le => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b <= a));
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(usize::from((b as i64) <= (a as i64)));
                         ip += 1;
                     },
         
                     InstructionType::Band => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
                         stack.push(a & b);
                         ip += 1;
                     }
         
                     InstructionType::Bor => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
                         stack.push(a | b);
                         ip += 1;
                     }
         
                     InstructionType::Shr => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b >> a);
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(b.wrapping_shr(a as u32));
                         ip += 1;
                     }
         
                     InstructionType::Shl => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b << a);
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(b.wrapping_shl(a as u32));
                         ip += 1;
                     }
                     
                     InstructionType::DivMod => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        if a == 0 {
+                            lerror!(&pos, "Division by zero");
+                            return Err(eyre!(""));
+                        }
                         stack.push(b / a);
                         stack.push(b % a);
                         ip += 1;
                     }
                     InstructionType::Mul => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b * a);
+                        let a = stack_pop(stack, &pos)?;
+                        let b = stack_pop(stack, &pos)?;
+                        stack.push(b.wrapping_mul(a));
                         ip += 1;
                     }
-                    InstructionType::Syscall0 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall1 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall2 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall3 => {
-                        let rax = stack_pop(&mut stack, &pos)?;
-                        let rdi = stack_pop(&mut stack, &pos)?;
-                        let rsi = stack_pop(&mut stack, &pos)?;
-                        let rdx = stack_pop(&mut stack, &pos)?;
-                        // println!("yes");
-                        let ret = match rax {
-                            1 => syscalls::sys_write(rax, rdi, rsi, rdx, &mem),
-                            0 => 0, //? temp, so clippy doesnt complain
-                            _ => {
-                                error!("Syscall(3) #{} is not implemented", rax);
-                                return Err(eyre!("Syscall not implemented"));
-                            }
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let argc = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
                         };
-                        stack.push(ret);
-                        // println!("{}", stack.len());
+                        // the syscall number is on top, followed by the first argument
+                        let n = stack_pop(stack, &pos)?;
+                        let mut sys_args = [0; 6];
+                        for arg in sys_args.iter_mut().take(argc) {
+                            *arg = stack_pop(stack, &pos)?;
+                        }
+                        match sys.call(n, sys_args, mem, defineds) {
+                            syscalls::Ret::Value(r) => stack.push(r),
+                            syscalls::Ret::Exit(code) => return Ok(code),
+                            syscalls::Ret::MemoryLimit(wanted) => {
+                                lerror!(&pos, "Memory limit of {} bytes reached, the program tried to allocate {wanted} bytes in total", limits.memory.unwrap_or_default());
+                                return Err(eyre!(""));
+                            }
+                        }
                         ip += 1;
                     },
-                    InstructionType::Syscall4 => {
-                        todo!();
-                        // ti += 1;
+                    InstructionType::Argc => {
+                        stack.push(usize::from_le_bytes(mem[*args..*args + 8].try_into().unwrap()));
+                        ip += 1;
                     },
-                    InstructionType::Syscall5 => {
-                        todo!();
-                        // ti += 1;
+                    InstructionType::Argv => {
+                        stack.push(*args + 8);
+                        ip += 1;
                     },
-                    InstructionType::Syscall6 => {
-                        todo!();
-                        // ti += 1;
+                    InstructionType::Envp => {
+                        let argc = usize::from_le_bytes(mem[*args..*args + 8].try_into().unwrap());
+                        stack.push(*args + 8 * (argc + 2));
+                        ip += 1;
                     },
                     InstructionType::MemUse => {
 
                         let m = memories.get(&op.addr.unwrap()).unwrap();
-                        stack.push(m.id);
+                        stack.push(m.addr);
                         ip += 1;
                     },
                     InstructionType::FnCall => {
@@ -339,7 +482,7 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                 match k {
                     // blocks
                     KeywordType::If => {
-                        let a = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(stack, &pos)?;
                         if a == 0 {
                             // println!("If({ti}) => t: {:?} j: {}", tokens[token.jmp as usize].typ, token.jmp);
                             ip = op.jmp;
@@ -385,6 +528,17 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
             }
             
         }
+
+        if let Some(max) = limits.stack {
+            if stack.len() > max {
+                lerror!(&pos, "Stack limit of {max} values reached");
+                return Err(eyre!(""));
+            }
+            if ret_stack.len() > max {
+                lerror!(&pos, "Return stack limit of {max} calls reached");
+                return Err(eyre!(""));
+            }
+        }
     }
     
 
@@ -397,7 +551,7 @@ pub struct Defineds {
     pub constants: HashMap<String, Constant>
 }
 
-pub fn pre_run(ops: &[Operator]) -> Defineds {
+pub fn pre_run(ops: &[Operator]) -> Result<Defineds> {
     let mut defineds = Defineds{
         memories: HashMap::new(),
         functions: HashMap::new(),
@@ -407,7 +561,7 @@ pub fn pre_run(ops: &[Operator]) -> Defineds {
 
         match op.typ {
             OpType::Keyword(KeywordType::Memory) => {
-                defineds.memories.insert(op.addr.unwrap(), Memory { size: op.value, loc: op.loc.clone(), id: op.addr.unwrap() });
+                defineds.memories.insert(op.addr.unwrap(), Memory { name: op.text.clone(), size: op.value, loc: op.loc.clone(), id: op.addr.unwrap(), addr: 0 });
             },
             OpType::Keyword(KeywordType::FunctionDefExported) => {
 
@@ -421,5 +575,19 @@ pub fn pre_run(ops: &[Operator]) -> Defineds {
             _ => ()   
         }
     }
-    defineds
+
+    // one after another in the order they were defined, like the compiled bss
+    let mut ids = defineds.memories.keys().copied().collect::<Vec<_>>();
+    ids.sort_unstable();
+    let mut addr = MEM_START;
+    for id in ids {
+        let m = defineds.memories.get_mut(&id).unwrap();
+        m.addr = addr;
+        addr = (addr + m.size).next_multiple_of(8);
+        if addr > crate::MEM_SZ {
+            lerror!(&m.loc, "Memory '{}' does not fit, all memory blocks together can take up at most {} bytes", m.name, crate::MEM_SZ - MEM_START);
+            return Err(eyre!(""));
+        }
+    }
+    Ok(defineds)
 }
\ No newline at end of file
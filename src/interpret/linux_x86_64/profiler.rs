@@ -0,0 +1,179 @@
+use std::{collections::{HashMap, HashSet}, fmt::Write as _, fs, path::PathBuf};
+
+use crate::constants::{Operator, OpType, KeywordType};
+use color_eyre::Result;
+
+use super::debugger::describe;
+
+/*
+ * Gets called by `execute` before every op and counts how often each one
+ * runs. The call stack is followed with the depth of the return stack, every
+ * distinct stack of functions is a node in a tree so counting an op only
+ * costs a lookup when the depth changes.
+ * The report has the counts by function, by source line and the hottest ops,
+ * the `.folded` file has one `main;caller;callee count` line per stack for
+ * flamegraph tools.
+ */
+
+/// How many ops the report lists
+const HOT_OPS: usize = 20;
+
+pub struct Profiler {
+    out: PathBuf,
+    /// How often every op ran, by index
+    counts: Vec<u64>,
+    /// The function every op is in, an index into `names`
+    function_of: Vec<Option<usize>>,
+    names: Vec<String>,
+    /// Call stack tree, every node is a parent node and a function
+    nodes: Vec<(Option<usize>, Option<usize>)>,
+    children: HashMap<(Option<usize>, Option<usize>), usize>,
+    /// Ops run with exactly this stack
+    node_counts: Vec<u64>,
+    /// The current path through the tree, one node per stack frame
+    frames: Vec<usize>,
+}
+
+impl Profiler {
+    /// Profile `ops`, the report goes to `out` and the collapsed stacks to
+    /// `out` with `.folded` added
+    pub fn new(ops: &[Operator], out: &str) -> Self {
+        let mut names = Vec::new();
+        let mut current = None;
+        let function_of = ops.iter().map(|op| {
+            match op.typ {
+                OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => {
+                    names.push(op.text.clone());
+                    current = Some(names.len() - 1);
+                    current
+                }
+                OpType::Keyword(KeywordType::FunctionDone) => current.take(),
+                _ => current,
+            }
+        }).collect();
+
+        Self {
+            out: PathBuf::from(out),
+            counts: vec![0; ops.len()],
+            function_of,
+            names,
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            node_counts: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Called before `ops[ip]` runs
+    pub fn before(&mut self, ip: usize, ret_stack: &[usize]) {
+        self.counts[ip] += 1;
+
+        let depth = ret_stack.len() + 1;
+        self.frames.truncate(depth);
+        while self.frames.len() < depth {
+            let parent = self.frames.last().copied();
+            let key = (parent, self.function_of[ip]);
+            let node = *self.children.entry(key).or_insert_with(|| {
+                self.nodes.push(key);
+                self.node_counts.push(0);
+                self.nodes.len() - 1
+            });
+            self.frames.push(node);
+        }
+        self.node_counts[*self.frames.last().unwrap()] += 1;
+    }
+
+    /// Write the report and the collapsed stacks
+    pub fn write(&self, ops: &[Operator]) -> Result<()> {
+        fs::write(&self.out, self.report(ops))?;
+        let mut folded = self.out.clone().into_os_string();
+        folded.push(".folded");
+        fs::write(folded, self.folded())?;
+        Ok(())
+    }
+
+    fn name(&self, function: Option<usize>) -> &str {
+        function.map_or("<top level>", |f| self.names[f].as_str())
+    }
+
+    /// The functions on the stack of `node`, outermost first
+    fn stack(&self, mut node: usize) -> Vec<Option<usize>> {
+        let mut stack = Vec::new();
+        loop {
+            let (parent, function) = self.nodes[node];
+            stack.push(function);
+            match parent {
+                Some(p) => node = p,
+                None => break,
+            }
+        }
+        stack.reverse();
+        stack
+    }
+
+    fn folded(&self) -> String {
+        let mut lines = (0..self.nodes.len())
+            .filter(|n| self.node_counts[*n] > 0)
+            .map(|n| {
+                let stack = self.stack(n).into_iter().map(|f| self.name(f)).collect::<Vec<_>>();
+                format!("{} {}\n", stack.join(";"), self.node_counts[n])
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.concat()
+    }
+
+    fn report(&self, ops: &[Operator]) -> String {
+        let total = self.counts.iter().sum::<u64>();
+        let percent = |n: u64| if total == 0 { 0.0 } else { n as f64 * 100.0 / total as f64 };
+
+        // self counts by the function an op is in, total counts by every
+        // function on the stack, recursion only counts once
+        let mut own = HashMap::<Option<usize>, u64>::new();
+        for (ip, n) in self.counts.iter().enumerate() {
+            *own.entry(self.function_of[ip]).or_default() += n;
+        }
+        let mut inclusive = HashMap::<Option<usize>, u64>::new();
+        for (node, n) in self.node_counts.iter().enumerate() {
+            for f in self.stack(node).into_iter().collect::<HashSet<_>>() {
+                *inclusive.entry(f).or_default() += n;
+            }
+        }
+        let mut functions = own.into_iter().filter(|(_, n)| *n > 0).collect::<Vec<_>>();
+        functions.sort_by(|(fa, a), (fb, b)| b.cmp(a).then_with(|| self.name(*fa).cmp(self.name(*fb))));
+
+        let mut lines = HashMap::<(&str, usize), u64>::new();
+        for (ip, n) in self.counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+            *lines.entry((ops[ip].loc.0.as_str(), ops[ip].loc.1)).or_default() += n;
+        }
+        let mut lines = lines.into_iter().collect::<Vec<_>>();
+        lines.sort_by(|(la, a), (lb, b)| b.cmp(a).then_with(|| la.cmp(lb)));
+
+        let mut hot = self.counts.iter().enumerate().filter(|(_, n)| **n > 0).collect::<Vec<_>>();
+        hot.sort_by(|(ia, a), (ib, b)| b.cmp(a).then_with(|| ia.cmp(ib)));
+
+        let mut s = String::new();
+        let _ = writeln!(s, "{total} operators executed");
+        let _ = writeln!(s);
+        let _ = writeln!(s, "by function:");
+        let _ = writeln!(s, "{:>12} {:>7} {:>12} {:>7}  function", "self", "%", "total", "%");
+        for (f, n) in functions {
+            let incl = inclusive.get(&f).copied().unwrap_or_default();
+            let _ = writeln!(s, "{n:>12} {:>6.2}% {incl:>12} {:>6.2}%  {}", percent(n), percent(incl), self.name(f));
+        }
+        let _ = writeln!(s);
+        let _ = writeln!(s, "by line:");
+        let _ = writeln!(s, "{:>12} {:>7}  line", "count", "%");
+        for ((file, line), n) in lines {
+            let _ = writeln!(s, "{n:>12} {:>6.2}%  {file}:{line}", percent(n));
+        }
+        let _ = writeln!(s);
+        let _ = writeln!(s, "hottest operators:");
+        let _ = writeln!(s, "{:>12} {:>7}  location", "count", "%");
+        for (ip, n) in hot.into_iter().take(HOT_OPS) {
+            let op = &ops[ip];
+            let _ = writeln!(s, "{n:>12} {:>6.2}%  {}:{}:{} {}", percent(*n), op.loc.0, op.loc.1, op.loc.2, describe(op));
+        }
+        s
+    }
+}
@@ -1,22 +1,414 @@
-#[allow(clippy::cast_possible_truncation)]
-pub fn sys_write(sys_n: usize, fd: usize, buff: usize, count: usize, mem: &Vec<u64> ) -> usize {
-    let mem = (*mem).clone();
-    // println!("{:?}", &mem[buff..(buff + count)]);
-    // return 0 ;
-    let s = &mem[buff..(buff + count)].iter().map(|i| {
-        char::from_u32(u32::from(*i as u8)).unwrap_or('_').to_string()
-    }).collect::<String>();
-    
-    match fd {
-        1 => {
-            print!("{s}");
-        },
-        2 => {
-            eprint!("{s}");
-        },
-        _ => panic!("Unknown file {fd}")
+use std::{fs::{self, DirBuilder, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, os::unix::fs::{DirBuilderExt, OpenOptionsExt}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use crate::warn;
+
+use super::{mem_range, Defineds, Limits, vfs::{Node, Vfs}};
+
+/*
+ * Syscalls get the same arguments the kernel would, failures return -errno
+ * like the syscall instruction does. File descriptors are indexes into a
+ * table of host files, 0, 1 and 2 are the ones of the interpreter.
+ * brk and anonymous mmap grow the address space past the string literals,
+ * mappings are never given back.
+ * With a sandbox every path refers to a file in it instead of on the host.
+ * Growing the heap or mapping past the memory limit stops the program.
+ */
+
+const SYS_READ: usize = 0;
+const SYS_WRITE: usize = 1;
+const SYS_OPEN: usize = 2;
+const SYS_CLOSE: usize = 3;
+const SYS_LSEEK: usize = 8;
+const SYS_MMAP: usize = 9;
+const SYS_BRK: usize = 12;
+const SYS_GETPID: usize = 39;
+const SYS_EXIT: usize = 60;
+const SYS_MKDIR: usize = 83;
+const SYS_RMDIR: usize = 84;
+const SYS_UNLINK: usize = 87;
+const SYS_CLOCK_GETTIME: usize = 228;
+const SYS_EXIT_GROUP: usize = 231;
+
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 38;
+
+const O_ACCMODE: usize = 0o3;
+const O_WRONLY: usize = 0o1;
+const O_RDWR: usize = 0o2;
+const O_CREAT: usize = 0o100;
+const O_EXCL: usize = 0o200;
+const O_TRUNC: usize = 0o1000;
+const O_APPEND: usize = 0o2000;
+
+const MAP_ANONYMOUS: usize = 0x20;
+const PAGE_SZ: usize = 4096;
+/// The address space never grows past this
+const MAX_MEM: usize = 1 << 30;
+/// What getpid returns in deterministic mode
+const FAKE_PID: usize = 1000;
+/// How far the clocks move with every clock_gettime in deterministic mode
+const FAKE_TICK: Duration = Duration::from_millis(1);
+
+pub enum Ret {
+    Value(usize),
+    Exit(i32),
+    /// Bytes the program would have had with brk and mmap
+    MemoryLimit(usize),
+}
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+    Sandboxed {
+        node: Node,
+        pos: usize,
+        read: bool,
+        write: bool,
+        append: bool,
+    },
+}
+
+pub struct Sys {
+    fds: Vec<Option<Fd>>,
+    /// Start of the heap, the end of the string literals
+    heap_start: usize,
+    brk: usize,
+    /// Where the first mapping starts, the break can not move past it
+    mmap_start: Option<usize>,
+    started: Instant,
+    sandbox: Option<Vfs>,
+    max_alloc: Option<usize>,
+    /// The time of both clocks in deterministic mode
+    fake_time: Option<Duration>,
+}
+
+impl Sys {
+    pub fn new(heap_start: usize, sandbox: Option<Vfs>, limits: &Limits) -> Self {
+        let heap_start = heap_start.next_multiple_of(PAGE_SZ);
+        Self {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            heap_start,
+            brk: heap_start,
+            mmap_start: None,
+            started: Instant::now(),
+            sandbox,
+            max_alloc: limits.memory,
+            fake_time: limits.deterministic.then_some(Duration::ZERO),
+        }
+    }
+
+    /// Put argc, the argv and envp pointers and their strings at the start
+    /// of the heap like linux puts them on the stack, returns where argc is
+    pub fn place_args(&mut self, argv: &[&[u8]], envp: &[&[u8]], mem: &mut Vec<u8>) -> usize {
+        let base = self.heap_start;
+        let mut at = base + 8 * (argv.len() + envp.len() + 3);
+        let mut slots = vec![argv.len()];
+        mem.resize(at, 0);
+        for strings in [argv, envp] {
+            for s in strings {
+                slots.push(at);
+                mem.extend_from_slice(s);
+                mem.push(0);
+                at += s.len() + 1;
+            }
+            slots.push(0);
+        }
+        for (i, v) in slots.iter().enumerate() {
+            mem[base + i * 8..base + i * 8 + 8].copy_from_slice(&v.to_le_bytes());
+        }
+
+        self.heap_start = at.next_multiple_of(PAGE_SZ);
+        self.brk = self.heap_start;
+        base
+    }
+
+    pub fn sandbox(&self) -> Option<&Vfs> {
+        self.sandbox.as_ref()
+    }
+
+    /// Run syscall `n`, unused arguments are 0
+    pub fn call(&mut self, n: usize, args: [usize; 6], mem: &mut Vec<u8>, defineds: &Defineds) -> Ret {
+        let [a, b, c, d, e, f] = args;
+        if let Some(wanted) = self.over_limit(n, a, b, mem) {
+            return Ret::MemoryLimit(wanted);
+        }
+        let ret = match n {
+            SYS_READ => self.read(a, b, c, mem, defineds),
+            SYS_WRITE => self.write(a, b, c, mem, defineds),
+            SYS_OPEN => self.open(a, b, c, mem, defineds),
+            SYS_CLOSE => self.close(a),
+            SYS_LSEEK => self.lseek(a, b, c),
+            SYS_MMAP => self.mmap(b, d, e, f, mem),
+            SYS_BRK => Ok(self.brk(a, mem)),
+            SYS_GETPID if self.fake_time.is_some() => Ok(FAKE_PID),
+            SYS_GETPID => Ok(std::process::id() as usize),
+            SYS_CLOCK_GETTIME => self.clock_gettime(a, b, mem, defineds),
+            SYS_MKDIR => self.mkdir(a, b, mem, defineds),
+            SYS_RMDIR => self.rmdir(a, mem, defineds),
+            SYS_UNLINK => self.unlink(a, mem, defineds),
+            SYS_EXIT | SYS_EXIT_GROUP => return Ret::Exit(a as i32 & 0xff),
+            _ => {
+                warn!("Syscall #{n} is not supported by the interpreter");
+                Err(ENOSYS)
+            }
+        };
+        Ret::Value(ret.unwrap_or_else(|errno| -(errno as isize) as usize))
+    }
+
+    fn read(&mut self, fd: usize, buf: usize, count: usize, mem: &mut [u8], defineds: &Defineds) -> Result<usize, i32> {
+        let file = self.fds.get_mut(fd).and_then(Option::as_mut).ok_or(EBADF)?;
+        if count == 0 {
+            return Ok(0);
+        }
+        let range = mem_range(buf, count, mem.len(), defineds).ok_or(EFAULT)?;
+        let buf = &mut mem[range];
+        let r = match file {
+            Fd::Stdin => io::stdin().read(buf),
+            Fd::File(f) => f.read(buf),
+            Fd::Sandboxed { node: Node::File(f), pos, read: true, .. } => {
+                let n = self.sandbox.as_ref().unwrap().read(*f, *pos, buf);
+                *pos += n;
+                Ok(n)
+            }
+            Fd::Sandboxed { node: Node::Dir, .. } => return Err(EISDIR),
+            Fd::Stdout | Fd::Stderr | Fd::Sandboxed { .. } => return Err(EBADF),
+        };
+        r.map_err(errno)
+    }
+
+    fn write(&mut self, fd: usize, buf: usize, count: usize, mem: &[u8], defineds: &Defineds) -> Result<usize, i32> {
+        let file = self.fds.get_mut(fd).and_then(Option::as_mut).ok_or(EBADF)?;
+        if count == 0 {
+            return Ok(0);
+        }
+        let buf = &mem[mem_range(buf, count, mem.len(), defineds).ok_or(EFAULT)?];
+        // unbuffered, like the compiled program
+        let r = match file {
+            Fd::Stdout => io::stdout().write_all(buf).and_then(|()| io::stdout().flush()),
+            Fd::Stderr => io::stderr().write_all(buf).and_then(|()| io::stderr().flush()),
+            Fd::File(f) => f.write_all(buf),
+            Fd::Sandboxed { node: Node::File(f), pos, write: true, append, .. } => {
+                let sandbox = self.sandbox.as_mut().unwrap();
+                if *append {
+                    *pos = sandbox.len(*f);
+                }
+                sandbox.write(*f, *pos, buf, self.max_alloc.unwrap_or(MAX_MEM))?;
+                *pos += count;
+                Ok(())
+            }
+            Fd::Stdin | Fd::Sandboxed { .. } => return Err(EBADF),
+        };
+        r.map(|()| count).map_err(errno)
+    }
+
+    fn open(&mut self, path: usize, flags: usize, mode: usize, mem: &[u8], defineds: &Defineds) -> Result<usize, i32> {
+        let path = path_arg(path, mem, defineds)?;
+        if let Some(sandbox) = &mut self.sandbox {
+            let file = open_sandboxed(sandbox, path, flags)?;
+            return Ok(self.add_fd(file));
+        }
+        let file = OpenOptions::new()
+            .read(flags & O_ACCMODE != O_WRONLY)
+            .write(flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .custom_flags((flags & !(O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND)) as i32)
+            .mode(mode as u32)
+            .open(path)
+            .map_err(errno)?;
+        Ok(self.add_fd(Fd::File(file)))
+    }
+
+    /// Put `file` in the lowest free fd, like the kernel
+    fn add_fd(&mut self, file: Fd) -> usize {
+        let fd = self.fds.iter().position(Option::is_none).unwrap_or(self.fds.len());
+        if fd == self.fds.len() {
+            self.fds.push(None);
+        }
+        self.fds[fd] = Some(file);
+        fd
+    }
+
+    fn close(&mut self, fd: usize) -> Result<usize, i32> {
+        self.fds.get_mut(fd).and_then(Option::take).ok_or(EBADF)?;
+        Ok(0)
+    }
+
+    fn lseek(&mut self, fd: usize, offset: usize, whence: usize) -> Result<usize, i32> {
+        let file = match self.fds.get_mut(fd).and_then(Option::as_mut) {
+            Some(Fd::File(file)) => file,
+            Some(Fd::Sandboxed { node, pos, .. }) => {
+                let base = match (whence, node) {
+                    (0, _) => 0,
+                    (1, _) => *pos,
+                    (2, Node::File(f)) => self.sandbox.as_ref().unwrap().len(*f),
+                    (2, Node::Dir) => 0,
+                    _ => return Err(EINVAL),
+                };
+                *pos = base.checked_add_signed(offset as isize).ok_or(EINVAL)?;
+                return Ok(*pos);
+            }
+            Some(_) => return Err(ESPIPE),
+            None => return Err(EBADF),
+        };
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        file.seek(pos).map(|p| p as usize).map_err(errno)
+    }
+
+    fn mkdir(&mut self, path: usize, mode: usize, mem: &[u8], defineds: &Defineds) -> Result<usize, i32> {
+        let path = path_arg(path, mem, defineds)?;
+        match &mut self.sandbox {
+            Some(sandbox) => sandbox.mkdir(path)?,
+            None => DirBuilder::new().mode(mode as u32).create(path).map_err(errno)?,
+        }
+        Ok(0)
+    }
+
+    fn rmdir(&mut self, path: usize, mem: &[u8], defineds: &Defineds) -> Result<usize, i32> {
+        let path = path_arg(path, mem, defineds)?;
+        match &mut self.sandbox {
+            Some(sandbox) => sandbox.rmdir(path)?,
+            None => fs::remove_dir(path).map_err(errno)?,
+        }
+        Ok(0)
+    }
+
+    fn unlink(&mut self, path: usize, mem: &[u8], defineds: &Defineds) -> Result<usize, i32> {
+        let path = path_arg(path, mem, defineds)?;
+        match &mut self.sandbox {
+            Some(sandbox) => sandbox.unlink(path)?,
+            None => fs::remove_file(path).map_err(errno)?,
+        }
+        Ok(0)
+    }
+
+    /// Bytes the program has gotten with brk and mmap
+    fn allocated(&self, mem: &[u8]) -> usize {
+        let mapped = self.mmap_start.map_or(0, |start| mem.len() - start);
+        self.brk - self.heap_start + mapped
+    }
+
+    /// How much the program would have if syscall `n` went through, if that
+    /// is more than the limit
+    fn over_limit(&self, n: usize, a: usize, b: usize, mem: &[u8]) -> Option<usize> {
+        let max = self.max_alloc?;
+        let wanted = match n {
+            SYS_BRK if a > self.brk && a <= self.mmap_start.unwrap_or(MAX_MEM) => self.allocated(mem) + (a - self.brk),
+            SYS_MMAP => match b.checked_next_multiple_of(PAGE_SZ) {
+                Some(len) => self.allocated(mem).saturating_add(len),
+                None => return None,
+            },
+            _ => return None,
+        };
+        (wanted > max).then_some(wanted)
+    }
+
+    /// Only anonymous mappings, they always go at the end of the address space
+    fn mmap(&mut self, len: usize, flags: usize, fd: usize, offset: usize, mem: &mut Vec<u8>) -> Result<usize, i32> {
+        if flags & MAP_ANONYMOUS == 0 {
+            warn!("mmap of files is not supported by the interpreter");
+            return Err(ENOSYS);
+        }
+        if len == 0 || fd as isize != -1 || offset != 0 {
+            return Err(EINVAL);
+        }
+        let addr = mem.len().next_multiple_of(PAGE_SZ);
+        let end = len.checked_next_multiple_of(PAGE_SZ).and_then(|len| addr.checked_add(len)).filter(|e| *e <= MAX_MEM).ok_or(ENOMEM)?;
+        mem.resize(end, 0);
+        self.mmap_start.get_or_insert(addr);
+        Ok(addr)
+    }
+
+    /// Returns the new break, or the old one if it can not be moved there
+    fn brk(&mut self, addr: usize, mem: &mut Vec<u8>) -> usize {
+        if addr < self.heap_start || addr > self.mmap_start.unwrap_or(MAX_MEM) {
+            return self.brk;
+        }
+        if self.mmap_start.is_none() {
+            // the heap is the end of the address space
+            mem.resize(addr, 0);
+        } else if addr > self.brk {
+            mem[self.brk..addr].fill(0);
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    fn clock_gettime(&mut self, clock: usize, tp: usize, mem: &mut [u8], defineds: &Defineds) -> Result<usize, i32> {
+        let time = match clock {
+            0 | 1 | 4 | 7 if self.fake_time.is_some() => {
+                let time = self.fake_time.unwrap_or_default() + FAKE_TICK;
+                self.fake_time = Some(time);
+                time
+            }
+            // CLOCK_REALTIME
+            0 => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            // CLOCK_MONOTONIC, CLOCK_MONOTONIC_RAW, CLOCK_BOOTTIME
+            1 | 4 | 7 => self.started.elapsed(),
+            _ => return Err(EINVAL),
+        };
+        let tp = mem_range(tp, 16, mem.len(), defineds).ok_or(EFAULT)?;
+        mem[tp.start..tp.start + 8].copy_from_slice(&time.as_secs().to_le_bytes());
+        mem[tp.start + 8..tp.end].copy_from_slice(&u64::from(time.subsec_nanos()).to_le_bytes());
+        Ok(0)
+    }
+}
+
+fn open_sandboxed(sandbox: &mut Vfs, path: &str, flags: usize) -> Result<Fd, i32> {
+    let write = flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR;
+    let node = match sandbox.lookup(path) {
+        Some(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(EEXIST),
+        Some(Node::Dir) if write => return Err(EISDIR),
+        Some(node) => node,
+        None if flags & O_CREAT != 0 => Node::File(sandbox.create(path)?),
+        None => return Err(ENOENT),
     };
-    let _ = std::io::Write::flush(&mut std::io::stdout());
-    let _ = std::io::Write::flush(&mut std::io::stderr());
-    sys_n
-}
\ No newline at end of file
+    if let Node::File(f) = node {
+        if write && flags & O_TRUNC != 0 {
+            sandbox.truncate(f);
+        }
+    }
+    Ok(Fd::Sandboxed {
+        node,
+        pos: 0,
+        read: flags & O_ACCMODE != O_WRONLY,
+        write,
+        append: flags & O_APPEND != 0,
+    })
+}
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EINVAL)
+}
+
+/// The NUL terminated path at `addr`
+fn path_arg<'a>(addr: usize, mem: &'a [u8], defineds: &Defineds) -> Result<&'a str, i32> {
+    let path = c_str(addr, mem, defineds).ok_or(EFAULT)?;
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    std::str::from_utf8(path).map_err(|_| EINVAL)
+}
+
+/// The bytes of the NUL terminated string at `addr`, without the NUL
+fn c_str<'a>(addr: usize, mem: &'a [u8], defineds: &Defineds) -> Option<&'a [u8]> {
+    let len = mem.get(addr..)?.iter().position(|b| *b == 0)?;
+    mem_range(addr, len + 1, mem.len(), defineds)?;
+    Some(&mem[addr..addr + len])
+}
@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use color_eyre::Result;
+use eyre::eyre;
+
+use crate::error;
+
+/*
+ * The file tree interpreted programs see with --sandbox. It lives in memory,
+ * can be seeded from a directory on the host and is written back to one when
+ * the program exits, the host file system is never touched otherwise.
+ * The working directory is always the root. File contents stay around after
+ * an unlink, so fds that still use them keep working like on linux.
+ */
+
+const ENOENT: i32 = 2;
+const EEXIST: i32 = 17;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EFBIG: i32 = 27;
+const ENOTEMPTY: i32 = 39;
+const EBUSY: i32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Node {
+    Dir,
+    /// Index into `Vfs::files`
+    File(usize),
+}
+
+pub struct Vfs {
+    /// Normalised absolute paths, the parent of every path is in here too
+    paths: BTreeMap<String, Node>,
+    files: Vec<Vec<u8>>,
+    dump: Option<PathBuf>,
+}
+
+impl Vfs {
+    /// An empty tree or a copy of `seed`, written to `dump` by `Vfs::dump`
+    pub fn new(seed: Option<&str>, dump: Option<String>) -> Result<Self> {
+        let mut vfs = Self {
+            paths: BTreeMap::from([(String::from("/"), Node::Dir)]),
+            files: Vec::new(),
+            dump: dump.map(PathBuf::from),
+        };
+        if let Some(seed) = seed {
+            if !Path::new(seed).is_dir() {
+                error!("Sandbox directory '{seed}' does not exist");
+                return Err(eyre!(""));
+            }
+            vfs.load(Path::new(seed), "")?;
+        }
+        Ok(vfs)
+    }
+
+    fn load(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = format!("{prefix}/{}", entry.file_name().to_string_lossy());
+            if entry.path().is_dir() {
+                self.paths.insert(path.clone(), Node::Dir);
+                self.load(&entry.path(), &path)?;
+            } else {
+                self.files.push(fs::read(entry.path())?);
+                self.paths.insert(path, Node::File(self.files.len() - 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the tree to the dump directory if there is one
+    pub fn dump(&self) -> Result<()> {
+        let Some(root) = &self.dump else {
+            return Ok(());
+        };
+        for (path, node) in &self.paths {
+            let host = root.join(path.trim_start_matches('/'));
+            match node {
+                Node::Dir => fs::create_dir_all(host)?,
+                Node::File(f) => fs::write(host, &self.files[*f])?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, path: &str) -> Option<Node> {
+        self.paths.get(&normalise(path)).copied()
+    }
+
+    /// Add an empty file at `path`
+    pub fn create(&mut self, path: &str) -> Result<usize, i32> {
+        let path = self.new_path(path)?;
+        self.files.push(Vec::new());
+        self.paths.insert(path, Node::File(self.files.len() - 1));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<(), i32> {
+        let path = self.new_path(path)?;
+        self.paths.insert(path, Node::Dir);
+        Ok(())
+    }
+
+    pub fn unlink(&mut self, path: &str) -> Result<(), i32> {
+        let path = normalise(path);
+        match self.paths.get(&path) {
+            None => Err(ENOENT),
+            Some(Node::Dir) => Err(EISDIR),
+            Some(Node::File(_)) => {
+                self.paths.remove(&path);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> Result<(), i32> {
+        let path = normalise(path);
+        match self.paths.get(&path) {
+            None => Err(ENOENT),
+            Some(Node::File(_)) => Err(ENOTDIR),
+            Some(Node::Dir) if path == "/" => Err(EBUSY),
+            Some(Node::Dir) => {
+                let prefix = format!("{path}/");
+                if self.paths.keys().any(|p| p.starts_with(&prefix)) {
+                    return Err(ENOTEMPTY);
+                }
+                self.paths.remove(&path);
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy the bytes of `file` from `pos` into `buf`, returns how many
+    pub fn read(&self, file: usize, pos: usize, buf: &mut [u8]) -> usize {
+        let data = self.files[file].get(pos..).unwrap_or_default();
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        n
+    }
+
+    /// Write `data` at `pos`, a gap before it gets filled with zeros, fails
+    /// with EFBIG instead of growing the file past `max_len`
+    pub fn write(&mut self, file: usize, pos: usize, data: &[u8], max_len: usize) -> Result<(), i32> {
+        let f = &mut self.files[file];
+        let end = pos.checked_add(data.len()).filter(|end| *end <= max_len.max(f.len())).ok_or(EFBIG)?;
+        if f.len() < end {
+            f.resize(end, 0);
+        }
+        f[pos..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn len(&self, file: usize) -> usize {
+        self.files[file].len()
+    }
+
+    pub fn truncate(&mut self, file: usize) {
+        self.files[file].clear();
+    }
+
+    /// The normalised `path` if it does not exist yet and its parent is a directory
+    fn new_path(&self, path: &str) -> Result<String, i32> {
+        let path = normalise(path);
+        if self.paths.contains_key(&path) {
+            return Err(EEXIST);
+        }
+        let parent = path.rsplit_once('/').map_or("/", |(p, _)| if p.is_empty() { "/" } else { p });
+        match self.paths.get(parent) {
+            Some(Node::Dir) => Ok(path),
+            Some(Node::File(_)) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+}
+
+/// Absolute path without `.`, `..` and empty components, `..` stops at the root
+fn normalise(path: &str) -> String {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
@@ -2,6 +2,7 @@ use crate::constants::Loc;
 
 pub mod linux_x86_64;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub loc: Loc,
@@ -12,13 +13,18 @@ pub struct Constant {
     // extern: bool
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Memory {
+    pub name: String,
     pub size: usize,
     pub loc: Loc,
-    pub id: usize
+    pub id: usize,
+    /// Where the block starts in the interpreters address space
+    pub addr: usize
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Function {
     pub loc: Loc,
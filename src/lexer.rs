@@ -1,126 +1,396 @@
+use crate::{constants::{Loc, Token, TokenType}, lerror, Args};
+use color_eyre::Result;
+use eyre::eyre;
 
-use crate::{constants::{Token, TokenType}, Args};
-
-fn lex_word(s: String, tok_type: TokenType) -> (TokenType, String) {
-    match s {
-        s if s.parse::<u64>().is_ok() && tok_type == TokenType::Word => { // negative numbers not yet implemented
-            (TokenType::Int, s)
-        },
-        s if tok_type == TokenType::Word => {
-            (TokenType::Word, s)
-        },
-        s if tok_type == TokenType::String => {
-            (TokenType::String, s)
-        }
-        s if tok_type == TokenType::Char => {
-            (TokenType::Char, s)
+/*
+ * Goes over the source once, character by character. Words are everything
+ * between whitespace and comments, `//` starts a comment that goes to the end
+ * of the line and `/* ... */` comments can span lines and nest.
+ * `///` comments are kept as doc tokens, the parser attaches them to the
+ * definition that follows.
+ * A quote at the start of a token starts a string or char literal, they can
+ * have escapes and strings can span lines. Raw strings (`r"..."`, or
+ * `r#"..."#` with any number of `#` to allow quotes) keep everything as is.
+ * A `c` right after the closing quote makes either kind a C string.
+ * Lines count from 1 and columns count characters from 0, `\r\n` is one
+ * newline everywhere, also inside strings. Tokens know their byte span.
+ */
+
+/// Why the source could not be lexed
+#[derive(Debug, Clone)]
+pub struct LexError {
+    pub loc: Loc,
+    pub msg: String,
+    /// A literal was still open at the end, more source could finish it
+    pub unterminated: bool,
+}
+
+/// Sign, radix and digits of an integer literal: an optional `-`, then
+/// `0x`, `0b`, `0o` or nothing and digits that can be separated by `_`
+fn int_literal(s: &str) -> Option<(bool, u32, &str)> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let (radix, digits) = match s.get(..2) {
+        Some("0x") => (16, &s[2..]),
+        Some("0b") => (2, &s[2..]),
+        Some("0o") => (8, &s[2..]),
+        _ if s.starts_with(|c: char| c.is_ascii_digit()) => (10, s),
+        _ => return None,
+    };
+    let valid = digits.chars().all(|c| c == '_' || c.is_digit(radix)) && digits.chars().any(|c| c.is_digit(radix));
+    valid.then_some((negative, radix, digits))
+}
+
+/// The value of the integer literal `s`, negative ones in two's complement,
+/// None if it does not fit in 64 bits
+pub fn int_value(s: &str) -> Option<usize> {
+    let (negative, radix, digits) = int_literal(s)?;
+    let mut n: u64 = 0;
+    for d in digits.chars().filter_map(|c| c.to_digit(radix)) {
+        n = n.checked_mul(u64::from(radix))?.checked_add(u64::from(d))?;
+    }
+    if negative {
+        if n > 1 << 63 {
+            return None;
         }
-        _ => unreachable!()
+        n = n.wrapping_neg();
     }
+    usize::try_from(n).ok()
 }
 
-pub fn find_col<F>(text: &str, mut col: usize, predicate: F) -> usize where F: Fn(char, char) -> bool {
-    let mut last = '\0';
-    while col < text.len() && !predicate(text.chars().nth(col).unwrap(), last) {
-        last = text.chars().nth(col).unwrap();
-        col += 1;
+/// Whether `s` starts with `//` or `/*`
+fn starts_comment(s: &str) -> bool {
+    s.starts_with("//") || s.starts_with("/*")
+}
+
+fn lex_word(s: String) -> (TokenType, String) {
+    if int_literal(&s).is_some() {
+        (TokenType::Int, s)
+    } else {
+        (TokenType::Word, s)
     }
+}
 
-    col
+struct Lexer<'a> {
+    src: &'a str,
+    file: &'a str,
+    /// Byte offset of the next character
+    pos: usize,
+    line: usize,
+    col: usize,
 }
 
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str, file: &'a str) -> Self {
+        Self { src, file, pos: 0, line: 1, col: 0 }
+    }
 
-// TODO: Implement multiline strings
-fn lex_line(text: &str) -> Vec<(usize, String, TokenType)> {
-    let mut tokens: Vec<(usize, String, TokenType)> = Vec::new();
+    fn peek(&self) -> Option<char> {
+        let rest = &self.src[self.pos..];
+        if rest.starts_with("\r\n") {
+            return Some('\n');
+        }
+        rest.chars().next()
+    }
 
-    let mut col = find_col(text, 0, |x, _| !x.is_whitespace());
-    let mut col_end: usize = 0;
-    while col_end < text.to_string().len() {
-        if (text.len() - col) < 1 {
-            return tokens;
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += if self.src[self.pos..].starts_with("\r\n") { 2 } else { c.len_utf8() };
+        if c == '\n' {
+            self.line += 1;
+            self.col = 0;
+        } else {
+            self.col += 1;
         }
-        if &text[col..=col] == "\"" {
-            col_end = find_col(text, col + 1, |x, x2| x == '"' && x2 != '\\');
-            let t = &text[(col + 1)..col_end];
-            let t = t.replace("\\n", "\n")
-                                .replace("\\t", "\t")
-                                .replace("\\r", "\r")
-                                .replace("\\\'", "\'")
-                                .replace("\\\"", "\"")
-                                .replace("\\0", "\0");
-            if !t.is_empty() {
-                tokens.push((col, t.to_string(), TokenType::String));
+        Some(c)
+    }
+
+    fn loc(&self) -> Loc {
+        (self.file.to_string(), self.line, self.col)
+    }
+
+    fn error(&self, loc: Loc, msg: String) -> LexError {
+        LexError { loc, msg, unterminated: false }
+    }
+
+    fn tokens(&mut self) -> Result<Vec<Token>, LexError> {
+        let mut tokens = Vec::new();
+        loop {
+            while self.peek().is_some_and(char::is_whitespace) {
+                self.bump();
+            }
+            let Some(c) = self.peek() else {
+                break;
+            };
+            let (start, line, col) = (self.pos, self.line, self.col);
+            let (typ, text) = match c {
+                '"' => {
+                    let s = self.string()?;
+                    self.string_type(s, (line, col))?
+                }
+                '\'' => (TokenType::Char, self.char()?),
+                'r' if self.raw_hashes().is_some() => {
+                    let s = self.raw_string()?;
+                    self.string_type(s, (line, col))?
+                }
+                '/' if self.src[self.pos..].starts_with("/*") => {
+                    self.block_comment()?;
+                    continue;
+                }
+                '/' if self.src[self.pos..].starts_with("//") => match self.line_comment() {
+                    Some(doc) => (TokenType::DocComment, doc),
+                    None => continue,
+                },
+                _ => {
+                    while self.peek().is_some_and(|c| !c.is_whitespace()) && !self.at_comment() {
+                        self.bump();
+                    }
+                    lex_word(self.src[start..self.pos].to_string())
+                }
+            };
+            tokens.push(Token {
+                file: self.file.to_string(),
+                line,
+                col,
+                span: (start, self.pos),
+                text,
+                typ,
+                value: None,
+                addr: None,
+                op_typ: crate::constants::OpType::Instruction(crate::constants::InstructionType::None)
+            });
+        }
+        Ok(tokens)
+    }
+
+    fn at_comment(&self) -> bool {
+        starts_comment(&self.src[self.pos..])
+    }
+
+    /// Skips a `//` comment, returns the text of a `///` doc comment without
+    /// the slashes and the first space
+    fn line_comment(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.bump();
+        }
+        let comment = &self.src[start..self.pos];
+        let doc = comment.strip_prefix("///").filter(|doc| !doc.starts_with('/'))?;
+        Some(doc.strip_prefix(' ').unwrap_or(doc).to_string())
+    }
+
+    /// Skips a `/* ... */` comment and the ones nested in it
+    fn block_comment(&mut self) -> Result<(), LexError> {
+        let start = self.loc();
+        let mut depth = 0;
+        loop {
+            let rest = &self.src[self.pos..];
+            if rest.starts_with("/*") {
+                depth += 1;
+            } else if rest.starts_with("*/") {
+                depth -= 1;
+            } else if rest.is_empty() {
+                return Err(LexError { loc: start, msg: String::from("Unterminated block comment"), unterminated: true });
+            } else {
+                self.bump();
+                continue;
             }
-            col = find_col(text, col_end + 1, |x, _| !x.is_whitespace());
-
-        } else if &text[col..=col] == "'"{
-            col_end = find_col(text, col + 1, |x, x2| x == '\'' && x2 != '\\');
-            let t = &text[(col + 1)..col_end];
-            let t = t.replace("\\n", "\n")
-                                .replace("\\t", "\t")
-                                .replace("\\r", "\r")
-                                .replace("\\\'", "\'")
-                                .replace("\\\"", "\"")
-                                .replace("\\0", "\0");
-
-            
-            if !t.is_empty() {
-                tokens.push((col, t.to_string(), TokenType::Char));
+            self.bump();
+            self.bump();
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// A string literal, can span lines
+    fn string(&mut self) -> Result<String, LexError> {
+        let start = self.loc();
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(LexError { loc: start, msg: String::from("Unterminated string literal"), unterminated: true }),
+                Some('"') => {
+                    self.bump();
+                    return Ok(s);
+                }
+                Some('\\') => s.push(self.escape()?),
+                Some(c) => {
+                    self.bump();
+                    s.push(c);
+                }
             }
-            col = find_col(text, col_end + 1, |x, _| !x.is_whitespace());
+        }
+    }
 
-        } else {
+    /// A string is a C string if a `c` follows its closing quote directly and
+    /// ends the word, those end at the first NUL so they can not have one inside
+    fn string_type(&mut self, s: String, (line, col): (usize, usize)) -> Result<(TokenType, String), LexError> {
+        let rest = &self.src[self.pos..];
+        let Some(after) = rest.strip_prefix('c') else {
+            return Ok((TokenType::String, s));
+        };
+        if after.starts_with(|c: char| !c.is_whitespace()) && !starts_comment(after) {
+            return Ok((TokenType::String, s));
+        }
+        self.bump();
+        if s.contains('\0') {
+            return Err(self.error((self.file.to_string(), line, col), String::from("C strings can not contain a NUL character, the terminating one is added automatically")));
+        }
+        Ok((TokenType::CString, s))
+    }
 
-            col_end = find_col(text, col, |x, _| x.is_whitespace());
-            let t = &text[col..col_end];
-            
-            if t == "//" {
-                return tokens;
+    /// A char literal, the parser checks that it is one character
+    fn char(&mut self) -> Result<String, LexError> {
+        let start = self.loc();
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err(self.error(start, String::from("Unterminated character literal"))),
+                Some('\'') => {
+                    self.bump();
+                    return Ok(s);
+                }
+                Some('\\') => s.push(self.escape()?),
+                Some(c) => {
+                    self.bump();
+                    s.push(c);
+                }
             }
+        }
+    }
 
-            if !t.is_empty() {
-                tokens.push((col, t.to_string(), TokenType::Word));
+    /// The escape sequence at the next `\`
+    fn escape(&mut self) -> Result<char, LexError> {
+        let start = self.loc();
+        self.bump();
+        let c = match self.bump() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('\'') => '\'',
+            Some('"') => '"',
+            Some('x') => {
+                let digits = (0..2).map_while(|_| self.peek().filter(char::is_ascii_hexdigit).and_then(|_| self.bump())).collect::<String>();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(n) if digits.len() == 2 && n <= 0x7f => char::from(n),
+                    Ok(_) if digits.len() == 2 => return Err(self.error(start, format!("Escape '\\x{digits}' is not ASCII, only '\\x00' to '\\x7f' are allowed, use '\\u{{...}}' for other characters"))),
+                    _ => return Err(self.error(start, String::from("Escape '\\x' needs exactly 2 hex digits"))),
+                }
             }
-            col = find_col(text, col_end, |x, _| !x.is_whitespace());
+            Some('u') => {
+                if self.peek() != Some('{') {
+                    return Err(self.error(start, String::from("Escape '\\u' needs its hex digits in braces, like '\\u{3bb}'")));
+                }
+                self.bump();
+                let digits = std::iter::from_fn(|| self.peek().filter(char::is_ascii_hexdigit).and_then(|_| self.bump())).collect::<String>();
+                if self.bump() != Some('}') || digits.is_empty() || digits.len() > 6 {
+                    return Err(self.error(start, String::from("Escape '\\u{...}' needs 1 to 6 hex digits and a closing brace")));
+                }
+                match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+                    Some(c) => c,
+                    None => return Err(self.error(start, format!("Escape '\\u{{{digits}}}' is not a valid unicode character"))),
+                }
+            }
+            Some(c) if c != '\n' => return Err(self.error(start, format!("Unknown escape '\\{c}'"))),
+            _ => return Err(self.error(start, String::from("Expected an escape after '\\'"))),
+        };
+        Ok(c)
+    }
+
+    /// How many `#` a raw string starting here has
+    fn raw_hashes(&self) -> Option<usize> {
+        let rest = self.src[self.pos..].strip_prefix('r')?;
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        rest[hashes..].starts_with('"').then_some(hashes)
+    }
+
+    /// A raw string, ends at a quote followed by as many `#` as it started with
+    fn raw_string(&mut self) -> Result<String, LexError> {
+        let start = self.loc();
+        let hashes = self.raw_hashes().unwrap_or_default();
+        for _ in 0..hashes + 2 {
+            self.bump();
         }
+        let end = format!("\"{}", "#".repeat(hashes));
+        let Some(len) = self.src[self.pos..].find(&end) else {
+            return Err(LexError { loc: start, msg: String::from("Unterminated raw string literal"), unterminated: true });
+        };
+        let s = self.src[self.pos..self.pos + len].replace("\r\n", "\n");
+        let end = self.pos + len + end.len();
+        while self.pos < end {
+            self.bump();
+        }
+        Ok(s)
     }
-    tokens
 }
 
-pub fn lex(code: &str, file: &str, _args: &Args) -> Vec<Token> {
-    let lines: Vec<(usize, &str)> = code
-        .split(['\n', '\r'])
-        .enumerate()
-        .collect();
-    
-    let lines: Vec<(usize, String)> = lines.iter().map(|i| (i.0, i.1.to_string())).collect();
-
-    let mut tokens: Vec<Token> = Vec::new();
-
-    for (row, line) in lines {
-        let lt = lex_line(&line);
-        for (col, tok, tok_type) in lt {
-            let (tok_type, tok) = lex_word(tok, tok_type);
-            let t = Token{
-                file: file.to_string(),
-                line: row + 1,
-                col,
-                text: tok,
-                typ: tok_type,
-                value: None,
-                addr: None,
-                op_typ: crate::constants::OpType::Instruction(crate::constants::InstructionType::None)
-            };
-            tokens.push(t);
+/// `lex` without printing the error
+pub fn lex_tokens(code: &str, file: &str) -> Result<Vec<Token>, LexError> {
+    Lexer::new(code, file).tokens()
+}
+
+pub fn lex(code: &str, file: &str, _args: &Args) -> Result<Vec<Token>> {
+    match lex_tokens(code, file) {
+        Ok(tokens) => Ok(tokens),
+        Err(e) => {
+            lerror!(&e.loc, "{}", e.msg);
+            Err(eyre!(""))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::lex_tokens;
+    use crate::constants::TokenType;
+
+    /// The best of a few runs of the lexer on `src` in ns per byte, the
+    /// first one also warms up the allocator
+    fn ns_per_byte(src: &str) -> f64 {
+        let mut best = Duration::MAX;
+        for _ in 0..5 {
+            let start = Instant::now();
+            lex_tokens(src, "bench").unwrap();
+            best = best.min(start.elapsed());
         }
+        #[allow(clippy::cast_precision_loss)]
+        let per_byte = best.as_nanos() as f64 / src.len() as f64;
+        per_byte
     }
-    // println!("{}", tokens.len());
 
-    // for token in tokens.clone() {
-    //     println!("tok: {:?}", token.text);
-    // }
-    
+    /// Lexing is linear, so the time per byte stays about the same when the
+    /// input grows 16 times, the margin is wide to not fail on noisy machines
+    fn assert_flat(small: &str, large: &str) {
+        let (small, large) = (ns_per_byte(small), ns_per_byte(large));
+        assert!(large < small * 4.0, "{large:.2} ns/byte on the large input, {small:.2} on the small one");
+    }
 
-    tokens
-}
\ No newline at end of file
+    #[test]
+    fn time_per_byte_is_flat_for_more_lines() {
+        let code = format!("{}\n", include_str!("../include/linux.mcl"));
+        assert_flat(&code.repeat(4), &code.repeat(64));
+    }
+
+    #[test]
+    fn time_per_byte_is_flat_for_longer_lines() {
+        let words = "\"\u{3bb}\" drop ";
+        assert_flat(&words.repeat(1_000), &words.repeat(16_000));
+    }
+
+    #[test]
+    fn spans_point_at_the_words() {
+        let src = "include \"std.mcl\"\r\n/* \u{3bb} */ fn main\r\n  1 2 + // \u{3bb}\r\ndone\n";
+        for t in lex_tokens(src, "spans").unwrap().iter().filter(|t| t.typ == TokenType::Word) {
+            assert_eq!(&src[t.span.0..t.span.1], t.text);
+        }
+    }
+}
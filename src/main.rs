@@ -9,9 +9,12 @@ mod lexer;
 mod preprocessor;
 mod typechecker;
 mod precompiler;
+mod optimiser;
+mod repl;
 mod config;
 mod errors;
 use config::*;
+use interpret::linux_x86_64::{coverage::Coverage, debugger::Debugger, profiler::Profiler, vfs::Vfs, Limits};
 use std::{fs, collections::HashMap};
 
 use clap::Parser;
@@ -19,11 +22,14 @@ use color_eyre::Result;
 use eyre::eyre;
 
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input source file
-    #[arg(long, short)] 
-    in_file: String,
+    #[arg(long, short, required = true)] 
+    in_file: Option<String>,
 
     /// Output compiled file
     #[arg(long, short, default_value_t=String::from(DEFAULT_OUT_FILE))]
@@ -42,33 +48,133 @@ pub struct Args {
     run: bool,
 
     /// Dont print any output exept the actual running codes output
-    #[arg(long, short)]
+    #[arg(long, short, global = true)]
     quiet: bool,
     
     /// Add an include directory [default: ["./include", "~/.mclang/include"]]
-    #[arg(long, short='I')]
+    #[arg(long, short='I', global = true)]
     include: Vec<String>,
 
     /// Unsafe mode, disables typechecking
-    #[arg(long="unsafe", default_value_t = false)]
+    #[arg(long="unsafe", default_value_t = false, global = true)]
     unsaf: bool,
     
-    /// Optimisation level, available levels: 'D': debug, '0': No optimisations
+    /// Optimisation level, available levels: 'D': debug, '0': No optimisations, '1': Fold constants, remove dead code and keep the top of the stack in registers (linux_x86_64)
     #[arg(long, short='O', default_value_t=String::from("0"))]
     optimisation: String,
 
     // disables the main function
     #[arg(long="lib")]
-    lib_mode: bool
+    lib_mode: bool,
+
+    /// What to emit when compiling, on linux_x86_64 'exe' and 'obj' use the built in assembler and 'asm' goes through nasm and ld, on the other targets 'asm' only writes the generated source
+    #[arg(long, value_enum, default_value_t=Emit::Exe)]
+    emit: Emit,
+
+    /// Target platform to compile for
+    #[arg(long, short='t', value_enum, default_value_t=Target::LinuxX86_64)]
+    target: Target,
+
+    /// Stop at the start of the program and let you step through it, only when interpreting
+    #[arg(long)]
+    debug: bool,
+
+    /// Read the debugger commands from a file instead of stdin, implies --debug
+    #[arg(long)]
+    debug_script: Option<String>,
+
+    /// Count how often every operator runs while interpreting, writes a report to FILE and collapsed stacks for flamegraph tools to FILE.folded
+    #[arg(long, value_name = "FILE")]
+    profile: Option<String>,
+
+    /// Record which operators and branches run while interpreting, writes LCOV to FILE
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<String>,
+
+    /// Give the interpreted program an in memory file system instead of the real one, it starts as a copy of DIR if given
+    #[arg(long, value_name = "DIR", num_args = 0..=1, global = true)]
+    sandbox: Option<Option<String>>,
+
+    /// Write the sandbox file system to DIR when the program exits
+    #[arg(long, value_name = "DIR", requires = "sandbox", global = true)]
+    sandbox_dump: Option<String>,
+
+    /// Stop interpreting with an error after N operators, in the repl after N operators on one line
+    #[arg(long, value_name = "N", global = true)]
+    max_steps: Option<u64>,
+
+    /// Stop interpreting with an error when there are more than N values on the stack or N calls on the return stack
+    #[arg(long, value_name = "N", global = true)]
+    max_stack: Option<usize>,
+
+    /// Stop interpreting with an error when the program tries to get more than BYTES with brk and mmap
+    #[arg(long, value_name = "BYTES", global = true)]
+    max_memory: Option<usize>,
+
+    /// Make getpid and clock_gettime return the same values every run while interpreting
+    #[arg(long, global = true)]
+    deterministic: bool,
+
+    /// Arguments for the program, after a `--`, passed on by --run and --interpret
+    #[arg(last = true)]
+    program_args: Vec<String>,
+
+    /// Make compiled programs check for return stack overflows, division by zero and accesses past the end of memory blocks, a failed check prints where it happened and exits with code 101 (linux_x86_64)
+    #[arg(long)]
+    checked: bool,
+
+    /// Print what the optimisations did at -O1
+    #[arg(long)]
+    stats: bool,
+
+    /// Print the doc comments of the fn, const and memory definitions, the included ones too, and exit
+    #[arg(long)]
+    docs: bool,
     //#[arg(long, short='F')]
     //features: Vec<String>,
 
 }
 
+#[derive(clap::Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Interpret code line by line, keeping the stack and all definitions between lines
+    Repl,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    /// Static executable, assembled and linked in process
+    Exe,
+    /// Relocatable ELF64 object file
+    Obj,
+    /// Nasm source, assembled and linked with nasm and ld
+    Asm,
+    /// LLVM IR, compiled with llc and linked with ld when llc is installed (linux_x86_64 only)
+    Llvm,
+    /// QBE IL, compiled with qbe and linked with ld when qbe is installed (linux_x86_64 only)
+    Qbe,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    #[value(name = "linux_x86_64")]
+    LinuxX86_64,
+    /// Assembled and linked with the aarch64-linux-gnu binutils, run with qemu-aarch64 on other hosts
+    #[value(name = "linux_aarch64")]
+    LinuxAarch64,
+    /// Portable C source, compiled with $CC or cc
+    #[value(name = "c")]
+    C,
+    /// WebAssembly text, assembled with wat2wasm and run with wasmtime
+    #[value(name = "wasm32-wasi")]
+    Wasm32Wasi,
+}
+
 impl Args {
     /// Get optimisation level
     /// 0 => no optimisations
     /// 1 => slight optimisations, mostly size ones
+    /// 2 => keep the top of the stack in registers on linux_x86_64
     /// # Errors
     /// 
     /// Throws when the opt level is not known
@@ -76,12 +182,23 @@ impl Args {
         match self.optimisation.as_str() {
             "D" | "d" => Ok(0),
             "0" | "" => Ok(1),
+            "1" => Ok(2),
             o => {
                 error!("Unknown optimisation level {o}");
                 Err(eyre!(""))
             }
         }
     }
+
+    /// The limits for interpreting
+    pub fn limits(&self) -> Limits {
+        Limits {
+            steps: self.max_steps,
+            stack: self.max_stack,
+            memory: self.max_memory,
+            deterministic: self.deterministic,
+        }
+    }
 }
 
 fn main() -> Result<()>{
@@ -89,12 +206,26 @@ fn main() -> Result<()>{
 
     let args = Args::parse();
 
-    let Ok(code) = fs::read_to_string(&args.in_file) else {
-        error!("Failed to read file {}, exiting!", &args.in_file);
+    if args.command == Some(Command::Repl) {
+        return repl::run(&args);
+    }
+
+    let in_file = args.in_file.clone().unwrap_or_default();
+    let Ok(code) = fs::read_to_string(&in_file) else {
+        error!("Failed to read file {}, exiting!", &in_file);
         return Ok(());
     };
     
-    let tokens = lexer::lex(&code, args.in_file.as_str(), &args);
+    let tokens = match lexer::lex(&code, in_file.as_str(), &args) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Lexing failed, exiting!");
+            if crate::DEV_MODE {
+                return Err(e)
+            }
+            return Ok(());
+        }
+    };
 
     
     let mut parser = parser::Parser::new(tokens, &args, None);
@@ -109,6 +240,17 @@ fn main() -> Result<()>{
         }
     };
 
+    if args.docs {
+        for ((file, line, col), what, doc) in parser.preprocessor.docs() {
+            println!("{what} ({file}:{line}:{col})");
+            for l in doc.lines() {
+                println!("    {l}");
+            }
+            println!();
+        }
+        return Ok(());
+    }
+
     match typechecker::typecheck(tokens.clone(), &args, None, HashMap::new(), HashMap::new()) {
         Ok(_) => (),
         Err(e) => {
@@ -120,16 +262,56 @@ fn main() -> Result<()>{
         }
     };
 
+    let tokens = if args.compile && args.get_opt_level()? >= 2 {
+        match optimiser::optimise(tokens, &args) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Optimising failed, exiting!");
+                if crate::DEV_MODE {
+                    return Err(e);
+                }
+                return Ok(());
+            }
+        }
+    } else {
+        tokens
+    };
+
     let c = if args.compile && args.interpret {
         error!("Cannot compile and interpret at the same time");
         0
     } else if args.interpret {
-        if let Ok(c) = interpret::linux_x86_64::run(&tokens) { c } else {
+        let debugger = if args.debug || args.debug_script.is_some() {
+            Some(Debugger::new(args.debug_script.as_deref())?)
+        } else {
+            None
+        };
+        let sandbox = args.sandbox.as_ref().map(|seed| Vfs::new(seed.as_deref(), args.sandbox_dump.clone())).transpose()?;
+        let argv = [vec![in_file.clone()], args.program_args.clone()].concat();
+        let profiler = args.profile.as_deref().map(|out| Profiler::new(&tokens, out));
+        let coverage = args.coverage.as_deref().map(|out| Coverage::new(&tokens, out));
+        if let Ok(c) = interpret::linux_x86_64::run(&tokens, debugger, profiler, coverage, sandbox, &argv, args.limits()) { c } else {
             error!("Interpretation failed, exiting!");
             1
         }
     } else if args.compile {
-        if let Ok(c) = compile::linux_x86_64::compile(&tokens, &args) { c } else {
+        let res = match args.target {
+            _ if args.checked && (args.target != Target::LinuxX86_64 || matches!(args.emit, Emit::Llvm | Emit::Qbe)) => {
+                error!("--checked is only supported by the linux_x86_64 backend without --emit llvm or --emit qbe");
+                Err(eyre!(""))
+            }
+            Target::LinuxX86_64 if args.emit == Emit::Llvm => compile::llvm::compile(&tokens, &args),
+            Target::LinuxX86_64 if args.emit == Emit::Qbe => compile::qbe::compile(&tokens, &args),
+            _ if matches!(args.emit, Emit::Llvm | Emit::Qbe) => {
+                error!("--emit llvm and --emit qbe are only supported on linux_x86_64");
+                Err(eyre!(""))
+            }
+            Target::LinuxX86_64 => compile::linux_x86_64::compile(&tokens, &args),
+            Target::LinuxAarch64 => compile::linux_aarch64::compile(&tokens, &args),
+            Target::C => compile::c::compile(&tokens, &args),
+            Target::Wasm32Wasi => compile::wasm32_wasi::compile(&tokens, &args),
+        };
+        if let Ok(c) = res { c } else {
             error!("Compilation failed, exiting!");
             1
         }
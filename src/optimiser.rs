@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use color_eyre::Result;
+
+use crate::{constants::{Operator, OpType, KeywordType, InstructionType}, parser::cross_ref, precompiler::precompile, Args, info};
+
+/*
+ * Runs on the whole program at -O1 before it gets compiled.
+ * Jump targets are always a keyword or the op right after one, so a run of
+ * known values never spans a jump target, it gets reset at every keyword that
+ * stays in the program. Jumps are recalculated with cross_ref at the end.
+ */
+
+#[derive(Debug, Default)]
+struct Stats {
+    folded: usize,
+    branches: usize,
+    functions: usize,
+}
+
+pub fn optimise(tokens: Vec<Operator>, args: &Args) -> Result<Vec<Operator>> {
+    let mut stats = Stats::default();
+    let tokens = fold(&tokens, &mut stats)?;
+    let tokens = if args.lib_mode {
+        tokens
+    } else {
+        remove_unused_functions(tokens, &mut stats)
+    };
+
+    if args.stats {
+        info!("optimiser: folded {} operators", stats.folded);
+        info!("optimiser: removed {} constant branches", stats.branches);
+        info!("optimiser: removed {} unused functions", stats.functions);
+    }
+
+    cross_ref(tokens)
+}
+
+/// How many values `instruction` takes if it can be evaluated at compile time
+fn foldable(instruction: &InstructionType) -> Option<usize> {
+    match instruction {
+        InstructionType::Drop |
+        InstructionType::Dup |
+        InstructionType::CastBool |
+        InstructionType::CastPtr |
+        InstructionType::CastInt |
+        InstructionType::CastVoid => Some(1),
+        InstructionType::Plus |
+        InstructionType::Minus |
+        InstructionType::Mul |
+        InstructionType::Equals |
+        InstructionType::NotEquals |
+        InstructionType::Gt |
+        InstructionType::Lt |
+        InstructionType::Ge |
+        InstructionType::Le |
+        InstructionType::Band |
+        InstructionType::Bor |
+        InstructionType::Shr |
+        InstructionType::Shl |
+        InstructionType::DivMod |
+        InstructionType::Swap |
+        InstructionType::Over => Some(2),
+        InstructionType::Rot => Some(3),
+        _ => None
+    }
+}
+
+fn push_int(value: usize, op: &Operator) -> Operator {
+    Operator::new(OpType::Instruction(InstructionType::PushInt), op.tok_typ, value, String::new(), op.loc.0.clone(), op.loc.1, op.loc.2)
+}
+
+/// Fold constants and remove `if` blocks with a known condition
+fn fold(tokens: &[Operator], stats: &mut Stats) -> Result<Vec<Operator>> {
+    let constants: HashMap<&str, usize> = tokens.iter()
+        .filter(|op| op.typ == OpType::Keyword(KeywordType::ConstantDef))
+        .map(|op| (op.text.as_str(), op.value))
+        .collect();
+
+    let mut out: Vec<Operator> = Vec::new();
+    let mut skip = vec![false; tokens.len()];
+    // how many ops at the end of `out` are PushInts of the current run
+    let mut known = 0;
+
+    for (ip, op) in tokens.iter().enumerate() {
+        if skip[ip] {
+            continue;
+        }
+        match &op.typ {
+            OpType::Instruction(InstructionType::PushInt) => {
+                out.push(op.clone());
+                known += 1;
+            }
+            OpType::Instruction(InstructionType::ConstUse) if constants.contains_key(op.text.as_str()) => {
+                out.push(push_int(constants[op.text.as_str()], op));
+                known += 1;
+            }
+            OpType::Instruction(i) if foldable(i).is_some_and(|n| n <= known) => {
+                let n = foldable(i).unwrap();
+                let args = out.split_off(out.len() - n);
+                if *i == InstructionType::DivMod && args[1].value == 0 {
+                    out.extend(args);
+                    out.push(op.clone());
+                    known = 0;
+                    continue;
+                }
+                let values = if matches!(i, InstructionType::CastBool | InstructionType::CastPtr | InstructionType::CastInt | InstructionType::CastVoid) {
+                    vec![args[0].value]
+                } else {
+                    let mut code = args;
+                    code.push(op.clone());
+                    precompile(&code)?
+                };
+                known = known - n + values.len();
+                out.extend(values.into_iter().map(|v| push_int(v, op)));
+                stats.folded += 1;
+            }
+            OpType::Keyword(KeywordType::If) if known > 0 => {
+                let cond = out.pop().unwrap().value;
+                known -= 1;
+                // without an else the if jumps straight to its end
+                let (else_ip, end_ip) = if tokens[op.jmp].typ == OpType::Keyword(KeywordType::End) {
+                    (None, op.jmp)
+                } else {
+                    (Some(op.jmp - 1), tokens[op.jmp - 1].jmp)
+                };
+                match (cond != 0, else_ip) {
+                    (true, Some(else_ip)) => skip[else_ip..=end_ip].fill(true),
+                    (true, None) => skip[end_ip] = true,
+                    (false, Some(else_ip)) => {
+                        skip[ip..=else_ip].fill(true);
+                        skip[end_ip] = true;
+                    }
+                    (false, None) => skip[ip..=end_ip].fill(true),
+                }
+                stats.branches += 1;
+            }
+            _ => {
+                out.push(op.clone());
+                known = 0;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Remove every function that can not be reached from main or an exported function
+fn remove_unused_functions(tokens: Vec<Operator>, stats: &mut Stats) -> Vec<Operator> {
+    let mut functions: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut start = None;
+    for (ip, op) in tokens.iter().enumerate() {
+        match op.typ {
+            OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => start = Some(ip),
+            OpType::Keyword(KeywordType::FunctionDone) => {
+                if let Some(s) = start.take() {
+                    functions.insert(tokens[s].text.clone(), (s, ip));
+                }
+            }
+            _ => ()
+        }
+    }
+
+    let mut todo: Vec<&str> = tokens.iter()
+        .filter(|op| op.typ == OpType::Keyword(KeywordType::FunctionDefExported))
+        .map(|op| op.text.as_str())
+        .collect();
+    todo.push("main");
+    let mut used: Vec<&str> = Vec::new();
+    while let Some(name) = todo.pop() {
+        if used.contains(&name) {
+            continue;
+        }
+        used.push(name);
+        let Some(&(s, e)) = functions.get(name) else {
+            continue;
+        };
+        for op in &tokens[s..e] {
+            if op.typ == OpType::Instruction(InstructionType::FnCall) {
+                todo.push(op.text.as_str());
+            }
+        }
+    }
+
+    let mut keep = vec![true; tokens.len()];
+    for (name, &(s, e)) in &functions {
+        if !used.contains(&name.as_str()) {
+            keep[s..=e].fill(false);
+            stats.functions += 1;
+        }
+    }
+    tokens.into_iter()
+        .zip(keep)
+        .filter_map(|(op, k)| k.then_some(op))
+        .collect()
+}
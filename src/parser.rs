@@ -1,6 +1,6 @@
 use std::ops::Deref;
 
-use crate::{constants::{Operator, OpType, Token, TokenType, Loc, KeywordType, InstructionType}, lerror, preprocessor::Preprocessor, Args};
+use crate::{constants::{Operator, OpType, Token, TokenType, Loc, KeywordType, InstructionType}, lerror, lwarn, lexer, preprocessor::Preprocessor, Args};
 use color_eyre::Result;
 use eyre::eyre;
 
@@ -98,14 +98,23 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse(&mut self) -> Result<Vec<Operator>> {
-        let mut tokens = Vec::new();
+        let mut tokens: Vec<Operator> = Vec::new();
+        // doc comment lines waiting for their definition
+        let mut doc: Option<(Loc, String)> = None;
 
         for token in &self.tokens {
-            if token.text.is_empty() {
-                continue;
-            }
             let pos = (token.file.clone(), token.line, token.col);
             match token.typ {
+                TokenType::DocComment => {
+                    match &mut doc {
+                        Some((_, text)) => {
+                            text.push('\n');
+                            text.push_str(&token.text);
+                        }
+                        None => doc = Some((pos, token.text.clone())),
+                    }
+                    continue;
+                }
                 TokenType::Word => {
                     let word_type = if token.op_typ == OpType::Instruction(InstructionType::MemUse) {
                         OpType::Instruction(InstructionType::MemUse)
@@ -115,16 +124,23 @@ impl<'a> Parser<'a> {
 
                     tokens.push(Operator::new(word_type, token.typ, token.value.unwrap_or(0), token.text.clone(), token.file.clone(), token.line, token.col).set_addr(token.addr.unwrap_or(0)));
                 },
-                TokenType::Int => {// negative numbers not yet implemented
-                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushInt), token.typ, token.text.parse::<usize>()?, String::new(), token.file.clone(), token.line, token.col));
+                TokenType::Int => {
+                    let Some(n) = lexer::int_value(&token.text) else {
+                        lerror!(&token.loc(), "Integer literal '{}' does not fit in 64 bits", token.text);
+                        return Err(eyre!(""));
+                    };
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushInt), token.typ, n, String::new(), token.file.clone(), token.line, token.col));
                 },
                 TokenType::String => {
                     tokens.push(Operator::new(OpType::Instruction(InstructionType::PushStr), token.typ, 0, token.text.clone(), token.file.clone(), token.line, token.col));
                 }
+                TokenType::CString => {
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushCStr), token.typ, 0, token.text.clone(), token.file.clone(), token.line, token.col));
+                }
                 TokenType::Char => {
                     let c = token.text.clone();
-                    if c.len() != 1 {
-                        lerror!(&token.loc(), "Chars can only be of lenght 1, got {}", c.len());
+                    if c.chars().count() != 1 {
+                        lerror!(&token.loc(), "Chars can only be of lenght 1, got {}", c.chars().count());
                         return Err(eyre!(""));
                     }
 
@@ -132,8 +148,20 @@ impl<'a> Parser<'a> {
                 }
             };
 
+            if let Some((loc, text)) = doc.take() {
+                let op = tokens.last_mut().expect("every token is an op");
+                match op.typ {
+                    OpType::Keyword(KeywordType::Function | KeywordType::Constant | KeywordType::Memory) => op.doc = Some(text),
+                    // `inline fn` and `export fn`
+                    OpType::Keyword(KeywordType::Inline | KeywordType::Export) => doc = Some((loc, text)),
+                    _ => lwarn!(&loc, "Doc comment is not followed by a fn, const or memory definition"),
+                }
+            }
 
         }
+        if let Some((loc, _)) = doc {
+            lwarn!(&loc, "Doc comment is not followed by a fn, const or memory definition");
+        }
         self.preprocessor.program = tokens;
         let t = self.preprocessor.preprocess()?.get_ops();
         let t = cross_ref(t)?;
@@ -190,6 +218,10 @@ pub fn lookup_word<P: Deref<Target = Loc>>(s: &str, _pos: P) -> OpType {
         "syscall4" => OpType::Instruction(InstructionType::Syscall4),
         "syscall5" => OpType::Instruction(InstructionType::Syscall5),
         "syscall6" => OpType::Instruction(InstructionType::Syscall6),
+
+        "argc" => OpType::Instruction(InstructionType::Argc),
+        "argv" => OpType::Instruction(InstructionType::Argv),
+        "envp" => OpType::Instruction(InstructionType::Envp),
         "cast(bool)" => OpType::Instruction(InstructionType::CastBool),
         "cast(ptr)" => OpType::Instruction(InstructionType::CastPtr),
         "cast(int)" => OpType::Instruction(InstructionType::CastInt),
@@ -11,6 +11,8 @@ fn stack_pop(stack: &mut Vec<usize>, loc: &Loc) -> Result<usize> {
     }
 }
 
+/// Evaluate a sequence of pure integer operators, the same way the compiled
+/// code would (wrapping arithmetic, signed comparisons)
 pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
 
     let mut stack: Vec<usize> = Vec::new();
@@ -25,12 +27,12 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     InstructionType::Plus => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b + a);
+                        stack.push(b.wrapping_add(a));
                     },
                     InstructionType::Minus => {
                     let a = stack_pop(&mut stack, &loc)?;
                     let b = stack_pop(&mut stack, &loc)?;
-                    stack.push(b - a);
+                    stack.push(b.wrapping_sub(a));
                     },
                     InstructionType::Equals => {
                         let a = stack_pop(&mut stack, &loc)?;
@@ -40,12 +42,12 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     InstructionType::Gt => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(usize::from(b > a));
+                        stack.push(usize::from((b as i64) > (a as i64)));
                     },
                     InstructionType::Lt => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(usize::from(b < a));
+                        stack.push(usize::from((b as i64) < (a as i64)));
                     },
                     InstructionType::NotEquals => {
                         let a = stack_pop(&mut stack, &loc)?;
@@ -55,12 +57,12 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     InstructionType::Ge => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(usize::from(b >= a));
+                        stack.push(usize::from((b as i64) >= (a as i64)));
                     },
                     InstructionType::Le => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(usize::from(b <= a));
+                        stack.push(usize::from((b as i64) <= (a as i64)));
                     },
                     
                     InstructionType::Band => {
@@ -78,25 +80,29 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     InstructionType::Shr => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b >> a);
+                        stack.push(b.wrapping_shr(a as u32));
                     }
                     
                     InstructionType::Shl => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b << a);
+                        stack.push(b.wrapping_shl(a as u32));
                     }
                     
                     InstructionType::DivMod => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
+                        if a == 0 {
+                            lerror!(&loc, "Division by zero");
+                            return Err(eyre!("Division by zero"));
+                        }
                         stack.push(b / a);
                         stack.push(b % a);
                     }
                     InstructionType::Mul => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b * a);
+                        stack.push(b.wrapping_mul(a));
                     }
                     InstructionType::Drop => {
                         stack.pop();
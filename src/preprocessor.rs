@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::ops::Deref;
 use std::path::{PathBuf, Path};
 
 
@@ -19,20 +18,22 @@ pub struct Function {
     pub loc: Loc,
     pub name: String,
     pub inline: bool,
-    pub tokens: Option<Vec<Operator>>
+    pub tokens: Option<Vec<Operator>>,
+    pub doc: Option<String>
 }
 
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub loc: Loc,
-    pub name: String
+    pub name: String,
+    pub doc: Option<String>
 }
 
 #[derive(Debug, Clone)]
 pub struct Memory {
     pub loc: Loc,
-    pub id: usize
-    
+    pub id: usize,
+    pub doc: Option<String>
 }
 
 type Functions = HashMap<String, Function>;
@@ -71,8 +72,7 @@ impl<'a> Preprocessor<'a> {
 
         let mut rtokens = self.program.clone();
         rtokens.reverse();
-        while !rtokens.is_empty() {
-            let mut op = rtokens.pop().unwrap();
+        while let Some(mut op) = rtokens.pop() {
             // println!("{token:?}");
             let op_type = op.typ.clone();
             match op_type {
@@ -118,7 +118,7 @@ impl<'a> Preprocessor<'a> {
                         return Err(eyre!(""));
                     }
                     let a = pth.to_str().unwrap().to_string();
-                    let code = lex(&include_code, a.as_str(), self.args);
+                    let code = lex(&include_code, a.as_str(), self.args)?;
                     let mut p = parser::Parser::new(code, self.args, Some(self.clone()));
                     let mut code = p.parse()?;
 
@@ -144,8 +144,7 @@ impl<'a> Preprocessor<'a> {
                     let mut code: Vec<Operator> = Vec::new();
 
                     let mut depth = 0;
-                    while !rtokens.is_empty() {
-                        let t = rtokens.pop().unwrap();
+                    while let Some(t) = rtokens.pop() {
                         let typ = t.typ.clone();
                         if typ == OpType::Keyword(KeywordType::End) && depth == 0 {
                             break;
@@ -168,9 +167,10 @@ impl<'a> Preprocessor<'a> {
                     }
                     op.value = res[0];
                     op.addr = Some(self.memories.len());
+                    op.text = name.text.clone();
                     program.push(op.clone());
 
-                    self.memories.insert(name.text, Memory { loc: op.loc, id: self.memories.len() });
+                    self.memories.insert(name.text, Memory { loc: op.loc, id: self.memories.len(), doc: op.doc });
 
                 }
 
@@ -215,8 +215,7 @@ impl<'a> Preprocessor<'a> {
                         f_inline = false;
                         let mut prog: Vec<Operator> = Vec::new();
                         let mut depth = -1;
-                        while !rtokens.is_empty() {
-                            let op = rtokens.pop().unwrap();
+                        while let Some(op) = rtokens.pop() {
 
                             match op.typ.clone() {
                                 OpType::Instruction(i) => {
@@ -268,7 +267,8 @@ impl<'a> Preprocessor<'a> {
                             loc: name.loc.clone(),
                             name: name.text.clone(),
                             inline: true,
-                            tokens: Some(prog)
+                            tokens: Some(prog),
+                            doc: op.doc.clone()
                         });
                         
                     } else if f_extern {
@@ -277,14 +277,14 @@ impl<'a> Preprocessor<'a> {
                             loc: name.loc.clone(),
                             name: name.text.clone(),
                             inline: false,
-                            tokens: None
+                            tokens: None,
+                            doc: op.doc.clone()
                         });
                         let mut a: Vec<Operator> = Vec::new();
                         let mut fn_def = op.clone();
                         a.push(rtokens.pop().unwrap());
                         let mut ret = false;
-                        while !rtokens.is_empty() {
-                            let op = rtokens.pop().unwrap();
+                        while let Some(op) = rtokens.pop() {
                             // println!("{:?}",op);
                             a.push(op.clone());
                             if op.typ == OpType::Instruction(InstructionType::Returns) {
@@ -321,7 +321,8 @@ impl<'a> Preprocessor<'a> {
                             loc: name.loc.clone(),
                             name: name.text.clone(),
                             inline: false,
-                            tokens: None
+                            tokens: None,
+                            doc: op.doc.clone()
                         });
                         
                         let mut fn_def = op.clone();
@@ -374,6 +375,7 @@ impl<'a> Preprocessor<'a> {
                     self.constants.insert(name.text.clone(), Constant{
                         loc: name.loc.clone(),
                         name: name.text.clone(),
+                        doc: op.doc.clone(),
                     });
 
                     // println!("{:?}", self.constants);
@@ -440,6 +442,7 @@ impl<'a> Preprocessor<'a> {
             if f.tok_typ == TokenType::Word && 
                 f.typ != OpType::Instruction(InstructionType::FnCall) && 
                 f.typ != OpType::Instruction(InstructionType::MemUse) &&
+                f.typ != OpType::Keyword(KeywordType::Memory) &&
                 f.typ != OpType::Keyword(KeywordType::FunctionDef) &&
                 f.typ != OpType::Keyword(KeywordType::FunctionDefExported) &&
                 f.typ != OpType::Keyword(KeywordType::ConstantDef) &&
@@ -467,8 +470,7 @@ impl<'a> Preprocessor<'a> {
         let mut rtokens = self.program.clone();
         rtokens.reverse();
 
-        while !rtokens.is_empty() {
-            let op = rtokens.pop().unwrap();
+        while let Some(op) = rtokens.pop() {
             let op_type = op.typ.clone();
             if op.tok_typ == TokenType::Word {
                 match op_type {
@@ -489,12 +491,12 @@ impl<'a> Preprocessor<'a> {
                             // println!("##### {:?}", t);
                         } else if let Some(mem) = mem {
                             let mut t = op.clone();
-                            t.addr = Some(mem.deref().id);
+                            t.addr = Some(mem.id);
                             t.typ = OpType::Instruction(InstructionType::MemUse);
                             program.push(t);
                         } else if let Some(cons) = cons {
                             let mut t = op.clone();
-                            t.text = cons.deref().name.clone();
+                            t.text = cons.name.clone();
                             t.typ = OpType::Instruction(InstructionType::ConstUse);
                             program.push(t);
                             
@@ -614,4 +616,18 @@ impl<'a> Preprocessor<'a> {
     pub fn get_memories(&mut self) -> Memories{
         self.memories.clone()
     }
+
+    /// Every definition that has a doc comment, as where it is, what it is
+    /// and the doc, in source order
+    pub fn docs(&self) -> Vec<(Loc, String, String)> {
+        let functions = self.functions.values().filter_map(|f| {
+            let what = format!("{}fn {}", if f.inline { "inline " } else { "" }, f.name);
+            Some((f.loc.clone(), what, f.doc.clone()?))
+        });
+        let constants = self.constants.values().filter_map(|c| Some((c.loc.clone(), format!("const {}", c.name), c.doc.clone()?)));
+        let memories = self.memories.iter().filter_map(|(name, m)| Some((m.loc.clone(), format!("memory {name}"), m.doc.clone()?)));
+        let mut docs = functions.chain(constants).chain(memories).collect::<Vec<_>>();
+        docs.sort_by(|a, b| a.0.cmp(&b.0));
+        docs
+    }
 }
\ No newline at end of file
@@ -0,0 +1,162 @@
+use std::{collections::HashMap, io::{self, Write}};
+use color_eyre::Result;
+
+use crate::{constants::{Operator, OpType, KeywordType, TokenType, Types}, interpret::linux_x86_64::{self, State, vfs::Vfs}, lexer, parser, preprocessor::Preprocessor, typechecker, Args, error, info};
+
+/*
+ * Every line goes through the same steps as a file, the preprocessor, the
+ * typechecker state and the interpreter state are kept between lines.
+ * Definitions are kept in `program` so functions can be called from later
+ * lines, the top level code of a line gets appended after them, run, and
+ * thrown away again.
+ * A line that fails anywhere changes nothing except the memory it wrote to.
+ */
+
+struct Repl<'a> {
+    args: &'a Args,
+    preprocessor: Preprocessor<'a>,
+    program: Vec<Operator>,
+    types: Vec<Types>,
+    functions: HashMap<String, typechecker::Function>,
+    constants: HashMap<String, typechecker::Constant>,
+    state: State,
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let mut repl = Repl {
+        args,
+        preprocessor: Preprocessor::new(Vec::new(), args),
+        program: Vec::new(),
+        types: Vec::new(),
+        functions: HashMap::new(),
+        constants: HashMap::new(),
+        state: State::new(
+            args.sandbox.as_ref().map(|seed| Vfs::new(seed.as_deref(), args.sandbox_dump.clone())).transpose()?,
+            &[String::from("<repl>")],
+            args.limits(),
+        ),
+    };
+
+    if !args.quiet {
+        info!("mclang repl, the stack is printed after every line, Ctrl-D to exit");
+    }
+
+    let stdin = io::stdin();
+    let mut n = 0;
+    loop {
+        let mut code = String::new();
+        // keep reading until every block is closed
+        loop {
+            print!("{}", if code.is_empty() { "mcl> " } else { "...> " });
+            io::stdout().flush()?;
+            if stdin.read_line(&mut code)? == 0 {
+                println!();
+                return repl.state.dump_sandbox();
+            }
+            if depth(&code) <= 0 {
+                break;
+            }
+        }
+        if code.trim().is_empty() {
+            continue;
+        }
+        n += 1;
+
+        if repl.eval(&code, &format!("<repl:{n}>")).is_ok() {
+            repl.print_stack();
+        }
+    }
+}
+
+/// How many blocks `code` leaves open, a string or comment that is still open
+/// and a doc comment waiting for its definition count as one
+fn depth(code: &str) -> isize {
+    match lexer::lex_tokens(code, "<repl>") {
+        Ok(tokens) if tokens.last().is_some_and(|t| t.typ == TokenType::DocComment) => 1,
+        Ok(tokens) => tokens.iter().filter(|t| t.typ == TokenType::Word).map(|t| match t.text.as_str() {
+            "if" | "while" | "fn" | "const" | "memory" => 1,
+            "end" | "done" => -1,
+            _ => 0
+        }).sum(),
+        Err(e) if e.unterminated => 1,
+        // eval reports it
+        Err(_) => 0,
+    }
+}
+
+impl Repl<'_> {
+    fn eval(&mut self, code: &str, name: &str) -> Result<()> {
+        let tokens = lexer::lex(code, name, self.args)?;
+
+        let mut parser = parser::Parser::new(tokens, self.args, Some(self.preprocessor.clone()));
+        let ops = parser.parse()?;
+
+        // definitions and the code that runs are checked separately, the
+        // typechecker leaves function arguments on the stack
+        let (defs, code): (Vec<Operator>, Vec<Operator>) = split_definitions(ops);
+        let (_, functions, constants) = typechecker::typecheck(defs.clone(), self.args, None, self.functions.clone(), self.constants.clone())?;
+        let (types, functions, constants) = typechecker::typecheck(code.clone(), self.args, Some(self.types.clone()), functions, constants)?;
+
+        let start = self.program.len() + defs.len();
+        let mut program = self.program.clone();
+        program.extend(defs);
+        program.extend(code);
+        let mut program = parser::cross_ref(program)?;
+        self.state.place_strings(&mut program)?;
+
+        let stack = self.state.stack.clone();
+        let defineds = linux_x86_64::pre_run(&program)?;
+        match linux_x86_64::execute(&program, start, &mut self.state, &defineds, None, None, None) {
+            Ok(0) => (),
+            r => {
+                if let Ok(c) = r {
+                    error!("Line exited with code {c}");
+                }
+                self.state.stack = stack;
+                self.state.ret_stack.clear();
+                return Err(eyre::eyre!(""));
+            }
+        }
+
+        self.preprocessor = parser.preprocessor;
+        self.program = program[..start].to_vec();
+        self.types = types;
+        self.functions = functions;
+        self.constants = constants;
+        Ok(())
+    }
+
+    fn print_stack(&self) {
+        let values = self.state.stack.iter().enumerate().map(|(i, v)| {
+            // in unsafe mode there are no types
+            match self.types.get(i) {
+                Some(t) => format!("{v} ({})", format!("{t:?}").to_lowercase()),
+                None => v.to_string(),
+            }
+        }).collect::<Vec<_>>();
+        println!("[{}]", values.join(", "));
+    }
+}
+
+/// Split off function, memory and constant definitions from the code of a line
+fn split_definitions(ops: Vec<Operator>) -> (Vec<Operator>, Vec<Operator>) {
+    let mut defs = Vec::new();
+    let mut code = Vec::new();
+    let mut in_function = false;
+    for op in ops {
+        match op.typ {
+            OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => {
+                in_function = true;
+                defs.push(op);
+            }
+            OpType::Keyword(KeywordType::FunctionDone) if in_function => {
+                in_function = false;
+                defs.push(op);
+            }
+            OpType::Keyword(KeywordType::Memory | KeywordType::ConstantDef) => defs.push(op),
+            _ if in_function => defs.push(op),
+            _ => code.push(op),
+        }
+    }
+    (defs, code)
+}
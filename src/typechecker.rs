@@ -44,13 +44,12 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
     let mut functions: HashMap<String, Function> = funcs;
     let mut constants: HashMap<String, Constant> = consts;
     // let mut in_function: (String, Function, Loc) = (String::new(), Function::default(), (String::new(), 0, 0));
-    let mut stack: Vec<Types> = if let Some(i) = init_types {i} else {Vec::new()};
+    let mut stack: Vec<Types> = init_types.unwrap_or_default();
     let mut stack_snapshots: Vec<Vec<Types>> = Vec::new();
     let mut rtokens = ops;
     rtokens.reverse();
     // println!("{:#?}", ops);
-    while !rtokens.is_empty() {
-        let op = rtokens.pop().unwrap();
+    while let Some(op) = rtokens.pop() {
         // println!("{:?}", stack.clone());
         // println!("{:?}", op);
         // println!("{}", ops.len());
@@ -132,8 +131,7 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
 
                         let mut code: Vec<Operator> = Vec::new();
 
-                        while !rtokens.is_empty() {
-                            let op = rtokens.pop().unwrap();
+                        while let Some(op) = rtokens.pop() {
 
                             if op.typ == OpType::Keyword(KeywordType::FunctionDone) {
                                 break;
@@ -193,6 +191,9 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         stack.push(Types::Ptr);
 
                     },
+                    InstructionType::PushCStr => {
+                        stack.push(Types::Ptr);
+                    },
                     InstructionType::Drop => {
                         stack_pop(&mut stack, &op, &[Types::Any])?;
                     },
@@ -201,6 +202,7 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                     },
                     InstructionType::Dup => {
                         let a = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        stack.push(a.clone());
                         stack.push(a);
                     },
                     InstructionType::Rot => {
@@ -331,6 +333,13 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                     InstructionType::MemUse => {
                         stack.push(Types::Ptr);
                     },
+                    InstructionType::Argc => {
+                        stack.push(Types::Int);
+                    },
+                    InstructionType::Argv |
+                    InstructionType::Envp => {
+                        stack.push(Types::Ptr);
+                    },
                     InstructionType::FnCall  => {
                         stack_snapshots.push(stack.clone());
                         